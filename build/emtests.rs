@@ -51,6 +51,9 @@ pub fn compile(file: &str, ignores: &Vec<String>) -> Option<String> {
         .arg(file)
         .arg("-s")
         .arg("WASM=1")
+        // Catch the C++ exceptions (through the invoke_* imports)
+        .arg("-s")
+        .arg("DISABLE_EXCEPTION_CATCHING=0")
         .arg("-o")
         .arg(output_str)
         .output()
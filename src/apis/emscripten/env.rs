@@ -1,66 +1,327 @@
 use super::super::host;
 /// NOTE: These syscalls only support wasm_32 for now because they take u32 offset
-use libc::{
-    c_int, c_long, getenv, getgrnam as libc_getgrnam, getpwnam as libc_getpwnam, putenv, setenv,
-    sysconf, unsetenv,
-};
+use byteorder::{ByteOrder, LittleEndian};
+use libc::{c_int, c_long, getgrnam as libc_getgrnam, getpwnam as libc_getpwnam, sysconf};
+use std::collections::BTreeMap;
 use std::ffi::CStr;
 use std::mem;
 use std::os::raw::c_char;
+use std::ptr;
 
-use super::utils::{allocate_on_stack, copy_cstr_into_wasm, copy_terminated_array_of_cstrs};
+use super::errno::set_errno;
+use super::trace::guest_str;
+use super::utils::{copy_cstr_into_wasm, copy_terminated_array_of_cstrs};
+use super::vfs::errno;
 use crate::webassembly::Instance;
 
-// #[no_mangle]
-/// emscripten: _getenv // (name: *const char) -> *const c_char;
-pub extern "C" fn _getenv(name: c_int, instance: &mut Instance) -> u32 {
-    debug!("emscripten::_getenv");
+/// The environment variables of a guest, and the strings of the guest
+/// `environ` built from them. The host environment is never exposed.
+#[derive(Debug)]
+pub struct Environment {
+    vars: BTreeMap<String, String>,
+    /// The address of the `environ` variable of the guest (0 until `___buildEnvironment`)
+    environ: u32,
+    /// The guest array of strings `environ` points to
+    array: u32,
+    /// The guest `NAME=VALUE` string of each variable, allocated on first use,
+    /// and freed when the variable changes
+    strings: BTreeMap<String, u32>,
+}
 
-    let name_addr = instance.memory_offset_addr(0, name as usize) as *const c_char;
+impl Environment {
+    /// The default variables of emscripten, overridden by `vars`.
+    pub fn new(vars: Vec<(String, String)>) -> Self {
+        let defaults = [
+            ("USER", "web_user"),
+            ("LOGNAME", "web_user"),
+            ("PATH", "/"),
+            ("PWD", "/"),
+            ("HOME", "/home/web_user"),
+            ("LANG", "C.UTF-8"),
+            ("_", "./this.program"),
+        ];
+        let defaults = defaults
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()));
+        Environment {
+            vars: defaults.chain(vars).collect(),
+            environ: 0,
+            array: 0,
+            strings: BTreeMap::new(),
+        }
+    }
 
-    debug!("=> name({:?})", unsafe { CStr::from_ptr(name_addr) });
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|value| value.as_str())
+    }
+}
 
-    let c_str = unsafe { getenv(name_addr) };
-    if c_str.is_null() {
-        return 0;
+impl Default for Environment {
+    fn default() -> Self {
+        Environment::new(Vec::new())
     }
+}
 
-    unsafe { copy_cstr_into_wasm(instance, c_str) }
+/// Where the strings of the environment are allocated: the guest memory and
+/// allocator (or a fake one in the tests).
+trait GuestHeap {
+    fn malloc(&mut self, size: u32) -> u32;
+    fn free(&mut self, ptr: u32);
+    fn write(&mut self, ptr: u32, bytes: &[u8]);
 }
 
-/// emscripten: _setenv // (name: *const char, name: *const value, overwrite: int);
-pub extern "C" fn _setenv(name: c_int, value: c_int, overwrite: c_int, instance: &mut Instance) {
-    debug!("emscripten::_setenv");
+impl GuestHeap for Instance {
+    fn malloc(&mut self, size: u32) -> u32 {
+        (self.emscripten_data.as_ref().unwrap().malloc)(size as i32, self)
+    }
 
-    let name_addr = instance.memory_offset_addr(0, name as usize) as *const c_char;
-    let value_addr = instance.memory_offset_addr(0, value as usize) as *const c_char;
+    fn free(&mut self, ptr: u32) {
+        let free = self.emscripten_data.as_ref().unwrap().free;
+        free(ptr as i32, self);
+    }
 
-    debug!("=> name({:?})", unsafe { CStr::from_ptr(name_addr) });
-    debug!("=> value({:?})", unsafe { CStr::from_ptr(value_addr) });
+    fn write(&mut self, ptr: u32, bytes: &[u8]) {
+        unsafe {
+            let addr = self.memory_offset_addr(0, ptr as _) as *mut u8;
+            ptr::copy_nonoverlapping(bytes.as_ptr(), addr, bytes.len());
+        }
+    }
+}
 
-    unsafe { setenv(name_addr, value_addr, overwrite) };
+/// Copies a string (null terminated) into a new guest allocation.
+fn allocate_string(heap: &mut GuestHeap, s: &str) -> u32 {
+    let offset = heap.malloc(s.len() as u32 + 1);
+    heap.write(offset, s.as_bytes());
+    heap.write(offset + s.len() as u32, &[0]);
+    offset
 }
 
-/// emscripten: _putenv // (name: *const char);
-pub extern "C" fn _putenv(name: c_int, instance: &mut Instance) {
-    debug!("emscripten::_putenv");
+impl Environment {
+    /// The guest `NAME=VALUE` string of a variable, if it is set.
+    fn variable_string(&mut self, heap: &mut GuestHeap, name: &str) -> Option<u32> {
+        if let Some(&string) = self.strings.get(name) {
+            return Some(string);
+        }
+        let string = allocate_string(heap, &format!("{}={}", name, self.vars.get(name)?));
+        self.strings.insert(name.to_string(), string);
+        Some(string)
+    }
 
-    let name_addr = instance.memory_offset_addr(0, name as usize) as *const c_char;
+    /// The guest string of the value of a variable, as returned by `getenv`.
+    fn getenv(&mut self, heap: &mut GuestHeap, name: &str) -> Option<u32> {
+        // The value follows the `=`
+        self.variable_string(heap, name)
+            .map(|string| string + name.len() as u32 + 1)
+    }
 
-    debug!("=> name({:?})", unsafe { CStr::from_ptr(name_addr) });
+    /// Sets a variable, unless it is already set and `overwrite` is false.
+    fn setenv(&mut self, heap: &mut GuestHeap, name: &str, value: &str, overwrite: bool) {
+        if overwrite || !self.vars.contains_key(name) {
+            self.vars.insert(name.to_string(), value.to_string());
+            self.changed(heap, name);
+        }
+    }
+
+    fn unsetenv(&mut self, heap: &mut GuestHeap, name: &str) {
+        if self.vars.remove(name).is_some() {
+            self.changed(heap, name);
+        }
+    }
 
-    unsafe { putenv(name_addr as _) };
+    /// Frees the string of a variable that changed, like `__env_rm_add` of musl:
+    /// the values returned by `getenv` for the other variables stay valid. Then
+    /// rebuilds the guest `environ`, if it was built.
+    fn changed(&mut self, heap: &mut GuestHeap, name: &str) {
+        if let Some(string) = self.strings.remove(name) {
+            heap.free(string);
+        }
+        self.build_environ(heap);
+    }
+
+    /// Builds the guest `environ` (at `environ`), and keeps it up to date.
+    fn set_environ(&mut self, heap: &mut GuestHeap, environ: u32) {
+        self.environ = environ;
+        self.build_environ(heap);
+    }
+
+    /// Points the guest `environ` to a new array of the strings of the variables.
+    fn build_environ(&mut self, heap: &mut GuestHeap) {
+        if self.environ == 0 {
+            return;
+        }
+        let names: Vec<String> = self.vars.keys().cloned().collect();
+        let strings: Vec<u32> = names
+            .iter()
+            .filter_map(|name| self.variable_string(heap, name))
+            .chain(Some(0))
+            .collect();
+        let array = heap.malloc(strings.len() as u32 * 4);
+        let mut entries = vec![0; strings.len() * 4];
+        LittleEndian::write_u32_into(&strings, &mut entries);
+        heap.write(array, &entries);
+        let mut environ = [0; 4];
+        LittleEndian::write_u32(&mut environ, array);
+        heap.write(self.environ, &environ);
+        let previous_array = mem::replace(&mut self.array, array);
+        if previous_array != 0 {
+            heap.free(previous_array);
+        }
+    }
 }
 
-/// emscripten: _unsetenv // (name: *const char);
-pub extern "C" fn _unsetenv(name: c_int, instance: &mut Instance) {
-    debug!("emscripten::_unsetenv");
+/// Runs `f` with the environment taken out of the instance, for it to call
+/// the guest allocator.
+fn with_environment<T, F>(instance: &mut Instance, f: F) -> T
+where
+    F: FnOnce(&mut Environment, &mut Instance) -> T,
+{
+    let mut env = mem::replace(
+        &mut instance.emscripten_data.as_mut().unwrap().env,
+        Environment::new(Vec::new()),
+    );
+    let ret = f(&mut env, instance);
+    instance.emscripten_data.as_mut().unwrap().env = env;
+    ret
+}
 
+/// Reads a guest string, if it is a valid variable name.
+fn variable_name(instance: &Instance, name: c_int) -> Option<String> {
+    if name == 0 {
+        return None;
+    }
     let name_addr = instance.memory_offset_addr(0, name as usize) as *const c_char;
+    let name = unsafe { CStr::from_ptr(name_addr) }.to_string_lossy();
+    if name.is_empty() || name.contains('=') {
+        None
+    } else {
+        Some(name.into_owned())
+    }
+}
+
+/// emscripten: _getenv // (name: *const char) -> *const c_char;
+pub extern "C" fn _getenv(name: c_int, instance: &mut Instance) -> u32 {
+    debug!("emscripten::_getenv");
+
+    let ret = match variable_name(instance, name) {
+        Some(name) => {
+            debug!("=> name({:?})", name);
+            with_environment(instance, |env, instance| env.getenv(instance, &name)).unwrap_or(0)
+        }
+        None => 0,
+    };
+    trace_call!(
+        instance,
+        "getenv",
+        ret,
+        0,
+        "{}",
+        guest_str(instance, name as u32)
+    );
+    ret
+}
 
-    debug!("=> name({:?})", unsafe { CStr::from_ptr(name_addr) });
+/// emscripten: _setenv // (name: *const char, name: *const value, overwrite: int);
+pub extern "C" fn _setenv(
+    name: c_int,
+    value: c_int,
+    overwrite: c_int,
+    instance: &mut Instance,
+) -> c_int {
+    debug!("emscripten::_setenv");
 
-    unsafe { unsetenv(name_addr) };
+    let ret = match variable_name(instance, name) {
+        Some(name) => {
+            let value_addr = instance.memory_offset_addr(0, value as usize) as *const c_char;
+            let value = unsafe { CStr::from_ptr(value_addr) }
+                .to_string_lossy()
+                .into_owned();
+            debug!("=> name({:?})", name);
+            debug!("=> value({:?})", value);
+            with_environment(instance, |env, instance| {
+                env.setenv(instance, &name, &value, overwrite != 0)
+            });
+            0
+        }
+        None => {
+            set_errno(instance, errno::EINVAL);
+            -1
+        }
+    };
+    trace_call!(
+        instance,
+        "setenv",
+        ret,
+        if ret < 0 { errno::EINVAL } else { 0 },
+        "{}, {}, {}",
+        guest_str(instance, name as u32),
+        guest_str(instance, value as u32),
+        overwrite
+    );
+    ret
+}
+
+/// emscripten: _putenv // (name: *const char);
+pub extern "C" fn _putenv(name: c_int, instance: &mut Instance) -> c_int {
+    debug!("emscripten::_putenv");
+
+    let string = if name == 0 {
+        String::new()
+    } else {
+        let name_addr = instance.memory_offset_addr(0, name as usize) as *const c_char;
+        unsafe { CStr::from_ptr(name_addr) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    debug!("=> name({:?})", string);
+
+    let ret = match string.find('=') {
+        Some(index) if index > 0 => {
+            let (name, value) = (&string[..index], &string[index + 1..]);
+            with_environment(instance, |env, instance| {
+                env.setenv(instance, name, value, true)
+            });
+            0
+        }
+        _ => {
+            set_errno(instance, errno::EINVAL);
+            -1
+        }
+    };
+    trace_call!(
+        instance,
+        "putenv",
+        ret,
+        if ret < 0 { errno::EINVAL } else { 0 },
+        "{}",
+        guest_str(instance, name as u32)
+    );
+    ret
+}
+
+/// emscripten: _unsetenv // (name: *const char);
+pub extern "C" fn _unsetenv(name: c_int, instance: &mut Instance) -> c_int {
+    debug!("emscripten::_unsetenv");
+
+    let ret = match variable_name(instance, name) {
+        Some(name) => {
+            debug!("=> name({:?})", name);
+            with_environment(instance, |env, instance| env.unsetenv(instance, &name));
+            0
+        }
+        None => {
+            set_errno(instance, errno::EINVAL);
+            -1
+        }
+    };
+    trace_call!(
+        instance,
+        "unsetenv",
+        ret,
+        if ret < 0 { errno::EINVAL } else { 0 },
+        "{}",
+        guest_str(instance, name as u32)
+    );
+    ret
 }
 
 pub extern "C" fn _getpwnam(name_ptr: c_int, instance: &mut Instance) -> c_int {
@@ -82,7 +343,7 @@ pub extern "C" fn _getpwnam(name_ptr: c_int, instance: &mut Instance) -> c_int {
         CStr::from_ptr(memory_name_ptr)
     };
 
-    unsafe {
+    let ret = unsafe {
         let passwd = &*libc_getpwnam(name.as_ptr());
         let passwd_struct_offset = (instance.emscripten_data.as_ref().unwrap().malloc)(
             mem::size_of::<GuestPasswd>() as _,
@@ -100,7 +361,16 @@ pub extern "C" fn _getpwnam(name_ptr: c_int, instance: &mut Instance) -> c_int {
         (*passwd_struct_ptr).pw_gid = passwd.pw_gid;
 
         passwd_struct_offset as c_int
-    }
+    };
+    trace_call!(
+        instance,
+        "getpwnam",
+        ret,
+        0,
+        "{}",
+        guest_str(instance, name_ptr as u32)
+    );
+    ret
 }
 
 pub extern "C" fn _getgrnam(name_ptr: c_int, instance: &mut Instance) -> c_int {
@@ -119,7 +389,7 @@ pub extern "C" fn _getgrnam(name_ptr: c_int, instance: &mut Instance) -> c_int {
         CStr::from_ptr(memory_name_ptr)
     };
 
-    unsafe {
+    let ret = unsafe {
         let group = &*libc_getgrnam(name.as_ptr());
         let group_struct_offset = (instance.emscripten_data.as_ref().unwrap().malloc)(
             mem::size_of::<GuestGroup>() as _,
@@ -134,7 +404,16 @@ pub extern "C" fn _getgrnam(name_ptr: c_int, instance: &mut Instance) -> c_int {
         (*group_struct_ptr).gr_mem = copy_terminated_array_of_cstrs(instance, group.gr_mem);
 
         group_struct_offset as c_int
-    }
+    };
+    trace_call!(
+        instance,
+        "getgrnam",
+        ret,
+        0,
+        "{}",
+        guest_str(instance, name_ptr as u32)
+    );
+    ret
 }
 
 pub extern "C" fn _getpagesize() -> u32 {
@@ -142,30 +421,152 @@ pub extern "C" fn _getpagesize() -> u32 {
     16384
 }
 
+/// emscripten: ___buildEnvironment // (environ: *mut *mut *mut c_char);
+/// Points the guest `environ` to the strings of the environment, which are
+/// rebuilt when it changes.
 pub extern "C" fn ___build_environment(environ: c_int, instance: &mut Instance) {
     debug!("emscripten::___build_environment {}", environ);
-    const MAX_ENV_VALUES: u32 = 64;
-    const TOTAL_ENV_SIZE: u32 = 1024;
-    let mut environment = instance.memory_offset_addr(0, environ as _) as *mut c_int;
-    unsafe {
-        let (pool_offset, pool_slice): (u32, &mut [u8]) =
-            allocate_on_stack(TOTAL_ENV_SIZE as u32, instance);
-        let (env_offset, env_slice): (u32, &mut [u8]) =
-            allocate_on_stack((MAX_ENV_VALUES * 4) as u32, instance);
-        let mut env_ptr = instance.memory_offset_addr(0, env_offset as _) as *mut c_int;
-        let mut pool_ptr = instance.memory_offset_addr(0, pool_offset as _) as *mut c_int;
-        *env_ptr = pool_offset as i32;
-        *environment = env_offset as i32;
-
-        // *env_ptr = 0;
-    };
-    // unsafe {
-    //     *env_ptr = 0;
-    // };
+    with_environment(instance, |env, instance| {
+        env.set_environ(instance, environ as u32)
+    });
 }
 
-pub extern "C" fn _sysconf(name: c_int, _instance: &mut Instance) -> c_long {
+pub extern "C" fn _sysconf(name: c_int, instance: &mut Instance) -> c_long {
     debug!("emscripten::_sysconf {}", name);
     // TODO: Implement like emscripten expects regarding memory/page size
-    unsafe { sysconf(name) }
+    let ret = unsafe { sysconf(name) };
+    // The errno of the host is not passed to the guest
+    trace_call!(instance, "sysconf", ret, 0, "{}", name);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Environment, GuestHeap};
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::env;
+
+    /// A guest memory that never reuses its allocations, to catch the uses
+    /// of the freed ones.
+    struct TestHeap {
+        memory: Vec<u8>,
+        freed: Vec<u32>,
+    }
+
+    /// The address of the guest `environ`.
+    const ENVIRON: u32 = 8;
+
+    impl TestHeap {
+        fn new() -> Self {
+            TestHeap {
+                memory: vec![0; 16],
+                freed: Vec::new(),
+            }
+        }
+
+        fn read_str(&self, ptr: u32) -> &str {
+            let bytes = &self.memory[ptr as usize..];
+            let len = bytes.iter().position(|&byte| byte == 0).unwrap();
+            ::std::str::from_utf8(&bytes[..len]).unwrap()
+        }
+
+        fn environ(&self) -> Vec<&str> {
+            let array = LittleEndian::read_u32(&self.memory[ENVIRON as usize..]);
+            self.memory[array as usize..]
+                .chunks(4)
+                .map(LittleEndian::read_u32)
+                .take_while(|&string| string != 0)
+                .map(|string| self.read_str(string))
+                .collect()
+        }
+    }
+
+    impl GuestHeap for TestHeap {
+        fn malloc(&mut self, size: u32) -> u32 {
+            let ptr = self.memory.len() as u32;
+            self.memory.resize(ptr as usize + size as usize, 0);
+            ptr
+        }
+
+        fn free(&mut self, ptr: u32) {
+            self.freed.push(ptr);
+        }
+
+        fn write(&mut self, ptr: u32, bytes: &[u8]) {
+            self.memory[ptr as usize..ptr as usize + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+
+    fn getenv<'a>(env: &mut Environment, heap: &'a mut TestHeap, name: &str) -> Option<&'a str> {
+        let value = env.getenv(heap, name)?;
+        Some(heap.read_str(value))
+    }
+
+    #[test]
+    fn test_host_variables_are_not_visible() {
+        env::set_var("WASMER_TEST_HOST_VARIABLE", "1");
+        let mut heap = TestHeap::new();
+        let mut env = Environment::default();
+        assert_eq!(
+            getenv(&mut env, &mut heap, "WASMER_TEST_HOST_VARIABLE"),
+            None
+        );
+        env.set_environ(&mut heap, ENVIRON);
+        assert_eq!(
+            heap.environ(),
+            vec![
+                "HOME=/home/web_user",
+                "LANG=C.UTF-8",
+                "LOGNAME=web_user",
+                "PATH=/",
+                "PWD=/",
+                "USER=web_user",
+                "_=./this.program"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_given_variables_override_the_defaults() {
+        let mut heap = TestHeap::new();
+        let mut env = Environment::new(vec![
+            ("HOME".to_string(), "/root".to_string()),
+            ("FOO".to_string(), "bar".to_string()),
+        ]);
+        assert_eq!(getenv(&mut env, &mut heap, "HOME"), Some("/root"));
+        env.set_environ(&mut heap, ENVIRON);
+        let environ = heap.environ();
+        assert_eq!(environ.len(), 8);
+        assert_eq!(environ[..2], ["FOO=bar", "HOME=/root"]);
+    }
+
+    #[test]
+    fn test_setenv_without_overwrite_keeps_the_value() {
+        let mut heap = TestHeap::new();
+        let mut env = Environment::default();
+        env.setenv(&mut heap, "USER", "me", false);
+        assert_eq!(getenv(&mut env, &mut heap, "USER"), Some("web_user"));
+        env.setenv(&mut heap, "FOO", "bar", false);
+        assert_eq!(getenv(&mut env, &mut heap, "FOO"), Some("bar"));
+        env.setenv(&mut heap, "USER", "me", true);
+        assert_eq!(getenv(&mut env, &mut heap, "USER"), Some("me"));
+    }
+
+    #[test]
+    fn test_getenv_values_of_other_variables_stay_valid() {
+        let mut heap = TestHeap::new();
+        let mut env = Environment::default();
+        env.set_environ(&mut heap, ENVIRON);
+        let home = env.getenv(&mut heap, "HOME").unwrap();
+        let user = env.getenv(&mut heap, "USER").unwrap();
+        env.setenv(&mut heap, "USER", "me", true);
+        env.unsetenv(&mut heap, "PATH");
+        // Only the string of the changed variable is freed
+        assert!(heap.freed.contains(&(user - "USER=".len() as u32)));
+        assert!(!heap.freed.contains(&(home - "HOME=".len() as u32)));
+        assert_eq!(heap.read_str(home), "/home/web_user");
+        let environ = heap.environ();
+        assert!(environ.contains(&"USER=me"));
+        assert!(!environ.iter().any(|string| string.starts_with("PATH=")));
+    }
 }
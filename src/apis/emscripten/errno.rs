@@ -1,9 +1,22 @@
 // use std::collections::HashMap;
+use crate::webassembly::Instance;
+
+/// Sets the `errno` of the guest, through the exported `___errno_location`.
+pub fn set_errno(instance: &mut Instance, value: i32) {
+    let errno_location = instance.emscripten_data.as_ref().unwrap().errno_location;
+    match errno_location {
+        Some(errno_location) => {
+            let addr = errno_location(instance);
+            unsafe { *(instance.memory_offset_addr(0, addr as _) as *mut i32) = value };
+        }
+        None => debug!("emscripten::set_errno: ___errno_location is not exported"),
+    }
+}
 
 pub extern "C" fn ___seterrno(value: i32) -> i32 {
     debug!("emscripten::___seterrno {}", value);
     // TODO: Incomplete impl
-    eprintln!("failed to set errno!");
+    warn!("failed to set errno!");
     value
 }
 
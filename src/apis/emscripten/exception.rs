@@ -1,15 +1,334 @@
-use super::process::_abort;
+//! The C++ exceptions, following the emscripten model (`EXCEPTIONS` in its
+//! `library.js`): a thrown exception is recorded by `___cxa_throw`, then
+//! unwinds to the innermost `invoke_*` trampoline (see `invoke.rs`). The
+//! landing pad of the guest asks `___cxa_find_matching_catch_N` whether one of
+//! its catch clauses matches, using the `___cxa_can_catch` exported by the
+//! module, and either catches it (`___cxa_begin_catch`/`___cxa_end_catch`) or
+//! resumes the unwinding (`___resumeException`).
+use std::collections::HashMap;
+use std::mem;
+
+use super::invoke::{dyn_call_addr, set_threw, throw};
+use super::process::abort_with_message;
 use crate::webassembly::Instance;
 
+struct ExceptionInfo {
+    /// The pointers to the exception object (or to one of its base classes)
+    /// given to the catch clauses that matched it.
+    adjusted: Vec<u32>,
+    ty: u32,
+    destructor: u32,
+    refcount: u32,
+    caught: bool,
+    rethrown: bool,
+}
+
+/// The exceptions thrown and not destroyed yet.
+#[derive(Default)]
+pub struct Exceptions {
+    infos: HashMap<u32, ExceptionInfo>,
+    /// The exception being thrown.
+    last: u32,
+    /// The exceptions being handled, the innermost last.
+    caught: Vec<u32>,
+    uncaught: u32,
+    /// A word of guest memory, given to `___cxa_can_catch`.
+    can_catch_buffer: u32,
+}
+
+impl Exceptions {
+    /// The exception whose object (or base class) is at `adjusted`.
+    fn de_adjust(&self, adjusted: u32) -> u32 {
+        if adjusted == 0 || self.infos.contains_key(&adjusted) {
+            return adjusted;
+        }
+        self.infos
+            .iter()
+            .find(|(_, info)| info.adjusted.contains(&adjusted))
+            .map(|(&ptr, _)| ptr)
+            .unwrap_or(adjusted)
+    }
+
+    fn add_ref(&mut self, ptr: u32) {
+        if let Some(info) = self.infos.get_mut(&ptr) {
+            info.refcount += 1;
+        }
+    }
+}
+
+fn exceptions(instance: &mut Instance) -> &mut Exceptions {
+    &mut instance.emscripten_data.as_mut().unwrap().exceptions
+}
+
+/// Drops a reference to the exception, destroying it when it was the last one.
+fn dec_ref(ptr: u32, instance: &mut Instance) {
+    let destructor = {
+        let exceptions = exceptions(instance);
+        let info = match exceptions.infos.get_mut(&ptr) {
+            Some(info) => info,
+            None => return,
+        };
+        info.refcount = info.refcount.saturating_sub(1);
+        if info.refcount > 0 || info.rethrown {
+            return;
+        }
+        let destructor = info.destructor;
+        exceptions.infos.remove(&ptr);
+        destructor
+    };
+    if destructor != 0 {
+        let dyn_call_vi: extern "C" fn(i32, i32, &Instance) =
+            unsafe { mem::transmute(dyn_call_addr("dynCall_vi", instance)) };
+        dyn_call_vi(destructor as i32, ptr as i32, instance);
+    }
+    ___cxa_free_exception(ptr, instance);
+}
+
 /// emscripten: ___cxa_allocate_exception
 pub extern "C" fn ___cxa_allocate_exception(size: u32, instance: &mut Instance) -> u32 {
     debug!("emscripten::___cxa_allocate_exception");
     (instance.emscripten_data.as_ref().unwrap().malloc)(size as _, instance)
 }
 
+/// emscripten: ___cxa_free_exception
+pub extern "C" fn ___cxa_free_exception(ptr: u32, instance: &mut Instance) {
+    debug!("emscripten::___cxa_free_exception {}", ptr);
+    let free = instance.emscripten_data.as_ref().unwrap().free;
+    free(ptr as _, instance);
+}
+
 /// emscripten: ___cxa_throw
-/// TODO: We don't have support for exceptions yet
 pub extern "C" fn ___cxa_throw(ptr: u32, ty: u32, destructor: u32, instance: &mut Instance) {
-    debug!("emscripten::___cxa_throw");
-    _abort();
+    debug!("emscripten::___cxa_throw {}", ptr);
+    {
+        let exceptions = exceptions(instance);
+        exceptions.infos.insert(
+            ptr,
+            ExceptionInfo {
+                adjusted: vec![ptr],
+                ty,
+                destructor,
+                refcount: 0,
+                caught: false,
+                rethrown: false,
+            },
+        );
+        exceptions.last = ptr;
+        exceptions.uncaught += 1;
+    }
+    throw(instance);
+}
+
+/// emscripten: ___cxa_rethrow
+pub extern "C" fn ___cxa_rethrow(instance: &mut Instance) {
+    debug!("emscripten::___cxa_rethrow");
+    {
+        let exceptions = exceptions(instance);
+        let ptr = exceptions.caught.pop().unwrap_or(0);
+        let ptr = exceptions.de_adjust(ptr);
+        if let Some(info) = exceptions.infos.get_mut(&ptr) {
+            if !info.rethrown {
+                info.rethrown = true;
+                exceptions.caught.push(ptr);
+            }
+        }
+        exceptions.last = ptr;
+    }
+    throw(instance);
+}
+
+/// emscripten: ___resumeException
+/// Continues the unwinding, when no catch clause of a landing pad matched.
+pub extern "C" fn ___resume_exception(ptr: u32, instance: &mut Instance) {
+    debug!("emscripten::___resumeException {}", ptr);
+    {
+        let exceptions = exceptions(instance);
+        if exceptions.last == 0 {
+            exceptions.last = ptr;
+        }
+    }
+    throw(instance);
+}
+
+/// emscripten: ___cxa_begin_catch
+pub extern "C" fn ___cxa_begin_catch(ptr: u32, instance: &mut Instance) -> u32 {
+    debug!("emscripten::___cxa_begin_catch {}", ptr);
+    let exceptions = exceptions(instance);
+    if let Some(info) = exceptions.infos.get_mut(&ptr) {
+        if !info.caught {
+            info.caught = true;
+            exceptions.uncaught = exceptions.uncaught.saturating_sub(1);
+        }
+        info.rethrown = false;
+    }
+    exceptions.caught.push(ptr);
+    let primary = exceptions.de_adjust(ptr);
+    exceptions.add_ref(primary);
+    ptr
+}
+
+/// emscripten: ___cxa_end_catch
+pub extern "C" fn ___cxa_end_catch(instance: &mut Instance) {
+    debug!("emscripten::___cxa_end_catch");
+    // The exception is handled, clear __THREW__
+    set_threw(0, 0, instance);
+    let ptr = exceptions(instance).caught.pop().unwrap_or(0);
+    if ptr != 0 {
+        let primary = exceptions(instance).de_adjust(ptr);
+        dec_ref(primary, instance);
+        exceptions(instance).last = 0;
+    }
+}
+
+fn find_matching_catch(types: &[u32], instance: &mut Instance) -> u32 {
+    let thrown = exceptions(instance).last;
+    let thrown_type = match exceptions(instance).infos.get(&thrown) {
+        Some(info) if thrown != 0 => info.ty,
+        _ => 0,
+    };
+    if thrown_type == 0 {
+        instance.emscripten_data.as_mut().unwrap().temp_ret_0 = 0;
+        return thrown;
+    }
+
+    // `___cxa_can_catch` adjusts the pointer for the catch clauses of a base
+    // class, so it takes it in guest memory
+    if exceptions(instance).can_catch_buffer == 0 {
+        let malloc = instance.emscripten_data.as_ref().unwrap().malloc;
+        exceptions(instance).can_catch_buffer = malloc(4, instance);
+    }
+    let buffer = exceptions(instance).can_catch_buffer;
+    let buffer_addr = instance.memory_offset_addr(0, buffer as usize) as *mut u32;
+    unsafe { *buffer_addr = thrown };
+
+    let can_catch = instance.emscripten_data.as_ref().unwrap().cxa_can_catch;
+    let mut selector = thrown_type;
+    if let Some(can_catch) = can_catch {
+        for &ty in types {
+            if ty != 0 && can_catch(ty, thrown_type, buffer, instance) != 0 {
+                selector = ty;
+                break;
+            }
+        }
+    }
+    let adjusted = unsafe { *buffer_addr };
+    if selector != thrown_type {
+        if let Some(info) = exceptions(instance).infos.get_mut(&thrown) {
+            info.adjusted.push(adjusted);
+        }
+    }
+    instance.emscripten_data.as_mut().unwrap().temp_ret_0 = selector as i32;
+    adjusted
+}
+
+/// emscripten: ___cxa_find_matching_catch_2 (for a landing pad without typed catch clauses)
+pub extern "C" fn ___cxa_find_matching_catch_2(instance: &mut Instance) -> u32 {
+    debug!("emscripten::___cxa_find_matching_catch_2");
+    find_matching_catch(&[], instance)
+}
+
+/// emscripten: ___cxa_find_matching_catch_3
+pub extern "C" fn ___cxa_find_matching_catch_3(type1: u32, instance: &mut Instance) -> u32 {
+    debug!("emscripten::___cxa_find_matching_catch_3");
+    find_matching_catch(&[type1], instance)
+}
+
+/// emscripten: ___cxa_find_matching_catch_4
+pub extern "C" fn ___cxa_find_matching_catch_4(
+    type1: u32,
+    type2: u32,
+    instance: &mut Instance,
+) -> u32 {
+    debug!("emscripten::___cxa_find_matching_catch_4");
+    find_matching_catch(&[type1, type2], instance)
+}
+
+/// emscripten: ___cxa_find_matching_catch_5
+pub extern "C" fn ___cxa_find_matching_catch_5(
+    type1: u32,
+    type2: u32,
+    type3: u32,
+    instance: &mut Instance,
+) -> u32 {
+    debug!("emscripten::___cxa_find_matching_catch_5");
+    find_matching_catch(&[type1, type2, type3], instance)
+}
+
+/// emscripten: ___cxa_find_matching_catch_6
+pub extern "C" fn ___cxa_find_matching_catch_6(
+    type1: u32,
+    type2: u32,
+    type3: u32,
+    type4: u32,
+    instance: &mut Instance,
+) -> u32 {
+    debug!("emscripten::___cxa_find_matching_catch_6");
+    find_matching_catch(&[type1, type2, type3, type4], instance)
+}
+
+/// emscripten: ___cxa_uncaught_exception
+pub extern "C" fn ___cxa_uncaught_exception(instance: &mut Instance) -> i32 {
+    debug!("emscripten::___cxa_uncaught_exception");
+    (exceptions(instance).uncaught > 0) as i32
+}
+
+/// emscripten: ___cxa_current_primary_exception
+pub extern "C" fn ___cxa_current_primary_exception(instance: &mut Instance) -> u32 {
+    debug!("emscripten::___cxa_current_primary_exception");
+    let exceptions = exceptions(instance);
+    let ptr = exceptions.caught.last().cloned().unwrap_or(0);
+    if ptr != 0 {
+        let primary = exceptions.de_adjust(ptr);
+        exceptions.add_ref(primary);
+    }
+    ptr
+}
+
+/// emscripten: ___cxa_rethrow_primary_exception
+pub extern "C" fn ___cxa_rethrow_primary_exception(ptr: u32, instance: &mut Instance) {
+    debug!("emscripten::___cxa_rethrow_primary_exception {}", ptr);
+    if ptr == 0 {
+        return;
+    }
+    {
+        let exceptions = exceptions(instance);
+        exceptions.caught.push(ptr);
+        if let Some(info) = exceptions.infos.get_mut(&ptr) {
+            info.rethrown = true;
+        }
+    }
+    ___cxa_rethrow(instance);
+}
+
+/// emscripten: ___cxa_increment_exception_refcount
+pub extern "C" fn ___cxa_increment_exception_refcount(ptr: u32, instance: &mut Instance) {
+    debug!("emscripten::___cxa_increment_exception_refcount {}", ptr);
+    let exceptions = exceptions(instance);
+    let primary = exceptions.de_adjust(ptr);
+    exceptions.add_ref(primary);
+}
+
+/// emscripten: ___cxa_decrement_exception_refcount
+pub extern "C" fn ___cxa_decrement_exception_refcount(ptr: u32, instance: &mut Instance) {
+    debug!("emscripten::___cxa_decrement_exception_refcount {}", ptr);
+    let primary = exceptions(instance).de_adjust(ptr);
+    dec_ref(primary, instance);
+}
+
+/// emscripten: ___gxx_personality_v0
+pub extern "C" fn ___gxx_personality_v0() -> i32 {
+    debug!("emscripten::___gxx_personality_v0");
+    0
+}
+
+/// emscripten: _llvm_eh_typeid_for
+pub extern "C" fn _llvm_eh_typeid_for(type_info: u32) -> u32 {
+    debug!("emscripten::_llvm_eh_typeid_for {}", type_info);
+    type_info
+}
+
+/// emscripten: ___cxa_pure_virtual
+pub extern "C" fn ___cxa_pure_virtual() {
+    debug!("emscripten::___cxa_pure_virtual");
+    abort_with_message("Pure virtual function called!");
 }
@@ -0,0 +1,227 @@
+//! A directory of the host, mounted in the `Vfs` of a guest (`--dir` and
+//! `--mapdir`).
+//!
+//! The guest paths are normalized by the `Vfs`, so `..` never leaves the
+//! mount. The symlinks are resolved on the host, and the paths that end up
+//! outside of the directory fail with `EACCES`.
+use std::fs::{self, DirBuilder, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, DirEntryExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use super::vfs::{errno, io_errno, DirEntry, FileHandle, FileSystem, OpenFlags, Stat, VfsResult};
+
+fn metadata_stat(metadata: &Metadata) -> Stat {
+    Stat {
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+        mode: metadata.mode(),
+        nlink: metadata.nlink() as u32,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: metadata.rdev(),
+        size: metadata.size(),
+        blocks: metadata.blocks(),
+        atime: metadata.atime() as u64,
+        mtime: metadata.mtime() as u64,
+        ctime: metadata.ctime() as u64,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HostFileSystem {
+    /// The canonical path of the directory.
+    root: PathBuf,
+}
+
+impl HostFileSystem {
+    /// Exposes the host directory `root` (which must exist).
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(HostFileSystem { root })
+    }
+
+    /// Checks that a canonical host path is inside the directory.
+    fn check(&self, host_path: PathBuf) -> VfsResult<PathBuf> {
+        if host_path.starts_with(&self.root) {
+            Ok(host_path)
+        } else {
+            Err(errno::EACCES)
+        }
+    }
+
+    /// The host path of an existing file, with all the symlinks resolved.
+    fn resolve(&self, path: &str) -> VfsResult<PathBuf> {
+        let host_path = self.root.join(path.trim_start_matches('/'));
+        self.check(host_path.canonicalize().map_err(io_errno)?)
+    }
+
+    /// The host path of a directory entry, resolving the symlinks of its
+    /// parent but not the entry itself (for `unlink`, `rename`...).
+    fn resolve_entry(&self, path: &str) -> VfsResult<PathBuf> {
+        let path = path.trim_start_matches('/');
+        let relative = Path::new(path);
+        match (relative.parent(), relative.file_name()) {
+            (Some(parent), Some(name)) => Ok(self.resolve(parent.to_str().unwrap())?.join(name)),
+            // The root of the filesystem
+            _ => Err(errno::EBUSY),
+        }
+    }
+}
+
+impl FileSystem for HostFileSystem {
+    fn open(&mut self, path: &str, flags: OpenFlags, mode: u32) -> VfsResult<Box<FileHandle>> {
+        let host_path = if flags.create {
+            let entry = self.resolve_entry(path)?;
+            match fs::symlink_metadata(&entry) {
+                // A file, or a symlink that must stay in the directory
+                Ok(_) if !flags.exclusive => self.resolve(path)?,
+                Ok(_) => return Err(errno::EEXIST),
+                Err(_) => entry,
+            }
+        } else {
+            self.resolve(path)?
+        };
+        let metadata = fs::metadata(&host_path).ok();
+        let is_dir = metadata.as_ref().map(|metadata| metadata.is_dir());
+        if flags.directory && is_dir == Some(false) {
+            return Err(errno::ENOTDIR);
+        }
+        if flags.write && is_dir == Some(true) {
+            return Err(errno::EISDIR);
+        }
+        let file = OpenOptions::new()
+            // Directories are opened read-only, for fstat
+            .read(flags.read || is_dir == Some(true))
+            .write(flags.write)
+            .append(flags.append)
+            .create(flags.create && flags.write)
+            .create_new(flags.create && flags.exclusive && flags.write)
+            .truncate(flags.truncate && flags.write)
+            .mode(mode)
+            .open(&host_path)
+            .map_err(io_errno)?;
+        Ok(Box::new(HostFile { file }))
+    }
+
+    fn stat(&mut self, path: &str) -> VfsResult<Stat> {
+        let host_path = self.resolve(path)?;
+        fs::metadata(host_path)
+            .map(|metadata| metadata_stat(&metadata))
+            .map_err(io_errno)
+    }
+
+    fn mkdir(&mut self, path: &str, mode: u32) -> VfsResult<()> {
+        let host_path = self.resolve_entry(path)?;
+        DirBuilder::new()
+            .mode(mode)
+            .create(host_path)
+            .map_err(io_errno)
+    }
+
+    fn rmdir(&mut self, path: &str) -> VfsResult<()> {
+        let host_path = self.resolve_entry(path)?;
+        fs::remove_dir(host_path).map_err(io_errno)
+    }
+
+    fn unlink(&mut self, path: &str) -> VfsResult<()> {
+        let host_path = self.resolve_entry(path)?;
+        fs::remove_file(host_path).map_err(io_errno)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> VfsResult<()> {
+        let from = self.resolve_entry(from)?;
+        let to = self.resolve_entry(to)?;
+        fs::rename(from, to).map_err(io_errno)
+    }
+
+    fn read_dir(&mut self, path: &str) -> VfsResult<Vec<DirEntry>> {
+        let host_path = self.resolve(path)?;
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(host_path).map_err(io_errno)? {
+            let entry = entry.map_err(io_errno)?;
+            entries.push(DirEntry {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                ino: entry.ino(),
+                is_dir: entry.file_type().map_err(io_errno)?.is_dir(),
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// A file opened in a `HostFileSystem`.
+#[derive(Debug)]
+struct HostFile {
+    file: File,
+}
+
+impl FileHandle for HostFile {
+    fn read(&mut self, buf: &mut [u8]) -> VfsResult<usize> {
+        self.file.read(buf).map_err(io_errno)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> VfsResult<usize> {
+        self.file.write(buf).map_err(io_errno)
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> VfsResult<u64> {
+        self.file.seek(pos).map_err(io_errno)
+    }
+
+    fn stat(&self) -> VfsResult<Stat> {
+        self.file
+            .metadata()
+            .map(|metadata| metadata_stat(&metadata))
+            .map_err(io_errno)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HostFileSystem;
+    use crate::apis::emscripten::vfs::{errno, Vfs, O_CREAT, O_WRONLY};
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_hostfs_sandbox() {
+        let dir = std::env::temp_dir().join(format!("wasmer-hostfs-{}", std::process::id()));
+        let data = dir.join("data");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("input.txt"), b"hello").unwrap();
+        fs::write(dir.join("secret.txt"), b"secret").unwrap();
+        symlink("../secret.txt", data.join("escape")).unwrap();
+        symlink("input.txt", data.join("link")).unwrap();
+
+        let mut vfs = Vfs::default();
+        vfs.mount("/data", Box::new(HostFileSystem::new(&data).unwrap()));
+
+        assert_eq!(vfs.stat("/data/input.txt").unwrap().size, 5);
+        assert_eq!(vfs.stat("/data/link").unwrap().size, 5);
+        assert_eq!(vfs.stat("/data/../secret.txt"), Err(errno::ENOENT));
+        assert_eq!(vfs.stat("/data/escape"), Err(errno::EACCES));
+        assert_eq!(
+            vfs.open("/data/escape", O_WRONLY | O_CREAT, 0o644),
+            Err(errno::EACCES)
+        );
+
+        let fd = vfs
+            .open("/data/out.txt", O_WRONLY | O_CREAT, 0o644)
+            .unwrap();
+        assert_eq!(vfs.write(fd, b"result"), Ok(6));
+        vfs.close(fd).unwrap();
+        assert_eq!(fs::read(data.join("out.txt")).unwrap(), b"result");
+        // Removes the symlink, not its target
+        vfs.unlink("/data/escape").unwrap();
+        assert!(dir.join("secret.txt").is_file());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,291 @@
+//! The `invoke_*` trampolines, through which emscripten calls the functions
+//! that may throw a C++ exception or `longjmp`.
+//!
+//! In JS, `invoke_vii(index, a1, a2)` calls `dynCall_vii(index, a1, a2)` (the
+//! function at `index` in the table) inside a `try` block. When the callee
+//! throws, the `catch` block restores the stack pointer and calls
+//! `setThrew(1, 0)`, and the compiled code checks `__THREW__` after the call
+//! to find out what happened.
+//!
+//! Here each trampoline saves its host context with `setjmp`, and throwing
+//! `longjmp`s to the innermost trampoline. The frames skipped are wasm frames,
+//! and the host function that throws, which must not own anything to drop.
+//!
+//! The trampolines are native functions, so only the signatures defined here
+//! (the ones with up to 9 `i32` arguments) are supported. The module fails to
+//! link when it imports another one, rather than mocking it (its exceptions
+//! wouldn't be caught anymore).
+use libc::{c_int, c_void};
+use std::mem;
+
+use super::process::abort_with_message;
+use crate::webassembly::Instance;
+
+/// Large enough for the `jmp_buf` of glibc (200 bytes) and macOS (148 bytes).
+const JMP_BUF_LEN: usize = 32;
+
+pub type JumpBuffer = [u64; JMP_BUF_LEN];
+
+extern "C" {
+    fn setjmp(env: *mut c_void) -> c_int;
+    fn longjmp(env: *mut c_void, val: c_int) -> !;
+}
+
+/// Transfers the control to the innermost `invoke_*` trampoline, like a JS `throw`.
+/// Without any, the exception can't be caught and the program aborts.
+pub fn throw(instance: &mut Instance) -> ! {
+    let jump_buf = instance
+        .emscripten_data
+        .as_mut()
+        .unwrap()
+        .invoke_jumps
+        .last_mut()
+        .map(|jump_buf| jump_buf.as_mut_ptr() as *mut c_void);
+    match jump_buf {
+        Some(jump_buf) => unsafe { longjmp(jump_buf, 1) },
+        None => abort_with_message("uncaught exception"),
+    }
+}
+
+/// Calls the exported `stackSave`.
+pub fn stack_save(instance: &Instance) -> u32 {
+    let stack_save = instance.emscripten_data.as_ref().unwrap().stack_save;
+    stack_save
+        .map(|stack_save| stack_save(instance))
+        .unwrap_or(0)
+}
+
+/// Calls the exported `stackRestore`.
+pub fn stack_restore(sp: u32, instance: &Instance) {
+    let stack_restore = instance.emscripten_data.as_ref().unwrap().stack_restore;
+    if let Some(stack_restore) = stack_restore {
+        stack_restore(sp, instance);
+    }
+}
+
+/// Calls the exported `setThrew`, which sets `__THREW__` and `threwValue`
+/// unless a throw is already recorded.
+pub fn set_threw(threw: i32, value: i32, instance: &Instance) {
+    let set_threw = instance.emscripten_data.as_ref().unwrap().set_threw;
+    if let Some(set_threw) = set_threw {
+        set_threw(threw, value, instance);
+    }
+}
+
+/// The address of the exported `dynCall_*` function (calling a function of
+/// the table with the given signature).
+pub fn dyn_call_addr(name: &str, instance: &Instance) -> *const u8 {
+    let dyn_calls = &instance.emscripten_data.as_ref().unwrap().dyn_calls;
+    match dyn_calls.get(name) {
+        Some(&addr) => addr,
+        None => abort_with_message(format!("{} is not exported by the module", name)),
+    }
+}
+
+fn push_jump_buffer(instance: &mut Instance) -> *mut c_void {
+    let invoke_jumps = &mut instance.emscripten_data.as_mut().unwrap().invoke_jumps;
+    invoke_jumps.push(Box::new([0; JMP_BUF_LEN]));
+    invoke_jumps.last_mut().unwrap().as_mut_ptr() as *mut c_void
+}
+
+fn pop_jump_buffer(instance: &mut Instance) {
+    instance
+        .emscripten_data
+        .as_mut()
+        .unwrap()
+        .invoke_jumps
+        .pop();
+}
+
+macro_rules! invoke {
+    ($name:ident, $dyn_call:expr, ($($arg:ident: $ty:ty),*)) => {
+        invoke!($name, $dyn_call, ($($arg: $ty),*) -> (), ());
+    };
+    ($name:ident, $dyn_call:expr, ($($arg:ident: $ty:ty),*) -> $ret:ty, $default:expr) => {
+        /// emscripten: calls a function of the table, returning normally if it throws
+        pub extern "C" fn $name(index: i32, $($arg: $ty,)* instance: &mut Instance) -> $ret {
+            debug!(concat!("emscripten::", stringify!($name), " {}"), index);
+            let dyn_call: extern "C" fn(i32, $($ty,)* &Instance) -> $ret =
+                unsafe { mem::transmute(dyn_call_addr($dyn_call, instance)) };
+            let sp = stack_save(instance);
+            let jump_buf = push_jump_buffer(instance);
+            if unsafe { setjmp(jump_buf) } == 0 {
+                let ret = dyn_call(index, $($arg,)* instance);
+                pop_jump_buffer(instance);
+                ret
+            } else {
+                // The callee threw an exception or called longjmp
+                pop_jump_buffer(instance);
+                stack_restore(sp, instance);
+                set_threw(1, 0, instance);
+                $default
+            }
+        }
+    };
+}
+
+invoke!(invoke_v, "dynCall_v", ());
+invoke!(invoke_vi, "dynCall_vi", (a1: i32));
+invoke!(invoke_vii, "dynCall_vii", (a1: i32, a2: i32));
+invoke!(invoke_viii, "dynCall_viii", (a1: i32, a2: i32, a3: i32));
+invoke!(invoke_viiii, "dynCall_viiii", (a1: i32, a2: i32, a3: i32, a4: i32));
+invoke!(
+    invoke_viiiii,
+    "dynCall_viiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32, a5: i32)
+);
+invoke!(
+    invoke_viiiiii,
+    "dynCall_viiiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32, a5: i32, a6: i32)
+);
+invoke!(
+    invoke_viiiiiii,
+    "dynCall_viiiiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32, a5: i32, a6: i32, a7: i32)
+);
+invoke!(
+    invoke_viiiiiiii,
+    "dynCall_viiiiiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32, a5: i32, a6: i32, a7: i32, a8: i32)
+);
+invoke!(
+    invoke_viiiiiiiii,
+    "dynCall_viiiiiiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32, a5: i32, a6: i32, a7: i32, a8: i32, a9: i32)
+);
+invoke!(invoke_i, "dynCall_i", () -> i32, 0);
+invoke!(invoke_ii, "dynCall_ii", (a1: i32) -> i32, 0);
+invoke!(invoke_iii, "dynCall_iii", (a1: i32, a2: i32) -> i32, 0);
+invoke!(invoke_iiii, "dynCall_iiii", (a1: i32, a2: i32, a3: i32) -> i32, 0);
+invoke!(
+    invoke_iiiii,
+    "dynCall_iiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32) -> i32,
+    0
+);
+invoke!(
+    invoke_iiiiii,
+    "dynCall_iiiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32, a5: i32) -> i32,
+    0
+);
+invoke!(
+    invoke_iiiiiii,
+    "dynCall_iiiiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32, a5: i32, a6: i32) -> i32,
+    0
+);
+invoke!(
+    invoke_iiiiiiii,
+    "dynCall_iiiiiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32, a5: i32, a6: i32, a7: i32) -> i32,
+    0
+);
+invoke!(
+    invoke_iiiiiiiii,
+    "dynCall_iiiiiiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32, a5: i32, a6: i32, a7: i32, a8: i32) -> i32,
+    0
+);
+invoke!(
+    invoke_iiiiiiiiii,
+    "dynCall_iiiiiiiiii",
+    (a1: i32, a2: i32, a3: i32, a4: i32, a5: i32, a6: i32, a7: i32, a8: i32, a9: i32) -> i32,
+    0
+);
+
+/// emscripten: setTempRet0
+/// `tempRet0` holds the high 32 bits of the i64 results, and the type
+/// selector of a caught exception.
+pub extern "C" fn set_temp_ret_0(value: i32, instance: &mut Instance) {
+    debug!("emscripten::setTempRet0 {}", value);
+    instance.emscripten_data.as_mut().unwrap().temp_ret_0 = value;
+}
+
+/// emscripten: getTempRet0
+pub extern "C" fn get_temp_ret_0(instance: &mut Instance) -> i32 {
+    debug!("emscripten::getTempRet0");
+    instance.emscripten_data.as_ref().unwrap().temp_ret_0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::apis::generate_emscripten_env;
+    use crate::webassembly::{
+        get_isa, instantiate, start_instance, ErrorKind, Features, InstanceABI, InstanceOptions,
+    };
+    use wabt::wat2wasm;
+
+    const WAT: &str = r#"
+        (module
+          (type $vi (func (param i32)))
+          (import "env" "invoke_vi" (func $invoke_vi (param i32 i32)))
+          (import "env" "___cxa_throw" (func $throw (param i32 i32 i32)))
+          (memory 256 256)
+          (table 2 2 anyfunc)
+          (elem (i32.const 0) $thrower $returner)
+          (global $threw (mut i32) (i32.const 0))
+          (func $thrower (param i32)
+            (call $throw (i32.const 16) (i32.const 0) (i32.const 0)))
+          (func $returner (param i32))
+          (func (export "dynCall_vi") (param i32 i32)
+            (call_indirect (type $vi) (get_local 1) (get_local 0)))
+          (func (export "setThrew") (param i32 i32)
+            (if (i32.eqz (get_global $threw))
+              (then (set_global $threw (get_local 0)))))
+          (func (export "stackSave") (result i32) (i32.const 0))
+          (func (export "stackRestore") (param i32))
+          (func (export "_main") (result i32)
+            (call $invoke_vi (i32.const 1) (i32.const 0))
+            (if (get_global $threw) (then (return (i32.const 2))))
+            (call $invoke_vi (i32.const 0) (i32.const 0))
+            (get_global $threw)))
+    "#;
+
+    fn options() -> Option<InstanceOptions> {
+        Some(InstanceOptions {
+            mock_missing_imports: true,
+            mock_missing_globals: true,
+            mock_missing_tables: true,
+            abi: InstanceABI::Emscripten,
+            show_progressbar: false,
+            isa: get_isa(),
+            features: Features::default(),
+            gdb_jit: false,
+            perf_map: false,
+            jitdump: false,
+            trace_syscalls: None,
+            coverage: false,
+            vfs: None,
+            env: Vec::new(),
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            stack_size: None,
+        })
+    }
+
+    #[test]
+    fn test_invoke_catches_throw() {
+        let wasm = wat2wasm(WAT).unwrap();
+        let mut result_object = instantiate(wasm, generate_emscripten_env(), options()).unwrap();
+        let code = start_instance(
+            &result_object.module,
+            &mut result_object.instance,
+            "invoke",
+            vec![],
+        );
+        // The first call returns normally, the second one throws and sets `__THREW__`
+        assert_eq!(code, Ok(1));
+    }
+
+    #[test]
+    fn test_unsupported_invoke_fails_to_link() {
+        let wasm = wat2wasm(WAT.replace("invoke_vi", "invoke_vid")).unwrap();
+        match instantiate(wasm, generate_emscripten_env(), options()) {
+            Err(ErrorKind::LinkError(message)) => assert!(message.contains("env.invoke_vid")),
+            _ => panic!("the module shouldn't link"),
+        }
+    }
+}
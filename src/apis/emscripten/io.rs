@@ -1,15 +1,33 @@
-use libc::printf as _printf;
-
+use super::errno::set_errno;
+use super::printf::format;
 use crate::webassembly::Instance;
 
 /// putchar
-pub use libc::putchar;
+/// Writes to the stdout of the guest.
+pub extern "C" fn putchar(chr: i32, instance: &mut Instance) -> i32 {
+    debug!("emscripten::putchar {}", chr);
+    let vfs = &mut instance.emscripten_data.as_mut().unwrap().vfs;
+    match vfs.write(1, &[chr as u8]) {
+        Ok(_) => chr & 0xff,
+        // EOF
+        Err(_) => -1,
+    }
+}
 
 /// printf
-pub extern "C" fn printf(memory_offset: i32, extra: i32, instance: &Instance) -> i32 {
-    debug!("emscripten::printf {}, {}", memory_offset, extra);
-    unsafe {
-        let addr = instance.memory_offset_addr(0, memory_offset as _) as _;
-        _printf(addr, extra)
+/// Formats the guest arguments at `varargs` and writes to the stdout of the guest.
+pub extern "C" fn printf(format_offset: i32, varargs: i32, instance: &mut Instance) -> i32 {
+    debug!("emscripten::printf {}, {}", format_offset, varargs);
+    let formatted = match format(&instance.memories[0], format_offset as u32, varargs as u32) {
+        Ok(formatted) => formatted,
+        Err(errno) => {
+            set_errno(instance, errno);
+            return -1;
+        }
+    };
+    let vfs = &mut instance.emscripten_data.as_mut().unwrap().vfs;
+    match vfs.write(1, &formatted) {
+        Ok(written) => written as i32,
+        Err(_) => -1,
     }
 }
@@ -1,41 +1,38 @@
+//! setjmp/longjmp, following the emscripten protocol.
+//!
+//! Everything but the jump itself happens inside wasm: `setjmp` is compiled to
+//! a call to the exported `_saveSetjmp`, which records the `jmp_buf` in a
+//! table, and the calls that may `longjmp` go through the `invoke_*`
+//! trampolines. After such a call, the compiled code looks for `__THREW__` in
+//! its table with `_testSetjmp`, and resumes after the matching `setjmp`, with
+//! the value given to `longjmp` (read with `getTempRet0`).
+//!
+//! So `longjmp` only has to record the `jmp_buf` and the value with
+//! `setThrew`, and unwind to the innermost trampoline (see `invoke.rs`). When
+//! the `jmp_buf` is not in the table of the caller, it resumes the unwinding.
+use libc::c_int;
+
+use super::invoke::{set_threw, throw};
 use crate::webassembly::Instance;
-use libc::{c_int, c_void};
-use std::cell::UnsafeCell;
 
-/// setjmp
-pub extern "C" fn __setjmp(env_addr: u32, instance: &mut Instance) -> c_int {
-    debug!("emscripten::__setjmp (setjmp)");
-    unsafe {
-        // Rather than using the env as the holder of the jump buffer pointer,
-        // we use the environment address to store the index relative to jumps
-        // so the address of the jump it's outside the wasm memory itself.
-        let jump_index = instance.memory_offset_addr(0, env_addr as usize) as *mut i8;
-        // We create the jump buffer outside of the wasm memory
-        let jump_buf: UnsafeCell<[c_int; 27]> = UnsafeCell::new([0; 27]);
-        let mut jumps = &mut instance.emscripten_data.as_mut().unwrap().jumps;
-        let result = setjmp(jump_buf.get() as _);
-        // We set the jump index to be the last value of jumps
-        *jump_index = jumps.len() as _;
-        // We hold the reference of the jump buffer
-        jumps.push(jump_buf);
-        result
-    }
+/// emscripten: _longjmp
+pub extern "C" fn _longjmp(env_addr: u32, val: c_int, instance: &mut Instance) {
+    debug!("emscripten::_longjmp {} {}", env_addr, val);
+    // setjmp returns 1 when longjmp is given 0
+    let val = if val == 0 { 1 } else { val };
+    set_threw(env_addr as i32, val, instance);
+    throw(instance);
 }
 
-/// longjmp
-pub extern "C" fn __longjmp(env_addr: u32, val: c_int, instance: &mut Instance) -> ! {
-    debug!("emscripten::__longjmp (longjmp) {}", val);
-    unsafe {
-        // We retrieve the jump index from the env address
-        let jump_index = instance.memory_offset_addr(0, env_addr as usize) as *mut i8;
-        let mut jumps = &mut instance.emscripten_data.as_mut().unwrap().jumps;
-        // We get the real jump buffer from the jumps vector, using the retrieved index
-        let mut jump_buf = &jumps[*jump_index as usize];
-        longjmp(jump_buf.get() as _, val)
-    };
+/// emscripten: _emscripten_longjmp
+pub extern "C" fn _emscripten_longjmp(env_addr: u32, val: c_int, instance: &mut Instance) {
+    debug!("emscripten::_emscripten_longjmp {} {}", env_addr, val);
+    _longjmp(env_addr, val, instance);
 }
 
-extern "C" {
-    fn setjmp(env: *mut c_void) -> c_int;
-    fn longjmp(env: *mut c_void, val: c_int) -> !;
+/// emscripten: _siglongjmp
+/// Signal masks aren't emulated, so it is a plain longjmp.
+pub extern "C" fn _siglongjmp(env_addr: u32, val: c_int, instance: &mut Instance) {
+    debug!("emscripten::_siglongjmp {} {}", env_addr, val);
+    _longjmp(env_addr, val, instance);
 }
@@ -0,0 +1,202 @@
+//! The layout of the memory of an emscripten module, which emscripten's JS
+//! glue computes when building the module:
+//!
+//! ```text
+//! GLOBAL_BASE | static data | DYNAMICTOP_PTR | stack | heap (DYNAMIC_BASE..)
+//! ```
+//!
+//! The `emscripten_metadata` custom section (`-s EMIT_EMSCRIPTEN_METADATA=1`)
+//! records it. Otherwise we derive it from the data segments of the module.
+use super::storage::align_memory;
+use crate::webassembly::{ImportObject, ImportValue, LinearMemory, Module};
+use byteorder::{ByteOrder, LittleEndian};
+use std::mem;
+
+/// Where emscripten starts the static data.
+const GLOBAL_BASE: u32 = 1024;
+/// The default stack size of emscripten (`-s TOTAL_STACK`), 5 MiB.
+const TOTAL_STACK: u32 = 5_242_880;
+/// The static data of the emscripten modules built without metadata that
+/// we used to run, bss included.
+const DEFAULT_STATIC_BUMP: u32 = 215_536;
+/// The runtime allocates `tempDoublePtr` and a few other variables between
+/// the static data and `DYNAMICTOP_PTR`.
+const STATIC_RESERVED: u32 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLayout {
+    /// The address of the pointer to the top of the heap (the `sbrk` break)
+    pub dynamictop_ptr: u32,
+    pub stacktop: u32,
+    pub stack_max: u32,
+    /// Where the heap starts
+    pub dynamic_base: u32,
+}
+
+impl MemoryLayout {
+    /// The layout of `module` with a stack of `stack_size` bytes (the one the
+    /// module was built with by default). Fails when it doesn't fit in 4 GiB.
+    pub fn new(module: &Module, stack_size: Option<u32>) -> Result<Self, String> {
+        match module
+            .custom_sections("emscripten_metadata")
+            .first()
+            .and_then(|payload| read_metadata(payload))
+        {
+            Some((dynamic_base, dynamictop_ptr)) => {
+                let stack_size = match stack_size {
+                    Some(stack_size) => stack_size,
+                    // The stack fills the space up to the heap
+                    None => {
+                        let stacktop = Self::with_stack(dynamictop_ptr, 0)?.stacktop;
+                        dynamic_base.saturating_sub(stacktop)
+                    }
+                };
+                Self::with_stack(dynamictop_ptr, stack_size)
+            }
+            None => {
+                let static_bump = static_bump(module);
+                debug!("emscripten::layout static bump: {}", static_bump);
+                let dynamictop_ptr = GLOBAL_BASE
+                    .checked_add(static_bump)
+                    .and_then(|static_top| static_top.checked_add(STATIC_RESERVED))
+                    .ok_or_else(|| {
+                        format!(
+                            "the emscripten static data ({} bytes) doesn't fit in the memory",
+                            static_bump
+                        )
+                    })?;
+                Self::with_stack(dynamictop_ptr, stack_size.unwrap_or(TOTAL_STACK))
+            }
+        }
+    }
+
+    fn with_stack(dynamictop_ptr: u32, stack_size: u32) -> Result<Self, String> {
+        let layout = || -> Option<Self> {
+            let stacktop = checked_align(dynamictop_ptr.checked_add(4)?)?;
+            let stack_max = stacktop.checked_add(checked_align(stack_size)?)?;
+            Some(MemoryLayout {
+                dynamictop_ptr,
+                stacktop,
+                stack_max,
+                dynamic_base: checked_align(stack_max)?,
+            })
+        };
+        layout().ok_or_else(|| {
+            format!(
+                "the emscripten stack ({} bytes) doesn't fit in the memory",
+                stack_size
+            )
+        })
+    }
+
+    /// Sets the `STACKTOP`, `STACK_MAX` and `DYNAMICTOP_PTR` globals imported
+    /// by the module.
+    pub fn set_globals(&self, import_object: &mut ImportObject<&str, &str>) {
+        import_object.set("env", "STACKTOP", ImportValue::Global(self.stacktop as _));
+        import_object.set("env", "STACK_MAX", ImportValue::Global(self.stack_max as _));
+        import_object.set(
+            "env",
+            "DYNAMICTOP_PTR",
+            ImportValue::Global(self.dynamictop_ptr as _),
+        );
+    }
+}
+
+/// `align_memory`, failing on overflow.
+fn checked_align(ptr: u32) -> Option<u32> {
+    Some(ptr.checked_add(15)? & !15)
+}
+
+/// The static data of a module without metadata: its data segments, but the
+/// bss (zeroed) is not part of them, so we never go below the default.
+fn static_bump(module: &Module) -> u32 {
+    let data_end = module
+        .info
+        .data_initializers
+        .iter()
+        .filter(|init| init.base.is_none())
+        .map(|init| init.offset + init.data.len())
+        .max()
+        .unwrap_or(0) as u32;
+    align_memory(data_end.saturating_sub(GLOBAL_BASE)).max(DEFAULT_STATIC_BUMP)
+}
+
+/// Reads `DYNAMIC_BASE` and `DYNAMICTOP_PTR` from the `emscripten_metadata`
+/// section: a list of LEB128 fields, of which we need the 8th and 9th.
+/// See: https://github.com/kripken/emscripten/blob/incoming/tools/shared.py
+fn read_metadata(payload: &[u8]) -> Option<(u32, u32)> {
+    let mut fields = Vec::new();
+    let mut bytes = payload.iter();
+    while fields.len() < 9 {
+        let mut value: u32 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *bytes.next()?;
+            value |= u32::from(byte & 0x7f).checked_shl(shift)?;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        fields.push(value);
+    }
+    // major, minor, abi_major, abi_minor, mem_size, table_size, global_base
+    Some((fields[7], fields[8]))
+}
+
+/// Writes the initial top of the heap, growing the memory to hold the stack
+/// when possible. Fails when the layout does not fit in the memory.
+pub fn emscripten_set_up_memory(
+    memory: &mut LinearMemory,
+    layout: &MemoryLayout,
+) -> Result<(), String> {
+    let dynamic_base = layout.dynamic_base as usize;
+    if dynamic_base > memory.current_size() {
+        let missing = dynamic_base - memory.current_size();
+        let add_pages =
+            (missing + LinearMemory::PAGE_SIZE as usize - 1) / LinearMemory::PAGE_SIZE as usize;
+        if memory.grow(add_pages as u32).is_none() {
+            return Err(format!(
+                "the emscripten stack ends at {} bytes, beyond the maximum size of the memory ({} pages)",
+                dynamic_base,
+                memory.maximum_size()
+            ));
+        }
+    }
+    let dynamictop_ptr = layout.dynamictop_ptr as usize;
+    let mem = &mut memory[dynamictop_ptr..dynamictop_ptr + mem::size_of::<u32>()];
+    LittleEndian::write_u32(mem, layout.dynamic_base);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_metadata, MemoryLayout};
+
+    #[test]
+    fn test_read_metadata() {
+        // 0.1, abi 0.0, 16 MiB, 10 functions, base 1024, ...
+        let payload = [
+            0, 1, 0, 0, 0x80, 0x80, 0x80, 0x08, 10, 0x80, 0x08, 0x90, 0xa6, 0x54, 0xa0, 0x4e, 8,
+        ];
+        assert_eq!(read_metadata(&payload), Some((1_381_136, 10_016)));
+        assert_eq!(read_metadata(&payload[..12]), None);
+    }
+
+    #[test]
+    fn test_layout_with_stack() {
+        let layout = MemoryLayout::with_stack(216_624, 1000).unwrap();
+        assert_eq!(layout.stacktop, 216_640);
+        assert_eq!(layout.stack_max, 217_648);
+        assert_eq!(layout.dynamic_base, 217_648);
+    }
+
+    #[test]
+    fn test_layout_beyond_4gib() {
+        // The stack starts at 1040
+        let layout = MemoryLayout::with_stack(1024, u32::max_value() - 1055).unwrap();
+        assert_eq!(layout.dynamic_base, u32::max_value() - 15);
+        assert!(MemoryLayout::with_stack(1024, u32::max_value() - 1054).is_err());
+        assert!(MemoryLayout::with_stack(u32::max_value() - 8, 0).is_err());
+    }
+}
@@ -1,17 +1,25 @@
+//! The locks of musl (`LOCK`/`UNLOCK` and `__wait`). Without pthreads (which
+//! we don't support) the guest has a single thread, and like emscripten we
+//! ignore them.
 use crate::webassembly::Instance;
-use libc::c_int;
 
-// NOTE: Not implemented by Emscripten
-pub extern "C" fn ___lock(which: c_int, varargs: c_int, _instance: &mut Instance) {
-    debug!("emscripten::___lock {}, {}", which, varargs);
+/// emscripten: ___lock // (lock: *mut c_int)
+pub extern "C" fn ___lock(lock: u32, _instance: &mut Instance) {
+    debug!("emscripten::___lock {}", lock);
 }
 
-// NOTE: Not implemented by Emscripten
-pub extern "C" fn ___unlock(which: c_int, varargs: c_int, _instance: &mut Instance) {
-    debug!("emscripten::___unlock {}, {}", which, varargs);
+/// emscripten: ___unlock // (lock: *mut c_int)
+pub extern "C" fn ___unlock(lock: u32, _instance: &mut Instance) {
+    debug!("emscripten::___unlock {}", lock);
 }
 
-// NOTE: Not implemented by Emscripten
-pub extern "C" fn ___wait(_which: c_int, _varargs: c_int, _instance: &mut Instance) {
-    debug!("emscripten::___wait");
+/// emscripten: ___wait // (addr: *mut c_int, waiters: *mut c_int, value: c_int, private: c_int)
+pub extern "C" fn ___wait(
+    addr: u32,
+    _waiters: u32,
+    value: i32,
+    _private: i32,
+    _instance: &mut Instance,
+) {
+    debug!("emscripten::___wait {}, {}", addr, value);
 }
@@ -0,0 +1,477 @@
+//! An in-memory filesystem, like the `MEMFS` of emscripten.
+//!
+//! A `MemFileSystem` is a handle to a tree shared by its clones, so an
+//! embedder can keep one to populate the files before the run, and read the
+//! files written by the guest after it.
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::vfs::{
+    errno, DirEntry, FileHandle, FileSystem, OpenFlags, Stat, VfsResult, S_IFDIR, S_IFREG,
+};
+
+type NodeRef = Rc<RefCell<Node>>;
+
+/// The maximum size of a file, 2 GiB. Writing past it fails with `EFBIG`,
+/// and seeking past it with `EINVAL`, as for the host file size limit.
+const MAX_FILE_SIZE: u64 = 1 << 31;
+
+#[derive(Debug)]
+enum Contents {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, NodeRef>),
+}
+
+#[derive(Debug)]
+struct Node {
+    ino: u64,
+    /// The permission bits.
+    mode: u32,
+    mtime: u64,
+    contents: Contents,
+}
+
+impl Node {
+    fn stat(&self) -> Stat {
+        let (mode, size, nlink) = match &self.contents {
+            Contents::File(data) => (S_IFREG | self.mode, data.len() as u64, 1),
+            Contents::Directory(entries) => (S_IFDIR | self.mode, 4096, 2 + entries.len() as u32),
+        };
+        Stat {
+            dev: 1,
+            ino: self.ino,
+            mode,
+            nlink,
+            size,
+            blocks: (size + 511) / 512,
+            atime: self.mtime,
+            mtime: self.mtime,
+            ctime: self.mtime,
+            ..Default::default()
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        match self.contents {
+            Contents::Directory(_) => true,
+            Contents::File(_) => false,
+        }
+    }
+
+    fn entries(&mut self) -> VfsResult<&mut BTreeMap<String, NodeRef>> {
+        match &mut self.contents {
+            Contents::Directory(entries) => Ok(entries),
+            Contents::File(_) => Err(errno::ENOTDIR),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// The parent directory and the name of the last component of a path.
+fn split_parent(path: &str) -> VfsResult<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(index) if !path[index + 1..].is_empty() => Ok((&path[..index], &path[index + 1..])),
+        // The root
+        _ => Err(errno::EBUSY),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemFileSystem {
+    root: NodeRef,
+    next_ino: Rc<Cell<u64>>,
+}
+
+impl MemFileSystem {
+    /// An empty filesystem.
+    pub fn new() -> Self {
+        MemFileSystem {
+            root: Rc::new(RefCell::new(Node {
+                ino: 1,
+                mode: 0o777,
+                mtime: now(),
+                contents: Contents::Directory(BTreeMap::new()),
+            })),
+            next_ino: Rc::new(Cell::new(2)),
+        }
+    }
+
+    fn new_node(&self, mode: u32, contents: Contents) -> NodeRef {
+        let ino = self.next_ino.get();
+        self.next_ino.set(ino + 1);
+        Rc::new(RefCell::new(Node {
+            ino,
+            mode: mode & 0o7777,
+            mtime: now(),
+            contents,
+        }))
+    }
+
+    fn lookup(&self, path: &str) -> VfsResult<NodeRef> {
+        let mut node = self.root.clone();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let child = node
+                .borrow_mut()
+                .entries()?
+                .get(name)
+                .cloned()
+                .ok_or(errno::ENOENT)?;
+            node = child;
+        }
+        Ok(node)
+    }
+
+    fn lookup_parent<'a>(&self, path: &'a str) -> VfsResult<(NodeRef, &'a str)> {
+        let (parent, name) = split_parent(path)?;
+        let parent = self.lookup(parent)?;
+        if !parent.borrow().is_dir() {
+            return Err(errno::ENOTDIR);
+        }
+        Ok((parent, name))
+    }
+
+    /// Creates a directory and its missing parents.
+    pub fn create_dir_all(&self, path: &str) -> VfsResult<()> {
+        let mut node = self.root.clone();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let child = {
+                let mut node = node.borrow_mut();
+                let entries = node.entries()?;
+                entries
+                    .entry(name.to_string())
+                    .or_insert_with(|| self.new_node(0o777, Contents::Directory(BTreeMap::new())))
+                    .clone()
+            };
+            node = child;
+        }
+        if node.borrow().is_dir() {
+            Ok(())
+        } else {
+            Err(errno::ENOTDIR)
+        }
+    }
+
+    /// Creates or replaces a file, creating its missing parent directories.
+    pub fn write_file(&self, path: &str, data: &[u8]) -> VfsResult<()> {
+        let (parent, name) = split_parent(path)?;
+        self.create_dir_all(parent)?;
+        let (parent, _) = self.lookup_parent(path)?;
+        let file = self.new_node(0o666, Contents::File(data.to_vec()));
+        let mut parent = parent.borrow_mut();
+        let entries = parent.entries()?;
+        if entries
+            .get(name)
+            .map_or(false, |node| node.borrow().is_dir())
+        {
+            return Err(errno::EISDIR);
+        }
+        entries.insert(name.to_string(), file);
+        Ok(())
+    }
+
+    /// The contents of a file.
+    pub fn read_file(&self, path: &str) -> VfsResult<Vec<u8>> {
+        let node = self.lookup(path)?;
+        let node = node.borrow();
+        match &node.contents {
+            Contents::File(data) => Ok(data.clone()),
+            Contents::Directory(_) => Err(errno::EISDIR),
+        }
+    }
+}
+
+impl Default for MemFileSystem {
+    fn default() -> Self {
+        MemFileSystem::new()
+    }
+}
+
+impl FileSystem for MemFileSystem {
+    fn open(&mut self, path: &str, flags: OpenFlags, mode: u32) -> VfsResult<Box<FileHandle>> {
+        let node = match self.lookup(path) {
+            Ok(_) if flags.create && flags.exclusive => return Err(errno::EEXIST),
+            Ok(node) => node,
+            Err(errno::ENOENT) if flags.create => {
+                let (parent, name) = self.lookup_parent(path)?;
+                let file = self.new_node(mode, Contents::File(Vec::new()));
+                parent
+                    .borrow_mut()
+                    .entries()?
+                    .insert(name.to_string(), file.clone());
+                file
+            }
+            Err(error) => return Err(error),
+        };
+        {
+            let mut node = node.borrow_mut();
+            match &mut node.contents {
+                Contents::Directory(_) if flags.write => return Err(errno::EISDIR),
+                Contents::Directory(_) => {}
+                Contents::File(_) if flags.directory => return Err(errno::ENOTDIR),
+                Contents::File(data) => {
+                    if flags.truncate && flags.write {
+                        data.clear();
+                    }
+                }
+            }
+        }
+        Ok(Box::new(MemFile {
+            node,
+            position: 0,
+            flags,
+        }))
+    }
+
+    fn stat(&mut self, path: &str) -> VfsResult<Stat> {
+        Ok(self.lookup(path)?.borrow().stat())
+    }
+
+    fn mkdir(&mut self, path: &str, mode: u32) -> VfsResult<()> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let mut parent = parent.borrow_mut();
+        let entries = parent.entries()?;
+        if entries.contains_key(name) {
+            return Err(errno::EEXIST);
+        }
+        let dir = self.new_node(mode, Contents::Directory(BTreeMap::new()));
+        entries.insert(name.to_string(), dir);
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> VfsResult<()> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let mut parent = parent.borrow_mut();
+        let entries = parent.entries()?;
+        match entries.get(name) {
+            None => return Err(errno::ENOENT),
+            Some(node) => match &node.borrow().contents {
+                Contents::File(_) => return Err(errno::ENOTDIR),
+                Contents::Directory(children) if !children.is_empty() => {
+                    return Err(errno::ENOTEMPTY)
+                }
+                Contents::Directory(_) => {}
+            },
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn unlink(&mut self, path: &str) -> VfsResult<()> {
+        let (parent, name) = self.lookup_parent(path)?;
+        let mut parent = parent.borrow_mut();
+        let entries = parent.entries()?;
+        match entries.get(name) {
+            None => return Err(errno::ENOENT),
+            Some(node) if node.borrow().is_dir() => return Err(errno::EISDIR),
+            Some(_) => {}
+        }
+        // The open handles keep the contents
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> VfsResult<()> {
+        let node = self.lookup(from)?;
+        if to == from {
+            return Ok(());
+        }
+        if to.starts_with(from) && to[from.len()..].starts_with('/') {
+            // Moving a directory into itself
+            return Err(errno::EINVAL);
+        }
+        let (to_parent, to_name) = self.lookup_parent(to)?;
+        if let Some(target) = to_parent.borrow_mut().entries()?.get(to_name) {
+            match (node.borrow().is_dir(), target.borrow().is_dir()) {
+                (true, false) => return Err(errno::ENOTDIR),
+                (false, true) => return Err(errno::EISDIR),
+                (true, true) => {
+                    if let Contents::Directory(children) = &target.borrow().contents {
+                        if !children.is_empty() {
+                            return Err(errno::ENOTEMPTY);
+                        }
+                    }
+                }
+                (false, false) => {}
+            }
+        }
+        let (from_parent, from_name) = self.lookup_parent(from)?;
+        from_parent.borrow_mut().entries()?.remove(from_name);
+        to_parent
+            .borrow_mut()
+            .entries()?
+            .insert(to_name.to_string(), node);
+        Ok(())
+    }
+
+    fn read_dir(&mut self, path: &str) -> VfsResult<Vec<DirEntry>> {
+        let node = self.lookup(path)?;
+        let mut node = node.borrow_mut();
+        Ok(node
+            .entries()?
+            .iter()
+            .map(|(name, child)| {
+                let child = child.borrow();
+                DirEntry {
+                    name: name.clone(),
+                    ino: child.ino,
+                    is_dir: child.is_dir(),
+                }
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug)]
+struct MemFile {
+    node: NodeRef,
+    position: u64,
+    flags: OpenFlags,
+}
+
+impl FileHandle for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> VfsResult<usize> {
+        if !self.flags.read {
+            return Err(errno::EBADF);
+        }
+        let node = self.node.borrow();
+        let data = match &node.contents {
+            Contents::File(data) => data,
+            Contents::Directory(_) => return Err(errno::EISDIR),
+        };
+        let start = (self.position as usize).min(data.len());
+        let count = buf.len().min(data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> VfsResult<usize> {
+        if !self.flags.write {
+            return Err(errno::EBADF);
+        }
+        let mut node = self.node.borrow_mut();
+        node.mtime = now();
+        let data = match &mut node.contents {
+            Contents::File(data) => data,
+            Contents::Directory(_) => return Err(errno::EISDIR),
+        };
+        if self.flags.append {
+            self.position = data.len() as u64;
+        }
+        let end = match self.position.checked_add(buf.len() as u64) {
+            Some(end) if end <= MAX_FILE_SIZE => end as usize,
+            _ => return Err(errno::EFBIG),
+        };
+        let start = self.position as usize;
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        self.position = end as u64;
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> VfsResult<u64> {
+        let size = match &self.node.borrow().contents {
+            Contents::File(data) => data.len() as i64,
+            Contents::Directory(_) => 0,
+        };
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => {
+                (self.position as i64).checked_add(offset).map(|p| p as u64)
+            }
+            SeekFrom::End(offset) => size.checked_add(offset).map(|p| p as u64),
+        };
+        match position {
+            Some(position) if position <= MAX_FILE_SIZE => self.position = position,
+            _ => return Err(errno::EINVAL),
+        }
+        Ok(self.position)
+    }
+
+    fn stat(&self) -> VfsResult<Stat> {
+        Ok(self.node.borrow().stat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemFileSystem, MAX_FILE_SIZE};
+    use crate::apis::emscripten::vfs::{errno, FileSystem, OpenFlags};
+    use std::io::SeekFrom;
+
+    #[test]
+    fn test_memfs_tree() {
+        let mut fs = MemFileSystem::new();
+        fs.write_file("/a/b/file", b"data").unwrap();
+        assert!(fs.stat("/a/b").unwrap().is_dir());
+        assert_eq!(fs.mkdir("/a/b", 0o755), Err(errno::EEXIST));
+        assert_eq!(fs.rmdir("/a/b"), Err(errno::ENOTEMPTY));
+        assert_eq!(fs.mkdir("/a/b/file/c", 0o755), Err(errno::ENOTDIR));
+
+        let create = OpenFlags {
+            write: true,
+            create: true,
+            exclusive: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            fs.open("/a/b/file", create, 0o644).unwrap_err(),
+            errno::EEXIST
+        );
+
+        fs.rename("/a/b", "/c").unwrap();
+        assert_eq!(fs.read_file("/c/file").unwrap(), b"data");
+        assert_eq!(fs.stat("/a/b"), Err(errno::ENOENT));
+        let names: Vec<String> = fs
+            .read_dir("/")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, vec!["a", "c"]);
+        fs.unlink("/c/file").unwrap();
+        fs.rmdir("/c").unwrap();
+    }
+
+    #[test]
+    fn test_memfile_size_limit() {
+        let mut fs = MemFileSystem::new();
+        let flags = OpenFlags {
+            read: true,
+            write: true,
+            create: true,
+            ..Default::default()
+        };
+        let mut file = fs.open("/file", flags, 0o644).unwrap();
+        assert_eq!(file.write(b"data"), Ok(4));
+        assert_eq!(file.seek(SeekFrom::Current(-2)), Ok(2));
+        assert_eq!(file.seek(SeekFrom::Current(-3)), Err(errno::EINVAL));
+        assert_eq!(
+            file.seek(SeekFrom::End(i64::min_value())),
+            Err(errno::EINVAL)
+        );
+        assert_eq!(
+            file.seek(SeekFrom::Start(u64::max_value())),
+            Err(errno::EINVAL)
+        );
+        assert_eq!(
+            file.seek(SeekFrom::Start(MAX_FILE_SIZE - 2)),
+            Ok(MAX_FILE_SIZE - 2)
+        );
+        assert_eq!(file.write(b"data"), Err(errno::EFBIG));
+        assert_eq!(file.seek(SeekFrom::Current(0)), Ok(MAX_FILE_SIZE - 2));
+        assert_eq!(file.stat().unwrap().size, 4);
+    }
+}
@@ -1,5 +1,5 @@
 use super::process::abort_with_message;
-use crate::webassembly::Instance;
+use crate::webassembly::{Instance, LinearMemory};
 use libc::{c_int, c_void, memcpy, size_t};
 
 /// emscripten: _emscripten_memcpy_big
@@ -22,16 +22,87 @@ pub extern "C" fn _emscripten_memcpy_big(
 }
 
 /// emscripten: getTotalMemory
-pub extern "C" fn get_total_memory(_instance: &mut Instance) -> u32 {
+pub extern "C" fn get_total_memory(instance: &mut Instance) -> u32 {
     debug!("emscripten::get_total_memory");
-    // instance.memories[0].current_pages()
-    16_777_216
+    instance.memories[0].current_size() as u32
+}
+
+/// emscripten: _emscripten_get_heap_size
+pub extern "C" fn _emscripten_get_heap_size(instance: &mut Instance) -> u32 {
+    debug!("emscripten::_emscripten_get_heap_size");
+    instance.memories[0].current_size() as u32
+}
+
+const PAGE_SIZE: u64 = LinearMemory::PAGE_SIZE as u64;
+
+/// The size emscripten grows a memory of `current_size` bytes to, to hold at
+/// least `size` bytes: doubling it up to 512 MiB, and then by a quarter of the
+/// remaining space up to 2 GiB. `None` when it can't hold `size` bytes.
+fn new_memory_size(current_size: u64, size: u64, maximum_size: u64) -> Option<u64> {
+    const LIMIT: u64 = 2_147_483_648 - PAGE_SIZE;
+    let align_up = |size: u64| (size + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+
+    if size <= current_size {
+        return Some(current_size);
+    }
+    if size > LIMIT || align_up(size) > maximum_size {
+        return None;
+    }
+    let mut new_size = current_size.max(PAGE_SIZE);
+    while new_size < size {
+        new_size = if new_size <= 536_870_912 {
+            align_up(2 * new_size)
+        } else {
+            align_up((3 * new_size + 2_147_483_648) / 4).min(LIMIT)
+        };
+    }
+    // The growth steps are a hint, the size needed is enough
+    Some(new_size.min(maximum_size).max(align_up(size)))
+}
+
+/// Grows the memory to hold at least `size` bytes, like emscripten.
+/// Returns whether the memory could be grown.
+fn grow_memory_to(size: u32, instance: &mut Instance) -> bool {
+    let memory = instance.memory_mut(0);
+    let current_size = memory.current_size() as u64;
+    let maximum_size = u64::from(memory.maximum_size()) * PAGE_SIZE;
+    let new_size = match new_memory_size(current_size, u64::from(size), maximum_size) {
+        Some(new_size) => new_size,
+        None => return false,
+    };
+    if new_size == current_size {
+        return true;
+    }
+    debug!(
+        "emscripten::grow_memory_to {} bytes: from {} to {} pages",
+        size,
+        current_size / PAGE_SIZE,
+        new_size / PAGE_SIZE
+    );
+    let add_pages = (new_size - current_size) / PAGE_SIZE;
+    memory.grow(add_pages as u32).is_some()
 }
 
 /// emscripten: enlargeMemory
-pub extern "C" fn enlarge_memory(_instance: &mut Instance) {
+/// Called by `sbrk` after moving the top of the heap (at `DYNAMICTOP_PTR`)
+/// beyond the end of the memory.
+pub extern "C" fn enlarge_memory(instance: &mut Instance) -> u32 {
     debug!("emscripten::enlarge_memory");
-    // instance.memories[0].grow(100);
+    let dynamictop_ptr = instance
+        .emscripten_data
+        .as_ref()
+        .unwrap()
+        .layout
+        .dynamictop_ptr;
+    let dynamictop_ptr = instance.memory_offset_addr(0, dynamictop_ptr as _);
+    let dynamic_top = unsafe { *(dynamictop_ptr as *const u32) };
+    grow_memory_to(dynamic_top, instance) as u32
+}
+
+/// emscripten: _emscripten_resize_heap
+pub extern "C" fn _emscripten_resize_heap(requested_size: u32, instance: &mut Instance) -> u32 {
+    debug!("emscripten::_emscripten_resize_heap {}", requested_size);
+    grow_memory_to(requested_size, instance) as u32
 }
 
 /// emscripten: abortOnCannotGrowMemory
@@ -46,3 +117,42 @@ pub extern "C" fn ___map_file() -> c_int {
     // NOTE: TODO: Em returns -1 here as well. May need to implement properly
     -1
 }
+
+#[cfg(test)]
+mod tests {
+    use super::new_memory_size;
+
+    const MIB: u64 = 1 << 20;
+    const MAXIMUM: u64 = 2048 * MIB;
+
+    #[test]
+    fn test_new_memory_size() {
+        // Enough room already
+        assert_eq!(new_memory_size(16 * MIB, 10 * MIB, MAXIMUM), Some(16 * MIB));
+        // Doubled below 512 MiB
+        assert_eq!(
+            new_memory_size(16 * MIB, 16 * MIB + 1, MAXIMUM),
+            Some(32 * MIB)
+        );
+        assert_eq!(
+            new_memory_size(16 * MIB, 100 * MIB, MAXIMUM),
+            Some(128 * MIB)
+        );
+        // Then grown by a quarter of the remaining space
+        assert_eq!(
+            new_memory_size(1024 * MIB, 1024 * MIB + 1, MAXIMUM),
+            Some(1280 * MIB)
+        );
+        assert_eq!(
+            new_memory_size(512 * MIB, 512 * MIB + 1, MAXIMUM),
+            Some(1024 * MIB)
+        );
+        // Up to the maximum size of the memory, if it's enough
+        assert_eq!(
+            new_memory_size(16 * MIB, 20 * MIB, 24 * MIB),
+            Some(24 * MIB)
+        );
+        assert_eq!(new_memory_size(16 * MIB, 25 * MIB, 24 * MIB), None);
+        assert_eq!(new_memory_size(16 * MIB, MAXIMUM, MAXIMUM), None);
+    }
+}
@@ -1,70 +1,41 @@
 /// NOTE: TODO: These emscripten api implementation only support wasm32 for now because they assume offsets are u32
-use crate::webassembly::{ImportObject, ImportValue, LinearMemory};
-use byteorder::{ByteOrder, LittleEndian};
-use std::mem;
+use crate::webassembly::{ImportObject, ImportValue};
 
 // EMSCRIPTEN APIS
 mod env;
 mod errno;
 mod exception;
+mod hostfs;
+mod invoke;
 mod io;
 mod jmp;
+mod layout;
 mod lock;
 mod math;
+mod memfs;
 mod memory;
 mod nullfunc;
+mod printf;
 mod process;
 mod signal;
 mod storage;
 mod syscalls;
 mod time;
+mod trace;
 mod utils;
 mod varargs;
+pub mod vfs;
 
+pub use self::env::Environment;
+pub use self::exception::Exceptions;
+pub use self::hostfs::HostFileSystem;
+pub use self::invoke::JumpBuffer;
+pub use self::layout::{emscripten_set_up_memory, MemoryLayout};
+pub use self::memfs::MemFileSystem;
 pub use self::storage::align_memory;
+pub use self::trace::{syscall_result, SyscallTracer};
 pub use self::utils::{allocate_cstr_on_stack, allocate_on_stack, is_emscripten_module};
-
-// TODO: Magic number - how is this calculated?
-const TOTAL_STACK: u32 = 5_242_880;
-// TODO: Magic number - how is this calculated?
-const DYNAMICTOP_PTR_DIFF: u32 = 1088;
-// TODO: make this variable
-const STATIC_BUMP: u32 = 215_536;
-
-fn stacktop(static_bump: u32) -> u32 {
-    align_memory(dynamictop_ptr(static_bump) + 4)
-}
-
-fn stack_max(static_bump: u32) -> u32 {
-    stacktop(static_bump) + TOTAL_STACK
-}
-
-fn dynamic_base(static_bump: u32) -> u32 {
-    align_memory(stack_max(static_bump))
-}
-
-fn dynamictop_ptr(static_bump: u32) -> u32 {
-    static_bump + DYNAMICTOP_PTR_DIFF
-}
-
-pub fn emscripten_set_up_memory(memory: &mut LinearMemory) {
-    let dynamictop_ptr = dynamictop_ptr(STATIC_BUMP) as usize;
-    let dynamictop_ptr_offset = dynamictop_ptr + mem::size_of::<u32>();
-
-    // println!("value = {:?}");
-
-    // We avoid failures of setting the u32 in our memory if it's out of bounds
-    if dynamictop_ptr_offset > memory.len() {
-        return; // TODO: We should panic instead?
-    }
-
-    // debug!("###### dynamic_base = {:?}", dynamic_base(STATIC_BUMP));
-    // debug!("###### dynamictop_ptr = {:?}", dynamictop_ptr);
-    // debug!("###### dynamictop_ptr_offset = {:?}", dynamictop_ptr_offset);
-
-    let mem = &mut memory[dynamictop_ptr..dynamictop_ptr_offset];
-    LittleEndian::write_u32(mem, dynamic_base(STATIC_BUMP));
-}
+pub use self::vfs::{FileHandle, FileSystem, ReadStream, Vfs, WriteStream};
 
 macro_rules! mock_external {
     ($import:ident, $name:ident) => {{
@@ -78,22 +49,7 @@ macro_rules! mock_external {
 
 pub fn generate_emscripten_env<'a, 'b>() -> ImportObject<&'a str, &'b str> {
     let mut import_object = ImportObject::new();
-    // Globals
-    import_object.set(
-        "env",
-        "STACKTOP",
-        ImportValue::Global(stacktop(STATIC_BUMP) as _),
-    );
-    import_object.set(
-        "env",
-        "STACK_MAX",
-        ImportValue::Global(stack_max(STATIC_BUMP) as _),
-    );
-    import_object.set(
-        "env",
-        "DYNAMICTOP_PTR",
-        ImportValue::Global(dynamictop_ptr(STATIC_BUMP) as _),
-    );
+    // Globals (the layout of the memory is set by the instance, see `MemoryLayout`)
     import_object.set(
         "global",
         "Infinity",
@@ -156,6 +112,11 @@ pub fn generate_emscripten_env<'a, 'b>() -> ImportObject<&'a str, &'b str> {
         "___syscall6",
         ImportValue::Func(syscalls::___syscall6 as _),
     );
+    import_object.set(
+        "env",
+        "___syscall10",
+        ImportValue::Func(syscalls::___syscall10 as _),
+    );
     import_object.set(
         "env",
         "___syscall12",
@@ -166,6 +127,16 @@ pub fn generate_emscripten_env<'a, 'b>() -> ImportObject<&'a str, &'b str> {
         "___syscall20",
         ImportValue::Func(syscalls::___syscall20 as _),
     );
+    import_object.set(
+        "env",
+        "___syscall33",
+        ImportValue::Func(syscalls::___syscall33 as _),
+    );
+    import_object.set(
+        "env",
+        "___syscall38",
+        ImportValue::Func(syscalls::___syscall38 as _),
+    );
     import_object.set(
         "env",
         "___syscall39",
@@ -176,6 +147,11 @@ pub fn generate_emscripten_env<'a, 'b>() -> ImportObject<&'a str, &'b str> {
         "___syscall40",
         ImportValue::Func(syscalls::___syscall40 as _),
     );
+    import_object.set(
+        "env",
+        "___syscall41",
+        ImportValue::Func(syscalls::___syscall41 as _),
+    );
     import_object.set(
         "env",
         "___syscall54",
@@ -241,6 +217,11 @@ pub fn generate_emscripten_env<'a, 'b>() -> ImportObject<&'a str, &'b str> {
         "___syscall181",
         ImportValue::Func(syscalls::___syscall181 as _),
     );
+    import_object.set(
+        "env",
+        "___syscall183",
+        ImportValue::Func(syscalls::___syscall183 as _),
+    );
     import_object.set(
         "env",
         "___syscall192",
@@ -346,6 +327,16 @@ pub fn generate_emscripten_env<'a, 'b>() -> ImportObject<&'a str, &'b str> {
         "getTotalMemory",
         ImportValue::Func(memory::get_total_memory as _),
     );
+    import_object.set(
+        "env",
+        "_emscripten_resize_heap",
+        ImportValue::Func(memory::_emscripten_resize_heap as _),
+    );
+    import_object.set(
+        "env",
+        "_emscripten_get_heap_size",
+        ImportValue::Func(memory::_emscripten_get_heap_size as _),
+    );
     import_object.set(
         "env",
         "___map_file",
@@ -359,14 +350,202 @@ pub fn generate_emscripten_env<'a, 'b>() -> ImportObject<&'a str, &'b str> {
     );
     import_object.set(
         "env",
-        "___cxa_allocate_exception",
-        ImportValue::Func(exception::___cxa_throw as _),
+        "___cxa_free_exception",
+        ImportValue::Func(exception::___cxa_free_exception as _),
     );
     import_object.set(
         "env",
         "___cxa_throw",
         ImportValue::Func(exception::___cxa_throw as _),
     );
+    import_object.set(
+        "env",
+        "___cxa_rethrow",
+        ImportValue::Func(exception::___cxa_rethrow as _),
+    );
+    import_object.set(
+        "env",
+        "___resumeException",
+        ImportValue::Func(exception::___resume_exception as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_begin_catch",
+        ImportValue::Func(exception::___cxa_begin_catch as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_end_catch",
+        ImportValue::Func(exception::___cxa_end_catch as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_find_matching_catch_2",
+        ImportValue::Func(exception::___cxa_find_matching_catch_2 as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_find_matching_catch_3",
+        ImportValue::Func(exception::___cxa_find_matching_catch_3 as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_find_matching_catch_4",
+        ImportValue::Func(exception::___cxa_find_matching_catch_4 as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_find_matching_catch_5",
+        ImportValue::Func(exception::___cxa_find_matching_catch_5 as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_find_matching_catch_6",
+        ImportValue::Func(exception::___cxa_find_matching_catch_6 as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_uncaught_exception",
+        ImportValue::Func(exception::___cxa_uncaught_exception as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_current_primary_exception",
+        ImportValue::Func(exception::___cxa_current_primary_exception as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_rethrow_primary_exception",
+        ImportValue::Func(exception::___cxa_rethrow_primary_exception as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_increment_exception_refcount",
+        ImportValue::Func(exception::___cxa_increment_exception_refcount as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_decrement_exception_refcount",
+        ImportValue::Func(exception::___cxa_decrement_exception_refcount as _),
+    );
+    import_object.set(
+        "env",
+        "___gxx_personality_v0",
+        ImportValue::Func(exception::___gxx_personality_v0 as _),
+    );
+    import_object.set(
+        "env",
+        "_llvm_eh_typeid_for",
+        ImportValue::Func(exception::_llvm_eh_typeid_for as _),
+    );
+    import_object.set(
+        "env",
+        "___cxa_pure_virtual",
+        ImportValue::Func(exception::___cxa_pure_virtual as _),
+    );
+    // Invoke
+    import_object.set("env", "invoke_v", ImportValue::Func(invoke::invoke_v as _));
+    import_object.set(
+        "env",
+        "invoke_vi",
+        ImportValue::Func(invoke::invoke_vi as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_vii",
+        ImportValue::Func(invoke::invoke_vii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_viii",
+        ImportValue::Func(invoke::invoke_viii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_viiii",
+        ImportValue::Func(invoke::invoke_viiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_viiiii",
+        ImportValue::Func(invoke::invoke_viiiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_viiiiii",
+        ImportValue::Func(invoke::invoke_viiiiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_viiiiiii",
+        ImportValue::Func(invoke::invoke_viiiiiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_viiiiiiii",
+        ImportValue::Func(invoke::invoke_viiiiiiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_viiiiiiiii",
+        ImportValue::Func(invoke::invoke_viiiiiiiii as _),
+    );
+    import_object.set("env", "invoke_i", ImportValue::Func(invoke::invoke_i as _));
+    import_object.set(
+        "env",
+        "invoke_ii",
+        ImportValue::Func(invoke::invoke_ii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_iii",
+        ImportValue::Func(invoke::invoke_iii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_iiii",
+        ImportValue::Func(invoke::invoke_iiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_iiiii",
+        ImportValue::Func(invoke::invoke_iiiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_iiiiii",
+        ImportValue::Func(invoke::invoke_iiiiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_iiiiiii",
+        ImportValue::Func(invoke::invoke_iiiiiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_iiiiiiii",
+        ImportValue::Func(invoke::invoke_iiiiiiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_iiiiiiiii",
+        ImportValue::Func(invoke::invoke_iiiiiiiii as _),
+    );
+    import_object.set(
+        "env",
+        "invoke_iiiiiiiiii",
+        ImportValue::Func(invoke::invoke_iiiiiiiiii as _),
+    );
+    import_object.set(
+        "env",
+        "setTempRet0",
+        ImportValue::Func(invoke::set_temp_ret_0 as _),
+    );
+    import_object.set(
+        "env",
+        "getTempRet0",
+        ImportValue::Func(invoke::get_temp_ret_0 as _),
+    );
     // NullFuncs
     import_object.set(
         "env",
@@ -483,8 +662,18 @@ pub fn generate_emscripten_env<'a, 'b>() -> ImportObject<&'a str, &'b str> {
     );
     import_object.set("asm2wasm", "f64-rem", ImportValue::Func(math::f64_rem as _));
 
-    import_object.set("env", "__setjmp", ImportValue::Func(jmp::__setjmp as _));
-    import_object.set("env", "__longjmp", ImportValue::Func(jmp::__longjmp as _));
+    // Jumps
+    import_object.set("env", "_longjmp", ImportValue::Func(jmp::_longjmp as _));
+    import_object.set(
+        "env",
+        "_emscripten_longjmp",
+        ImportValue::Func(jmp::_emscripten_longjmp as _),
+    );
+    import_object.set(
+        "env",
+        "_siglongjmp",
+        ImportValue::Func(jmp::_siglongjmp as _),
+    );
 
     mock_external!(import_object, _waitpid);
     mock_external!(import_object, _utimes);
@@ -0,0 +1,517 @@
+//! The formatting of the `printf` family, over the memory of the guest.
+//!
+//! The format string and the `%s` strings are read from the guest memory,
+//! and the arguments from the varargs buffer of the guest, where clang puts
+//! each of them at the alignment of its type (wasm32: `long` is 32 bits,
+//! `long double` is a 128 bits float). Everything is bounds checked: the
+//! bytes outside of the memory read as zeros.
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::vfs::errno;
+
+static ZEROS: [u8; 16] = [0; 16];
+/// The largest width, precision and output of a call (its result is an `int`).
+const INT_MAX: usize = 0x7fff_ffff;
+
+/// The arguments of a call, in the varargs buffer of the guest.
+struct Arguments<'a> {
+    memory: &'a [u8],
+    pointer: u32,
+}
+
+impl<'a> Arguments<'a> {
+    fn read(&mut self, size: u32) -> &'a [u8] {
+        // Aligned to the size of the type
+        let start = self.pointer.wrapping_add(size - 1) & !(size - 1);
+        self.pointer = start.wrapping_add(size);
+        let start = start as usize;
+        self.memory
+            .get(start..start + size as usize)
+            .unwrap_or(&ZEROS[..size as usize])
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        LittleEndian::read_u32(self.read(4))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        LittleEndian::read_u64(self.read(8))
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        f64::from_bits(self.next_u64())
+    }
+
+    /// A `long double`, rounded to a `f64`.
+    fn next_f128(&mut self) -> f64 {
+        let bytes = self.read(16);
+        f128_to_f64(
+            LittleEndian::read_u64(&bytes[8..]),
+            LittleEndian::read_u64(&bytes[..8]),
+        )
+    }
+}
+
+/// Truncates an IEEE binary128 (its high and low halves) to a binary64.
+fn f128_to_f64(high: u64, low: u64) -> f64 {
+    let sign = high & (1 << 63);
+    let exponent = ((high >> 48) & 0x7fff) as i64;
+    let mantissa = (high & 0xffff_ffff_ffff) << 4 | low >> 60;
+    let bits = match exponent {
+        0 => sign,
+        0x7fff => sign | 0x7ff << 52 | mantissa,
+        _ => {
+            let exponent = exponent - 16383 + 1023;
+            if exponent >= 0x7ff {
+                sign | 0x7ff << 52
+            } else if exponent <= 0 {
+                sign
+            } else {
+                sign | (exponent as u64) << 52 | mantissa
+            }
+        }
+    };
+    f64::from_bits(bits)
+}
+
+/// The NUL terminated string at `offset`, up to `max_len` bytes.
+fn read_string(memory: &[u8], offset: u32, max_len: Option<usize>) -> &[u8] {
+    let bytes = memory.get(offset as usize..).unwrap_or(&[]);
+    let bytes = match max_len {
+        Some(max_len) if max_len < bytes.len() => &bytes[..max_len],
+        _ => bytes,
+    };
+    match bytes.iter().position(|&byte| byte == 0) {
+        Some(len) => &bytes[..len],
+        None => bytes,
+    }
+}
+
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl Spec {
+    /// Writes `sign`, `prefix` and `body`, padded to the width.
+    fn pad(&self, out: &mut Vec<u8>, sign: &str, prefix: &str, body: &[u8], zero: bool) {
+        let len = sign.len() + prefix.len() + body.len();
+        let padding = self.width.saturating_sub(len);
+        if !self.left && !zero {
+            out.extend(std::iter::repeat(b' ').take(padding));
+        }
+        out.extend_from_slice(sign.as_bytes());
+        out.extend_from_slice(prefix.as_bytes());
+        if !self.left && zero {
+            out.extend(std::iter::repeat(b'0').take(padding));
+        }
+        out.extend_from_slice(body);
+        if self.left {
+            out.extend(std::iter::repeat(b' ').take(padding));
+        }
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    fn integer(&self, out: &mut Vec<u8>, value: u64, negative: bool, conversion: u8) {
+        let mut digits = match conversion {
+            b'o' => format!("{:o}", value),
+            b'x' | b'p' => format!("{:x}", value),
+            b'X' => format!("{:X}", value),
+            _ => value.to_string(),
+        };
+        if let Some(precision) = self.precision {
+            if value == 0 && precision == 0 {
+                digits.clear();
+            }
+            if digits.len() < precision {
+                digits = format!("{:0>1$}", digits, precision);
+            }
+        }
+        if conversion == b'o' && self.alternate && !digits.starts_with('0') {
+            digits.insert(0, '0');
+        }
+        let prefix = match conversion {
+            b'x' if self.alternate && value != 0 => "0x",
+            b'X' if self.alternate && value != 0 => "0X",
+            b'p' => "0x",
+            _ => "",
+        };
+        let sign = match conversion {
+            b'd' | b'i' => self.sign(negative),
+            _ => "",
+        };
+        let zero = self.zero && self.precision.is_none();
+        self.pad(out, sign, prefix, digits.as_bytes(), zero);
+    }
+
+    fn float(&self, out: &mut Vec<u8>, value: f64, conversion: u8) {
+        let upper = conversion.is_ascii_uppercase();
+        let sign = self.sign(value.is_sign_negative());
+        if !value.is_finite() {
+            let body = match (value.is_nan(), upper) {
+                (true, false) => "nan",
+                (true, true) => "NAN",
+                (false, false) => "inf",
+                (false, true) => "INF",
+            };
+            return self.pad(out, sign, "", body.as_bytes(), false);
+        }
+        let value = value.abs();
+        let precision = self.precision.unwrap_or(6);
+        let mut body = match conversion.to_ascii_lowercase() {
+            b'f' => format!("{:.*}", precision, value),
+            b'e' => exponential(value, precision),
+            _ => {
+                // %g: the shortest of %f and %e with `precision` significant digits
+                let precision = precision.max(1);
+                let exponent = exponent_of(&exponential(value, precision - 1));
+                let mut body = if exponent < -4 || exponent >= precision as i32 {
+                    exponential(value, precision - 1)
+                } else {
+                    format!("{:.*}", (precision as i32 - 1 - exponent) as usize, value)
+                };
+                if !self.alternate {
+                    body = strip_zeros(&body);
+                }
+                body
+            }
+        };
+        if self.alternate && !body.contains('.') {
+            let position = body.find('e').unwrap_or(body.len());
+            body.insert(position, '.');
+        }
+        if upper {
+            body = body.to_ascii_uppercase();
+        }
+        self.pad(out, sign, "", body.as_bytes(), self.zero);
+    }
+}
+
+/// `value` as `d.ddde+dd`, with `precision` digits after the point.
+fn exponential(value: f64, precision: usize) -> String {
+    let formatted = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, exponent.abs())
+}
+
+fn exponent_of(exponential: &str) -> i32 {
+    let position = exponential.find('e').unwrap();
+    exponential[position + 1..].parse().unwrap()
+}
+
+/// Removes the trailing zeros of the fractional part (and the point).
+fn strip_zeros(body: &str) -> String {
+    let (number, exponent) = body.split_at(body.find('e').unwrap_or(body.len()));
+    if !number.contains('.') {
+        return body.to_string();
+    }
+    let number = number.trim_end_matches('0').trim_end_matches('.');
+    format!("{}{}", number, exponent)
+}
+
+/// Reads the decimal number at `format[*i..]`, `None` when it is above `INT_MAX`.
+fn read_count(format: &[u8], i: &mut usize) -> Option<usize> {
+    let mut count: usize = 0;
+    while let Some(digit) = format.get(*i).filter(|byte| byte.is_ascii_digit()) {
+        count = count
+            .checked_mul(10)?
+            .checked_add((digit - b'0') as usize)?;
+        if count > INT_MAX {
+            return None;
+        }
+        *i += 1;
+    }
+    Some(count)
+}
+
+/// Formats the format string at `format` with the varargs at `varargs`.
+/// Fails with `EOVERFLOW` when a width, a precision or the output is above
+/// `INT_MAX`, like musl.
+pub fn format(memory: &[u8], format: u32, varargs: u32) -> Result<Vec<u8>, i32> {
+    let format = read_string(memory, format, None);
+    let mut args = Arguments {
+        memory,
+        pointer: varargs,
+    };
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' {
+            out.push(format[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        i += 1;
+        let mut spec = Spec::default();
+        while i < format.len() {
+            match format[i] {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                b'0' => spec.zero = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        if format.get(i) == Some(&b'*') {
+            let width = args.next_u32() as i32;
+            if width < 0 {
+                spec.left = true;
+            }
+            // -INT_MIN doesn't fit in an `int`
+            spec.width = width.checked_abs().ok_or(errno::EOVERFLOW)? as usize;
+            i += 1;
+        } else {
+            spec.width = read_count(format, &mut i).ok_or(errno::EOVERFLOW)?;
+        }
+        if format.get(i) == Some(&b'.') {
+            i += 1;
+            if format.get(i) == Some(&b'*') {
+                let precision = args.next_u32() as i32;
+                // A negative precision is taken as if it were omitted
+                if precision >= 0 {
+                    spec.precision = Some(precision as usize);
+                }
+                i += 1;
+            } else {
+                spec.precision = Some(read_count(format, &mut i).ok_or(errno::EOVERFLOW)?);
+            }
+        }
+        // The length modifiers: only the 64 bits and the `long double` ones
+        // change the size of the argument on wasm32
+        let mut wide = false;
+        let mut long_double = false;
+        let mut width_bits = 32;
+        while let Some(&modifier) = format.get(i) {
+            match modifier {
+                b'h' => width_bits /= 2,
+                b'l' if format.get(i + 1) == Some(&b'l') => {
+                    wide = true;
+                    i += 1;
+                }
+                b'l' => {}
+                b'j' | b'q' => wide = true,
+                b'z' | b't' => {}
+                b'L' => long_double = true,
+                _ => break,
+            }
+            i += 1;
+        }
+        let conversion = match format.get(i) {
+            Some(&conversion) => conversion,
+            None => {
+                out.extend_from_slice(&format[start..]);
+                break;
+            }
+        };
+        i += 1;
+        match conversion {
+            b'%' => out.push(b'%'),
+            b'd' | b'i' => {
+                let value = if wide {
+                    args.next_u64() as i64
+                } else {
+                    match width_bits {
+                        8 => i64::from(args.next_u32() as i8),
+                        16 => i64::from(args.next_u32() as i16),
+                        _ => i64::from(args.next_u32() as i32),
+                    }
+                };
+                spec.integer(&mut out, value.wrapping_abs() as u64, value < 0, conversion);
+            }
+            b'u' | b'o' | b'x' | b'X' => {
+                let value = if wide {
+                    args.next_u64()
+                } else {
+                    match width_bits {
+                        8 => u64::from(args.next_u32() as u8),
+                        16 => u64::from(args.next_u32() as u16),
+                        _ => u64::from(args.next_u32()),
+                    }
+                };
+                spec.integer(&mut out, value, false, conversion);
+            }
+            b'p' => {
+                let value = u64::from(args.next_u32());
+                spec.integer(&mut out, value, false, conversion);
+            }
+            b'f' | b'F' | b'e' | b'E' | b'g' | b'G' | b'a' | b'A' => {
+                let value = if long_double {
+                    args.next_f128()
+                } else {
+                    args.next_f64()
+                };
+                // No hexadecimal floats, %a prints like %e
+                let conversion = match conversion {
+                    b'a' => b'e',
+                    b'A' => b'E',
+                    conversion => conversion,
+                };
+                spec.float(&mut out, value, conversion);
+            }
+            b'c' => {
+                let chr = args.next_u32() as u8;
+                spec.pad(&mut out, "", "", &[chr], false);
+            }
+            b's' => {
+                let offset = args.next_u32();
+                let string = if offset == 0 {
+                    &b"(null)"[..]
+                } else {
+                    read_string(memory, offset, spec.precision)
+                };
+                spec.pad(&mut out, "", "", string, false);
+            }
+            // The count of the bytes written is not stored in the guest
+            b'n' => {
+                args.next_u32();
+            }
+            _ => out.extend_from_slice(&format[start..i]),
+        }
+        if out.len() > INT_MAX {
+            return Err(errno::EOVERFLOW);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+    use crate::apis::emscripten::vfs::errno;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    /// A guest memory with the format string at 0 and the varargs at 256.
+    fn try_printf(fmt: &str, args: &[&[u8]]) -> Result<String, i32> {
+        let mut memory = vec![0u8; 1024];
+        memory[..fmt.len()].copy_from_slice(fmt.as_bytes());
+        let mut pointer = 256;
+        for arg in args {
+            pointer = (pointer + arg.len() - 1) / arg.len() * arg.len();
+            memory[pointer..pointer + arg.len()].copy_from_slice(arg);
+            pointer += arg.len();
+        }
+        memory[512..517].copy_from_slice(b"hello");
+        format(&memory, 0, 256).map(|out| String::from_utf8(out).unwrap())
+    }
+
+    fn printf(fmt: &str, args: &[&[u8]]) -> String {
+        try_printf(fmt, args).unwrap()
+    }
+
+    fn int(value: i32) -> [u8; 4] {
+        let mut bytes = [0; 4];
+        LittleEndian::write_i32(&mut bytes, value);
+        bytes
+    }
+
+    fn long(value: i64) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        LittleEndian::write_i64(&mut bytes, value);
+        bytes
+    }
+
+    fn double(value: f64) -> [u8; 8] {
+        let mut bytes = [0; 8];
+        LittleEndian::write_f64(&mut bytes, value);
+        bytes
+    }
+
+    #[test]
+    fn test_printf_integers() {
+        assert_eq!(
+            printf("%d|%5d|%-5d|", &[&int(-42), &int(42), &int(7)]),
+            "-42|   42|7    |"
+        );
+        assert_eq!(
+            printf(
+                "%05d %+d %x %#X %o",
+                &[&int(-42), &int(3), &int(255), &int(255), &int(8)]
+            ),
+            "-0042 +3 ff 0XFF 10"
+        );
+        assert_eq!(
+            printf("%.3u|%hhd|%p", &[&int(5), &int(257), &int(16)]),
+            "005|1|0x10"
+        );
+        // The i64 is aligned to 8 bytes, after the first i32
+        assert_eq!(
+            printf("%d %lld %d", &[&int(1), &long(-5_000_000_000), &int(2)]),
+            "1 -5000000000 2"
+        );
+    }
+
+    #[test]
+    fn test_printf_floats() {
+        let value = double(1234.5678);
+        assert_eq!(
+            printf("%f|%.2f|%10.1f", &[&value, &value, &value]),
+            "1234.567800|1234.57|    1234.6"
+        );
+        assert_eq!(
+            printf("%e|%g|%G", &[&value, &value, &double(1e-10)]),
+            "1.234568e+03|1234.57|1E-10"
+        );
+        assert_eq!(
+            printf("%g %g", &[&double(0.0001), &double(100.0)]),
+            "0.0001 100"
+        );
+        assert_eq!(printf("%f", &[&double(-std::f64::INFINITY)]), "-inf");
+    }
+
+    #[test]
+    fn test_printf_strings() {
+        assert_eq!(
+            printf(
+                "%s, %.3s!|%-7s|%c",
+                &[&int(512), &int(512), &int(512), &int(65)]
+            ),
+            "hello, hel!|hello  |A"
+        );
+        assert_eq!(
+            printf("%*s|%s|%%", &[&int(6), &int(512), &int(0)]),
+            " hello|(null)|%"
+        );
+        // Out of the memory
+        assert_eq!(printf("%s|", &[&int(100_000)]), "|");
+    }
+
+    #[test]
+    fn test_printf_overflows() {
+        // Up to INT_MAX
+        assert_eq!(printf("%.2147483647s", &[&int(512)]), "hello");
+        assert_eq!(
+            try_printf("%.2147483648s", &[&int(512)]),
+            Err(errno::EOVERFLOW)
+        );
+        assert_eq!(
+            try_printf("%99999999999999999999999d", &[&int(1)]),
+            Err(errno::EOVERFLOW)
+        );
+        assert_eq!(
+            try_printf("%*d", &[&int(i32::min_value()), &int(1)]),
+            Err(errno::EOVERFLOW)
+        );
+    }
+}
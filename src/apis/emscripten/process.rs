@@ -1,19 +1,23 @@
-use libc::{abort, c_char, c_int, exit, pid_t, EAGAIN};
+//! Exiting and aborting stop the guest by unwinding to the protected call
+//! that runs it (which returns `ErrorKind::Exit` or `ErrorKind::Abort`), so
+//! the host process keeps running.
+use libc::{c_char, c_int, pid_t, EAGAIN};
 
-use crate::webassembly::Instance;
+use crate::recovery::unwind;
+use crate::webassembly::{ErrorKind, Instance};
 use std::ffi::CStr;
 
-pub extern "C" fn abort_with_message(message: &str) {
-    debug!("emscripten::abort_with_message");
-    println!("{}", message);
-    _abort();
+/// Stops the guest with an `ErrorKind::Abort`. The message is moved into the
+/// error, the frames skipped by the unwinding must not own it.
+pub fn abort_with_message<S: Into<String>>(message: S) -> ! {
+    let message = message.into();
+    debug!("emscripten::abort_with_message {}", message);
+    unsafe { unwind(ErrorKind::Abort(message)) }
 }
 
 pub extern "C" fn _abort() {
     debug!("emscripten::_abort");
-    unsafe {
-        abort();
-    }
+    abort_with_message("abort() was called");
 }
 
 pub extern "C" fn _fork(_instance: &mut Instance) -> pid_t {
@@ -26,19 +30,17 @@ pub extern "C" fn _fork(_instance: &mut Instance) -> pid_t {
 
 pub extern "C" fn _exit(status: c_int, _instance: &mut Instance) -> ! {
     debug!("emscripten::_exit {}", status);
-    unsafe { exit(status) }
+    unsafe { unwind(ErrorKind::Exit(status)) }
 }
 
 pub extern "C" fn em_abort(message: u32, instance: &mut Instance) {
     debug!("emscripten::em_abort {}", message);
     let message_addr = instance.memory_offset_addr(0, message as usize) as *mut c_char;
-    unsafe {
-        let message = CStr::from_ptr(message_addr)
-            .to_str()
-            .unwrap_or("Unexpected abort");
-
-        abort_with_message(message);
-    }
+    let message = unsafe { CStr::from_ptr(message_addr) }
+        .to_str()
+        .unwrap_or("Unexpected abort")
+        .to_string();
+    unsafe { unwind(ErrorKind::Abort(message)) }
 }
 
 pub extern "C" fn abort_stack_overflow() {
@@ -55,15 +57,13 @@ pub extern "C" fn _llvm_trap() {
 pub extern "C" fn _system() -> c_int {
     debug!("emscripten::_system");
     // TODO: May need to change this Em impl to a working version
-    eprintln!("Can't call external programs");
+    warn!("Can't call external programs");
     return EAGAIN;
 }
 
 pub extern "C" fn _popen() -> c_int {
     debug!("emscripten::_popen");
     // TODO: May need to change this Em impl to a working version
-    eprintln!("Missing function: popen");
-    unsafe {
-        abort();
-    }
+    warn!("Missing function: popen");
+    abort_with_message("popen is not supported");
 }
@@ -1,6 +1,9 @@
+use super::trace::{guest_buffer, guest_str, open_flags, socketcall_name};
 use super::utils::copy_stat_into_wasm;
 use super::varargs::VarArgs;
-use crate::webassembly::Instance;
+use super::vfs::{errno, last_host_errno, HostDescriptor, Vfs, VfsResult};
+use crate::recovery::unwind;
+use crate::webassembly::{ErrorKind, Instance};
 use byteorder::{ByteOrder, LittleEndian};
 /// NOTE: TODO: These syscalls only support wasm_32 for now because they assume offsets are u32
 /// Syscall list: https://www.cs.utexas.edu/~bismith/test/syscalls/syscalls32.html
@@ -11,15 +14,9 @@ use libc::{
     c_char,
     c_int,
     c_void,
-    chdir,
-    chown,
     // fcntl, setsockopt, getppid
-    close,
     connect,
-    dup2,
-    exit,
-    fcntl,
-    fstat,
+    fd_set,
     getgid,
     getpeername,
     getpid,
@@ -31,19 +28,11 @@ use libc::{
     ioctl,
     // iovec,
     listen,
-    lseek,
-    mkdir,
     msghdr,
-    off_t,
-    open,
     pid_t,
-    pread,
-    pwrite,
-    read,
     // readv,
     recvfrom,
     recvmsg,
-    rmdir,
     // ENOTTY,
     rusage,
     sa_family_t,
@@ -57,22 +46,23 @@ use libc::{
     socket,
     socklen_t,
     ssize_t,
-    stat,
+    timeval,
     uname,
     utsname,
-    write,
-    EINVAL,
     // sockaddr_in,
+    FD_ISSET,
+    FD_SET,
     FIOCLEX,
     FIONBIO,
-    F_GETFD,
-    F_SETFD,
     SOL_SOCKET,
     SO_REUSEADDR,
     TIOCGWINSZ,
 };
 
+use std::ffi::CStr;
+use std::io::SeekFrom;
 use std::mem;
+use std::ptr;
 use std::slice;
 // use std::sys::fd::FileDesc;
 
@@ -94,13 +84,101 @@ use libc::SO_NOSIGPIPE;
 #[cfg(not(target_os = "darwin"))]
 const SO_NOSIGPIPE: c_int = 0;
 
+fn vfs(instance: &mut Instance) -> &mut Vfs {
+    &mut instance.emscripten_data.as_mut().unwrap().vfs
+}
+
+/// The value returned to the guest: the result, or the negated errno.
+fn syscall_ret(result: VfsResult<c_int>) -> c_int {
+    match result {
+        Ok(ret) => ret,
+        Err(errno) => -errno,
+    }
+}
+
+/// The result of a host call for the guest, which expects the negated errno
+/// instead of -1.
+fn host_syscall_ret(ret: c_int) -> c_int {
+    if ret < 0 {
+        -last_host_errno()
+    } else {
+        ret
+    }
+}
+
+/// The NUL-terminated guest path at `ptr`.
+fn guest_path(instance: &Instance, ptr: u32) -> String {
+    let addr = instance.memory_offset_addr(0, ptr as usize) as *const c_char;
+    unsafe { CStr::from_ptr(addr) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Fails with `EFAULT` when the `len` bytes at `ptr` are not all in the guest memory.
+fn check_guest_range(instance: &Instance, ptr: u32, len: usize) -> VfsResult<()> {
+    match (ptr as usize).checked_add(len) {
+        Some(end) if end <= instance.memories[0].current_size() => Ok(()),
+        _ => Err(errno::EFAULT),
+    }
+}
+
+/// The guest buffer of `len` bytes at `ptr`.
+unsafe fn guest_slice<'a>(instance: &Instance, ptr: u32, len: usize) -> VfsResult<&'a mut [u8]> {
+    check_guest_range(instance, ptr, len)?;
+    let addr = instance.memory_offset_addr(0, ptr as usize) as *mut u8;
+    Ok(slice::from_raw_parts_mut(addr, len))
+}
+
+/// The base and length of the guest `iovec` at `ptr`.
+unsafe fn guest_iovec(instance: &Instance, ptr: u32) -> VfsResult<(u32, usize)> {
+    check_guest_range(instance, ptr, 8)?;
+    let addr = instance.memory_offset_addr(0, ptr as usize) as *const u32;
+    Ok((*addr, *addr.add(1) as usize))
+}
+
+/// The descriptors, below `nfds`, in the guest `fd_set` at `ptr` (an array of 32-bit words).
+unsafe fn read_guest_fd_set(instance: &Instance, ptr: u32, nfds: i32) -> Vec<i32> {
+    if ptr == 0 {
+        return Vec::new();
+    }
+    let words = instance.memory_offset_addr(0, ptr as usize) as *const u32;
+    (0..nfds)
+        .filter(|fd| *words.add(*fd as usize / 32) & (1 << (fd % 32)) != 0)
+        .collect()
+}
+
+unsafe fn write_guest_fd_set(instance: &Instance, ptr: u32, nfds: i32, fds: &[i32]) {
+    if ptr == 0 {
+        return;
+    }
+    let words = instance.memory_offset_addr(0, ptr as usize) as *mut u32;
+    for fd in 0..nfds {
+        *words.add(fd as usize / 32) &= !(1 << (fd % 32));
+    }
+    for fd in fds {
+        *words.add(*fd as usize / 32) |= 1 << (fd % 32);
+    }
+}
+
+/// The host descriptor of a guest socket, or -1 (an invalid descriptor).
+fn host_fd(instance: &mut Instance, fd: i32) -> c_int {
+    vfs(instance).host_fd(fd).unwrap_or(-1)
+}
+
+/// Adds a new host descriptor (a socket) to the descriptors of the guest.
+fn insert_host_fd(instance: &mut Instance, fd: c_int) -> c_int {
+    if fd < 0 {
+        return fd;
+    }
+    syscall_ret(vfs(instance).insert(Box::new(HostDescriptor::new(fd))))
+}
+
 /// exit
 pub extern "C" fn ___syscall1(which: c_int, mut varargs: VarArgs, instance: &mut Instance) {
     debug!("emscripten::___syscall1 (exit) {}", which);
     let status: i32 = varargs.get(instance);
-    unsafe {
-        exit(status);
-    }
+    trace_syscall_noreturn!(instance, "exit", "{}", status);
+    unsafe { unwind(ErrorKind::Exit(status)) }
 }
 
 /// read
@@ -112,12 +190,26 @@ pub extern "C" fn ___syscall3(
     debug!("emscripten::___syscall3 (read) {}", which);
     let fd: i32 = varargs.get(instance);
     let buf: u32 = varargs.get(instance);
-    let count: usize = varargs.get(instance);
+    let count: u32 = varargs.get(instance);
     debug!("=> fd: {}, buf_offset: {}, count: {}", fd, buf, count);
-    let buf_addr = instance.memory_offset_addr(0, buf as usize) as *mut c_void;
-    let ret = unsafe { read(fd, buf_addr, count) };
+    let ret = syscall_ret(
+        unsafe { guest_slice(instance, buf, count as usize) }.and_then(|buf_slice| {
+            vfs(instance)
+                .read(fd, buf_slice)
+                .map(|count| count as c_int)
+        }),
+    );
     debug!("=> ret: {}", ret);
-    ret
+    trace_syscall!(
+        instance,
+        "read",
+        ret,
+        "{}, {}, {}",
+        fd,
+        guest_buffer(instance, buf, ret.max(0) as usize),
+        count
+    );
+    ret as ssize_t
 }
 
 /// write
@@ -131,8 +223,23 @@ pub extern "C" fn ___syscall4(
     let buf: u32 = varargs.get(instance);
     let count: u32 = varargs.get(instance);
     debug!("=> fd: {}, buf: {}, count: {}", fd, buf, count);
-    let buf_addr = instance.memory_offset_addr(0, buf as usize) as *const c_void;
-    unsafe { write(fd, buf_addr, count as usize) as i32 }
+    let ret = syscall_ret(
+        unsafe { guest_slice(instance, buf, count as usize) }.and_then(|buf_slice| {
+            vfs(instance)
+                .write(fd, buf_slice)
+                .map(|count| count as c_int)
+        }),
+    );
+    trace_syscall!(
+        instance,
+        "write",
+        ret,
+        "{}, {}, {}",
+        fd,
+        guest_buffer(instance, buf, count as usize),
+        count
+    );
+    ret
 }
 
 /// open
@@ -145,12 +252,20 @@ pub extern "C" fn ___syscall5(
     let pathname: u32 = varargs.get(instance);
     let flags: i32 = varargs.get(instance);
     let mode: u32 = varargs.get(instance);
-    let pathname_addr = instance.memory_offset_addr(0, pathname as usize) as *const i8;
-    let path_str = unsafe { std::ffi::CStr::from_ptr(pathname_addr).to_str().unwrap() };
-    let fd = unsafe { open(pathname_addr, flags, mode) };
+    let path = guest_path(instance, pathname);
+    let fd = syscall_ret(vfs(instance).open(&path, flags, mode));
     debug!(
         "=> pathname: {}, flags: {}, mode: {} = fd: {}\npath: {}",
-        pathname, flags, mode, fd, path_str
+        pathname, flags, mode, fd, path
+    );
+    trace_syscall!(
+        instance,
+        "open",
+        fd,
+        "{}, {}, {:#o}",
+        guest_str(instance, pathname),
+        open_flags(flags),
+        mode
     );
     fd
 }
@@ -164,7 +279,23 @@ pub extern "C" fn ___syscall6(
     debug!("emscripten::___syscall6 (close) {}", which);
     let fd: i32 = varargs.get(instance);
     debug!("fd: {}", fd);
-    unsafe { close(fd) }
+    let ret = syscall_ret(vfs(instance).close(fd).map(|_| 0));
+    trace_syscall!(instance, "close", ret, "{}", fd);
+    ret
+}
+
+/// unlink
+pub extern "C" fn ___syscall10(
+    which: c_int,
+    mut varargs: VarArgs,
+    instance: &mut Instance,
+) -> c_int {
+    debug!("emscripten::___syscall10 (unlink) {}", which);
+    let pathname: u32 = varargs.get(instance);
+    let path = guest_path(instance, pathname);
+    let ret = syscall_ret(vfs(instance).unlink(&path).map(|_| 0));
+    trace_syscall!(instance, "unlink", ret, "{}", guest_str(instance, pathname));
+    ret
 }
 
 // chdir
@@ -175,19 +306,71 @@ pub extern "C" fn ___syscall12(
 ) -> c_int {
     debug!("emscripten::___syscall12 (chdir) {}", which);
     let path_addr: i32 = varargs.get(instance);
-    unsafe {
-        let path_ptr = instance.memory_offset_addr(0, path_addr as usize) as *const i8;
-        let path = std::ffi::CStr::from_ptr(path_ptr);
-        let ret = chdir(path_ptr);
-        debug!("=> path: {:?}, ret: {}", path, ret);
-        ret
-    }
+    let path = guest_path(instance, path_addr as u32);
+    let ret = syscall_ret(vfs(instance).chdir(&path).map(|_| 0));
+    debug!("=> path: {:?}, ret: {}", path, ret);
+    trace_syscall!(
+        instance,
+        "chdir",
+        ret,
+        "{}",
+        guest_str(instance, path_addr as u32)
+    );
+    ret
 }
 
 // getpid
-pub extern "C" fn ___syscall20() -> pid_t {
+pub extern "C" fn ___syscall20(_which: c_int, _varargs: VarArgs, instance: &mut Instance) -> pid_t {
     debug!("emscripten::___syscall20 (getpid)");
-    unsafe { getpid() }
+    let pid = unsafe { getpid() };
+    trace_syscall!(instance, "getpid", pid, "");
+    pid
+}
+
+// access
+pub extern "C" fn ___syscall33(
+    which: c_int,
+    mut varargs: VarArgs,
+    instance: &mut Instance,
+) -> c_int {
+    debug!("emscripten::___syscall33 (access) {}", which);
+    let pathname: u32 = varargs.get(instance);
+    let amode: i32 = varargs.get(instance);
+    let path = guest_path(instance, pathname);
+    // The permissions aren't emulated, an existing file is accessible
+    let ret = syscall_ret(vfs(instance).stat(&path).map(|_| 0));
+    trace_syscall!(
+        instance,
+        "access",
+        ret,
+        "{}, {}",
+        guest_str(instance, pathname),
+        amode
+    );
+    ret
+}
+
+// rename
+pub extern "C" fn ___syscall38(
+    which: c_int,
+    mut varargs: VarArgs,
+    instance: &mut Instance,
+) -> c_int {
+    debug!("emscripten::___syscall38 (rename) {}", which);
+    let old_pathname: u32 = varargs.get(instance);
+    let new_pathname: u32 = varargs.get(instance);
+    let old_path = guest_path(instance, old_pathname);
+    let new_path = guest_path(instance, new_pathname);
+    let ret = syscall_ret(vfs(instance).rename(&old_path, &new_path).map(|_| 0));
+    trace_syscall!(
+        instance,
+        "rename",
+        ret,
+        "{}, {}",
+        guest_str(instance, old_pathname),
+        guest_str(instance, new_pathname)
+    );
+    ret
 }
 
 // mkdir
@@ -199,8 +382,17 @@ pub extern "C" fn ___syscall39(
     debug!("emscripten::___syscall39 (mkdir) {}", which);
     let pathname: u32 = varargs.get(instance);
     let mode: u32 = varargs.get(instance);
-    let pathname_addr = instance.memory_offset_addr(0, pathname as usize) as *const i8;
-    unsafe { mkdir(pathname_addr, mode as _) }
+    let path = guest_path(instance, pathname);
+    let ret = syscall_ret(vfs(instance).mkdir(&path, mode).map(|_| 0));
+    trace_syscall!(
+        instance,
+        "mkdir",
+        ret,
+        "{}, {:#o}",
+        guest_str(instance, pathname),
+        mode
+    );
+    ret
 }
 
 // rmdir
@@ -211,8 +403,23 @@ pub extern "C" fn ___syscall40(
 ) -> c_int {
     debug!("emscripten::___syscall40 (rmdir)");
     let pathname: u32 = varargs.get(instance);
-    let pathname_addr = instance.memory_offset_addr(0, pathname as usize) as *const i8;
-    unsafe { rmdir(pathname_addr) }
+    let path = guest_path(instance, pathname);
+    let ret = syscall_ret(vfs(instance).rmdir(&path).map(|_| 0));
+    trace_syscall!(instance, "rmdir", ret, "{}", guest_str(instance, pathname));
+    ret
+}
+
+// dup
+pub extern "C" fn ___syscall41(
+    which: c_int,
+    mut varargs: VarArgs,
+    instance: &mut Instance,
+) -> c_int {
+    debug!("emscripten::___syscall41 (dup) {}", which);
+    let fd: i32 = varargs.get(instance);
+    let ret = syscall_ret(vfs(instance).dup(fd));
+    trace_syscall!(instance, "dup", ret, "{}", fd);
+    ret
 }
 
 /// ioctl
@@ -225,13 +432,18 @@ pub extern "C" fn ___syscall54(
     let fd: i32 = varargs.get(instance);
     let request: u32 = varargs.get(instance);
     debug!("fd: {}, op: {}", fd, request);
+    // Only the host descriptors (the standard streams, the sockets) are terminals
+    let host_fd = vfs(instance).host_fd(fd);
     // Got the equivalents here: https://code.woboq.org/linux/linux/include/uapi/asm-generic/ioctls.h.html
-    match request as _ {
+    let ret = match request as _ {
         21537 => {
             // FIONBIO
             let argp: u32 = varargs.get(instance);
             let argp_ptr = instance.memory_offset_addr(0, argp as _);
-            let ret = unsafe { ioctl(fd, FIONBIO, argp_ptr) };
+            let ret = match host_fd {
+                Some(host_fd) => host_syscall_ret(unsafe { ioctl(host_fd, FIONBIO, argp_ptr) }),
+                None => 0,
+            };
             debug!("ret(FIONBIO): {}", ret);
             ret
             // 0
@@ -240,15 +452,22 @@ pub extern "C" fn ___syscall54(
             // TIOCGWINSZ
             let argp: u32 = varargs.get(instance);
             let argp_ptr = instance.memory_offset_addr(0, argp as _);
-            let ret = unsafe { ioctl(fd, TIOCGWINSZ, argp_ptr) };
-            debug!("ret(TIOCGWINSZ): {} (harcoded to 0)", ret);
-            // ret
-            // TODO: We hardcode the value to have emscripten tests pass, as for some reason
-            // when the capturer is active, ioctl returns -1 instead of 0
-            if ret == -1 {
-                0
-            } else {
-                ret
+            match host_fd {
+                Some(host_fd) => {
+                    let ret = unsafe { ioctl(host_fd, TIOCGWINSZ, argp_ptr) };
+                    debug!("ret(TIOCGWINSZ): {} (harcoded to 0)", ret);
+                    // ret
+                    // TODO: We hardcode the value to have emscripten tests pass, as for some reason
+                    // when the capturer is active, ioctl returns -1 instead of 0
+                    if ret == -1 {
+                        0
+                    } else {
+                        ret
+                    }
+                }
+                // Like emscripten, the window size isn't reported to its terminals
+                None if vfs(instance).is_tty(fd) => 0,
+                None => -errno::ENOTTY,
             }
         }
         _ => {
@@ -258,7 +477,9 @@ pub extern "C" fn ___syscall54(
             );
             0
         }
-    }
+    };
+    trace_syscall!(instance, "ioctl", ret, "{}, {:#x}", fd, request);
+    ret
 }
 
 // setpgid
@@ -270,7 +491,9 @@ pub extern "C" fn ___syscall57(
     debug!("emscripten::___syscall57 (setpgid) {}", which);
     let pid: i32 = varargs.get(instance);
     let pgid: i32 = varargs.get(instance);
-    unsafe { setpgid(pid, pgid) }
+    let ret = host_syscall_ret(unsafe { setpgid(pid, pgid) });
+    trace_syscall!(instance, "setpgid", ret, "{}, {}", pid, pgid);
+    ret
 }
 
 // dup2
@@ -284,13 +507,17 @@ pub extern "C" fn ___syscall63(
     let src: i32 = varargs.get(instance);
     let dst: i32 = varargs.get(instance);
 
-    unsafe { dup2(src, dst) }
+    let ret = syscall_ret(vfs(instance).dup2(src, dst));
+    trace_syscall!(instance, "dup2", ret, "{}, {}", src, dst);
+    ret
 }
 
 // getppid
-pub extern "C" fn ___syscall64() -> pid_t {
+pub extern "C" fn ___syscall64(_which: c_int, _varargs: VarArgs, instance: &mut Instance) -> pid_t {
     debug!("emscripten::___syscall64 (getppid)");
-    unsafe { getpid() }
+    let pid = unsafe { getpid() };
+    trace_syscall!(instance, "getppid", pid, "");
+    pid
 }
 
 // socketcall
@@ -331,7 +558,7 @@ pub extern "C" fn ___syscall102(
             let domain: i32 = socket_varargs.get(instance);
             let ty: i32 = socket_varargs.get(instance);
             let protocol: i32 = socket_varargs.get(instance);
-            let fd = unsafe { socket(domain, ty, protocol) };
+            let fd = host_syscall_ret(unsafe { socket(domain, ty, protocol) });
             // set_cloexec
             unsafe {
                 ioctl(fd, FIOCLEX);
@@ -349,10 +576,12 @@ pub extern "C" fn ___syscall102(
                 );
             };
 
+            let fd = insert_host_fd(instance, fd);
             debug!(
                 "=> domain: {} (AF_INET/2), type: {} (SOCK_STREAM/1), protocol: {} = fd: {}",
                 domain, ty, protocol, fd
             );
+            trace_syscall!(instance, "socket", fd, "{}, {}, {}", domain, ty, protocol);
             fd
         }
         2 => {
@@ -373,12 +602,22 @@ pub extern "C" fn ___syscall102(
                 );
             }
 
-            let status = unsafe { bind(socket, address, address_len) };
+            let status =
+                host_syscall_ret(unsafe { bind(host_fd(instance, socket), address, address_len) });
             // debug!("=> status: {}", status);
             debug!(
                 "=> socketfd: {}, address: {:?}, address_len: {} = status: {}",
                 socket, address, address_len, status
             );
+            trace_syscall!(
+                instance,
+                "bind",
+                status,
+                "{}, {:?}, {}",
+                socket,
+                address,
+                address_len
+            );
             status
             // -1
         }
@@ -390,18 +629,31 @@ pub extern "C" fn ___syscall102(
             let address: u32 = socket_varargs.get(instance);
             let address_len: u32 = socket_varargs.get(instance);
             let address = instance.memory_offset_addr(0, address as usize) as *mut sockaddr;
-            unsafe { connect(socket, address, address_len) }
+            let ret = host_syscall_ret(unsafe {
+                connect(host_fd(instance, socket), address, address_len)
+            });
+            trace_syscall!(
+                instance,
+                "connect",
+                ret,
+                "{}, {:?}, {}",
+                socket,
+                address,
+                address_len
+            );
+            ret
         }
         4 => {
             debug!("socket: listen");
             // listen (socket: c_int, backlog: c_int) -> c_int
             let socket: i32 = socket_varargs.get(instance);
             let backlog: i32 = socket_varargs.get(instance);
-            let status = unsafe { listen(socket, backlog) };
+            let status = host_syscall_ret(unsafe { listen(host_fd(instance, socket), backlog) });
             debug!(
                 "=> socketfd: {}, backlog: {} = status: {}",
                 socket, backlog, status
             );
+            trace_syscall!(instance, "listen", status, "{}, {}", socket, backlog);
             status
         }
         5 => {
@@ -420,7 +672,9 @@ pub extern "C" fn ___syscall102(
                 instance.memory_offset_addr(0, address_len as usize) as *mut socklen_t;
             // let mut address_len_addr: socklen_t = 0;
 
-            let fd = unsafe { accept(socket, address, address_len_addr) };
+            let fd = host_syscall_ret(unsafe {
+                accept(host_fd(instance, socket), address, address_len_addr)
+            });
 
             unsafe {
                 let address_linux =
@@ -433,8 +687,18 @@ pub extern "C" fn ___syscall102(
             unsafe {
                 ioctl(fd, FIOCLEX);
             };
+            let fd = insert_host_fd(instance, fd);
 
             debug!("fd: {}", fd);
+            trace_syscall!(
+                instance,
+                "accept",
+                fd,
+                "{}, {:?}, {}",
+                socket,
+                address,
+                address_len
+            );
 
             fd
         }
@@ -447,7 +711,19 @@ pub extern "C" fn ___syscall102(
             let address = instance.memory_offset_addr(0, address as usize) as *mut sockaddr;
             let address_len_addr =
                 instance.memory_offset_addr(0, address_len as usize) as *mut socklen_t;
-            unsafe { getsockname(socket, address, address_len_addr) }
+            let ret = host_syscall_ret(unsafe {
+                getsockname(host_fd(instance, socket), address, address_len_addr)
+            });
+            trace_syscall!(
+                instance,
+                "getsockname",
+                ret,
+                "{}, {:?}, {:?}",
+                socket,
+                address,
+                address_len_addr
+            );
+            ret
         }
         7 => {
             debug!("socket: getpeername");
@@ -458,7 +734,19 @@ pub extern "C" fn ___syscall102(
             let address = instance.memory_offset_addr(0, address as usize) as *mut sockaddr;
             let address_len_addr =
                 instance.memory_offset_addr(0, address_len as usize) as *mut socklen_t;
-            unsafe { getpeername(socket, address, address_len_addr) }
+            let ret = host_syscall_ret(unsafe {
+                getpeername(host_fd(instance, socket), address, address_len_addr)
+            });
+            trace_syscall!(
+                instance,
+                "getpeername",
+                ret,
+                "{}, {:?}, {:?}",
+                socket,
+                address,
+                address_len_addr
+            );
+            ret
         }
         11 => {
             debug!("socket: sendto");
@@ -471,7 +759,29 @@ pub extern "C" fn ___syscall102(
             let address_len: u32 = socket_varargs.get(instance);
             let buf_addr = instance.memory_offset_addr(0, buf as usize) as *mut c_void;
             let address = instance.memory_offset_addr(0, address as usize) as *mut sockaddr;
-            unsafe { sendto(socket, buf_addr, flags, len, address, address_len) as i32 }
+            let ret = host_syscall_ret(unsafe {
+                sendto(
+                    host_fd(instance, socket),
+                    buf_addr,
+                    flags,
+                    len,
+                    address,
+                    address_len,
+                ) as i32
+            });
+            trace_syscall!(
+                instance,
+                "sendto",
+                ret,
+                "{}, {}, {}, {}, {:?}, {}",
+                socket,
+                guest_buffer(instance, buf, flags),
+                flags,
+                len,
+                address,
+                address_len
+            );
+            ret
         }
         12 => {
             debug!("socket: recvfrom");
@@ -486,7 +796,29 @@ pub extern "C" fn ___syscall102(
             let address = instance.memory_offset_addr(0, address as usize) as *mut sockaddr;
             let address_len_addr =
                 instance.memory_offset_addr(0, address_len as usize) as *mut socklen_t;
-            unsafe { recvfrom(socket, buf_addr, flags, len, address, address_len_addr) as i32 }
+            let ret = host_syscall_ret(unsafe {
+                recvfrom(
+                    host_fd(instance, socket),
+                    buf_addr,
+                    flags,
+                    len,
+                    address,
+                    address_len_addr,
+                ) as i32
+            });
+            trace_syscall!(
+                instance,
+                "recvfrom",
+                ret,
+                "{}, {}, {}, {}, {:?}, {:?}",
+                socket,
+                guest_buffer(instance, buf, ret.max(0) as usize),
+                flags,
+                len,
+                address,
+                address_len_addr
+            );
+            ret
         }
         14 => {
             debug!("socket: setsockopt");
@@ -506,11 +838,28 @@ pub extern "C" fn ___syscall102(
             let value: u32 = socket_varargs.get(instance);
             let option_len: u32 = socket_varargs.get(instance);
             let value_addr = instance.memory_offset_addr(0, value as usize) as *mut c_void; // Endian problem
-            let ret = unsafe { setsockopt(socket, level, name, value_addr, option_len) };
+            let ret = host_syscall_ret(unsafe {
+                setsockopt(
+                    host_fd(instance, socket),
+                    level,
+                    name,
+                    value_addr,
+                    option_len,
+                )
+            });
 
             // debug!("option_value = {:?}", unsafe { *(value_addr as *const u32) });
 
             debug!("=> socketfd: {}, level: {} (SOL_SOCKET/0xffff), name: {} (SO_REUSEADDR/4), value_addr: {:?}, option_len: {} = status: {}", socket, level, name, value_addr, option_len, ret);
+            trace_syscall!(
+                instance,
+                "setsockopt",
+                ret,
+                "{}, SOL_SOCKET, SO_REUSEADDR, {:?}, {}",
+                socket,
+                value_addr,
+                option_len
+            );
             ret
         }
         15 => {
@@ -524,7 +873,27 @@ pub extern "C" fn ___syscall102(
             let value_addr = instance.memory_offset_addr(0, value as usize) as *mut c_void;
             let option_len_addr =
                 instance.memory_offset_addr(0, option_len as usize) as *mut socklen_t;
-            unsafe { getsockopt(socket, level, name, value_addr, option_len_addr) }
+            let ret = host_syscall_ret(unsafe {
+                getsockopt(
+                    host_fd(instance, socket),
+                    level,
+                    name,
+                    value_addr,
+                    option_len_addr,
+                )
+            });
+            trace_syscall!(
+                instance,
+                "getsockopt",
+                ret,
+                "{}, {}, {}, {:?}, {:?}",
+                socket,
+                level,
+                name,
+                value_addr,
+                option_len_addr
+            );
+            ret
         }
         16 => {
             debug!("socket: sendmsg");
@@ -533,7 +902,19 @@ pub extern "C" fn ___syscall102(
             let msg: u32 = socket_varargs.get(instance);
             let flags: i32 = socket_varargs.get(instance);
             let msg_addr = instance.memory_offset_addr(0, msg as usize) as *const msghdr;
-            unsafe { sendmsg(socket, msg_addr, flags) as i32 }
+            let ret = host_syscall_ret(unsafe {
+                sendmsg(host_fd(instance, socket), msg_addr, flags) as i32
+            });
+            trace_syscall!(
+                instance,
+                "sendmsg",
+                ret,
+                "{}, {:?}, {}",
+                socket,
+                msg_addr,
+                flags
+            );
+            ret
         }
         17 => {
             debug!("socket: recvmsg");
@@ -542,10 +923,23 @@ pub extern "C" fn ___syscall102(
             let msg: u32 = socket_varargs.get(instance);
             let flags: i32 = socket_varargs.get(instance);
             let msg_addr = instance.memory_offset_addr(0, msg as usize) as *mut msghdr;
-            unsafe { recvmsg(socket, msg_addr, flags) as i32 }
+            let ret = host_syscall_ret(unsafe {
+                recvmsg(host_fd(instance, socket), msg_addr, flags) as i32
+            });
+            trace_syscall!(
+                instance,
+                "recvmsg",
+                ret,
+                "{}, {:?}, {}",
+                socket,
+                msg_addr,
+                flags
+            );
+            ret
         }
         _ => {
             // others
+            trace_syscall!(instance, socketcall_name(call as i32), -1, "...");
             -1
         }
     }
@@ -564,11 +958,21 @@ pub extern "C" fn ___syscall114(
     let rusage: u32 = varargs.get(instance);
     let status_addr = instance.memory_offset_addr(0, status as usize) as *mut c_int;
     let rusage_addr = instance.memory_offset_addr(0, rusage as usize) as *mut rusage;
-    let res = unsafe { wait4(pid, status_addr, options, rusage_addr) };
+    let res = host_syscall_ret(unsafe { wait4(pid, status_addr, options, rusage_addr) });
     debug!(
         "=> pid: {}, status: {:?}, options: {}, rusage: {:?} = pid: {}",
         pid, status_addr, options, rusage_addr, res
     );
+    trace_syscall!(
+        instance,
+        "wait4",
+        res,
+        "{}, {:?}, {}, {:?}",
+        pid,
+        status_addr,
+        options,
+        rusage_addr
+    );
     res
 }
 
@@ -583,7 +987,9 @@ pub extern "C" fn ___syscall122(
     let buf: u32 = varargs.get(instance);
     debug!("=> buf: {}", buf);
     let buf_addr = instance.memory_offset_addr(0, buf as usize) as *mut utsname;
-    unsafe { uname(buf_addr) }
+    let ret = host_syscall_ret(unsafe { uname(buf_addr) });
+    trace_syscall!(instance, "uname", ret, "{:?}", buf_addr);
+    ret
 }
 
 // select
@@ -603,10 +1009,85 @@ pub extern "C" fn ___syscall142(
     assert!(nfds <= 64, "`nfds` must be less than or equal to 64");
     assert!(exceptfds == 0, "`exceptfds` is not supporrted");
 
-    let readfds_ptr = instance.memory_offset_addr(0, readfds as _) as _;
-    let writefds_ptr = instance.memory_offset_addr(0, writefds as _) as _;
+    // The host descriptors are polled with the host select, the files of the
+    // `Vfs` are always ready
+    let guest_read = unsafe { read_guest_fd_set(instance, readfds, nfds) };
+    let guest_write = unsafe { read_guest_fd_set(instance, writefds, nfds) };
+    let mut host_readfds: fd_set = unsafe { mem::zeroed() };
+    let mut host_writefds: fd_set = unsafe { mem::zeroed() };
+    let mut host_nfds = 0;
+    let mut ready_read = Vec::new();
+    let mut ready_write = Vec::new();
+    for (guest_fds, host_fds, ready) in &mut [
+        (&guest_read, &mut host_readfds, &mut ready_read),
+        (&guest_write, &mut host_writefds, &mut ready_write),
+    ] {
+        for &fd in guest_fds.iter() {
+            match vfs(instance).host_fd(fd) {
+                Some(host_fd) => {
+                    unsafe { FD_SET(host_fd, &mut **host_fds) };
+                    host_nfds = host_nfds.max(host_fd + 1);
+                }
+                None if vfs(instance).fstat(fd).is_ok() => ready.push(fd),
+                None => {
+                    trace_syscall!(instance, "select", -errno::EBADF, "{}, ...", nfds);
+                    return -errno::EBADF;
+                }
+            }
+        }
+    }
 
-    unsafe { select(nfds, readfds_ptr, writefds_ptr, 0 as _, 0 as _) }
+    let mut ret = 0;
+    if host_nfds > 0 {
+        // Don't block if some files are ready
+        let mut no_wait = timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        };
+        let timeout = if ready_read.is_empty() && ready_write.is_empty() {
+            ptr::null_mut()
+        } else {
+            &mut no_wait as *mut timeval
+        };
+        ret = unsafe {
+            select(
+                host_nfds,
+                &mut host_readfds,
+                &mut host_writefds,
+                ptr::null_mut(),
+                timeout,
+            )
+        };
+    }
+    if ret >= 0 {
+        for (guest_fds, host_fds, ready) in &mut [
+            (&guest_read, &mut host_readfds, &mut ready_read),
+            (&guest_write, &mut host_writefds, &mut ready_write),
+        ] {
+            for &fd in guest_fds.iter() {
+                if let Some(host_fd) = vfs(instance).host_fd(fd) {
+                    if unsafe { FD_ISSET(host_fd, &mut **host_fds) } {
+                        ready.push(fd);
+                    }
+                }
+            }
+        }
+        unsafe {
+            write_guest_fd_set(instance, readfds, nfds, &ready_read);
+            write_guest_fd_set(instance, writefds, nfds, &ready_write);
+        }
+        ret = (ready_read.len() + ready_write.len()) as c_int;
+    }
+    trace_syscall!(
+        instance,
+        "select",
+        ret,
+        "{}, {:?}, {:?}, NULL, NULL",
+        nfds,
+        guest_read,
+        guest_write
+    );
+    ret
 }
 
 // mmap2
@@ -632,30 +1113,72 @@ pub extern "C" fn ___syscall192(
         (emscripten_data.memalign, emscripten_data.memset)
     };
 
-    if fd == -1 {
+    let ret = if fd == -1 {
         let ptr = memalign(16384, len, instance);
         if ptr == 0 {
-            return -1;
+            -1
+        } else {
+            memset(ptr, 0, len, instance);
+            ptr as _
         }
-        memset(ptr, 0, len, instance);
-        ptr as _
     } else {
         -1
-    }
+    };
+    trace_syscall!(
+        instance,
+        "mmap2",
+        ret,
+        "{:#x}, {}, {}, {}, {}, {}",
+        addr,
+        len,
+        prot,
+        flags,
+        fd,
+        off
+    );
+    ret
 }
 
-/// lseek
+/// _llseek
 pub extern "C" fn ___syscall140(
     which: c_int,
     mut varargs: VarArgs,
     instance: &mut Instance,
-) -> off_t {
+) -> c_int {
     debug!("emscripten::___syscall140 (lseek) {}", which);
     let fd: i32 = varargs.get(instance);
-    let offset: i64 = varargs.get(instance);
+    let offset_high: u32 = varargs.get(instance);
+    let offset_low: u32 = varargs.get(instance);
+    let result: u32 = varargs.get(instance);
     let whence: i32 = varargs.get(instance);
+    let offset = (i64::from(offset_high) << 32) | i64::from(offset_low);
     debug!("=> fd: {}, offset: {}, whence = {}", fd, offset, whence);
-    unsafe { lseek(fd, offset, whence) }
+    let pos = match whence {
+        0 if offset >= 0 => Ok(SeekFrom::Start(offset as u64)),
+        1 => Ok(SeekFrom::Current(offset)),
+        2 => Ok(SeekFrom::End(offset)),
+        _ => Err(errno::EINVAL),
+    };
+    let ret = syscall_ret(
+        pos.and_then(|pos| vfs(instance).seek(fd, pos))
+            .map(|position| {
+                // The new position is returned through `result`
+                let result_ptr = instance.memory_offset_addr(0, result as usize) as *mut i64;
+                unsafe { result_ptr.write_unaligned(position as i64) };
+                0
+            }),
+    );
+    trace_syscall!(
+        instance,
+        "_llseek",
+        ret,
+        "{}, {}, {:#x}, {}",
+        fd,
+        offset,
+        result,
+        whence
+    );
+    ret
 }
 
 /// readv
@@ -665,42 +1188,41 @@ pub extern "C" fn ___syscall145(
     instance: &mut Instance,
 ) -> ssize_t {
     debug!("emscripten::___syscall145 (readv) {}", which);
-    // let fd: i32 = varargs.get(instance);
-    // let iov: u32 = varargs.get(instance);
-    // let iovcnt: i32 = varargs.get(instance);
-    // debug!("=> fd: {}, iov: {}, iovcnt = {}", fd, iov, iovcnt);
-    // let iov_addr = instance.memory_offset_addr(0, iov as usize) as *mut iovec;
-    // unsafe { readv(fd, iov_addr, iovcnt) }
-
     let fd: i32 = varargs.get(instance);
     let iov: i32 = varargs.get(instance);
     let iovcnt: i32 = varargs.get(instance);
 
-    #[repr(C)]
-    struct GuestIovec {
-        iov_base: i32,
-        iov_len: i32,
-    }
-
     debug!("=> fd: {}, iov: {}, iovcnt = {}", fd, iov, iovcnt);
-    let mut ret = 0;
-    unsafe {
-        for i in 0..iovcnt {
-            let guest_iov_addr =
-                instance.memory_offset_addr(0, (iov + i * 8) as usize) as *mut GuestIovec;
-            let iov_base =
-                instance.memory_offset_addr(0, (*guest_iov_addr).iov_base as usize) as *mut c_void;
-            let iov_len: usize = (*guest_iov_addr).iov_len as _;
-            // debug!("=> iov_addr: {:?}, {:?}", iov_base, iov_len);
-            let curr = read(fd, iov_base, iov_len);
-            if curr < 0 {
-                return -1;
+    let mut result = Ok(0);
+    for i in 0..iovcnt {
+        let buf_slice = unsafe {
+            guest_iovec(instance, (iov + i * 8) as u32)
+                .and_then(|(iov_base, iov_len)| guest_slice(instance, iov_base, iov_len))
+        };
+        let iov_len = buf_slice
+            .as_ref()
+            .map(|buf_slice| buf_slice.len())
+            .unwrap_or(0);
+        match buf_slice.and_then(|buf_slice| vfs(instance).read(fd, buf_slice)) {
+            Ok(count) => {
+                result = result.map(|total| total + count as c_int);
+                // Stop at the end of the file
+                if count < iov_len {
+                    break;
+                }
+            }
+            // Report the error if nothing was read
+            Err(errno) => {
+                if result == Ok(0) {
+                    result = Err(errno);
+                }
+                break;
             }
-            ret += curr;
         }
-        // debug!(" => ret: {}", ret);
-        ret
     }
+    let ret = syscall_ret(result);
+    trace_syscall!(instance, "readv", ret, "{}, {:#x}, {}", fd, iov, iovcnt);
+    ret as ssize_t
 }
 
 // writev
@@ -714,31 +1236,36 @@ pub extern "C" fn ___syscall146(
     let iov: i32 = varargs.get(instance);
     let iovcnt: i32 = varargs.get(instance);
 
-    #[repr(C)]
-    struct GuestIovec {
-        iov_base: i32,
-        iov_len: i32,
-    }
-
     debug!("=> fd: {}, iov: {}, iovcnt = {}", fd, iov, iovcnt);
-    let mut ret = 0;
-    unsafe {
-        for i in 0..iovcnt {
-            let guest_iov_addr =
-                instance.memory_offset_addr(0, (iov + i * 8) as usize) as *mut GuestIovec;
-            let iov_base = instance.memory_offset_addr(0, (*guest_iov_addr).iov_base as usize)
-                as *const c_void;
-            let iov_len: usize = (*guest_iov_addr).iov_len as _;
-            // debug!("=> iov_addr: {:?}, {:?}", iov_base, iov_len);
-            let curr = write(fd, iov_base, iov_len);
-            if curr < 0 {
-                return -1;
+    let mut result = Ok(0);
+    for i in 0..iovcnt {
+        let buf_slice = unsafe {
+            guest_iovec(instance, (iov + i * 8) as u32)
+                .and_then(|(iov_base, iov_len)| guest_slice(instance, iov_base, iov_len))
+        };
+        let iov_len = buf_slice
+            .as_ref()
+            .map(|buf_slice| buf_slice.len())
+            .unwrap_or(0);
+        match buf_slice.and_then(|buf_slice| vfs(instance).write(fd, buf_slice)) {
+            Ok(count) => {
+                result = result.map(|total| total + count as c_int);
+                if count < iov_len {
+                    break;
+                }
+            }
+            // Report the error if nothing was written
+            Err(errno) => {
+                if result == Ok(0) {
+                    result = Err(errno);
+                }
+                break;
             }
-            ret += curr;
         }
-        // debug!(" => ret: {}", ret);
-        ret
     }
+    let ret = syscall_ret(result);
+    trace_syscall!(instance, "writev", ret, "{}, {:#x}, {}", fd, iov, iovcnt);
+    ret as ssize_t
 }
 
 // pread
@@ -757,9 +1284,28 @@ pub extern "C" fn ___syscall180(
     }
     let offset: i64 = varargs.get(instance);
 
-    let buf_ptr = instance.memory_offset_addr(0, buf as _) as _;
-
-    unsafe { pread(fd, buf_ptr, count as _, offset) as _ }
+    let ret = if offset < 0 {
+        -errno::EINVAL
+    } else {
+        syscall_ret(
+            unsafe { guest_slice(instance, buf, count as usize) }.and_then(|buf_slice| {
+                vfs(instance)
+                    .pread(fd, buf_slice, offset as u64)
+                    .map(|count| count as c_int)
+            }),
+        )
+    };
+    trace_syscall!(
+        instance,
+        "pread",
+        ret,
+        "{}, {}, {}, {}",
+        fd,
+        guest_buffer(instance, buf, ret.max(0) as usize),
+        count,
+        offset
+    );
+    ret
 }
 
 // pwrite
@@ -778,15 +1324,62 @@ pub extern "C" fn ___syscall181(
     }
     let offset: i64 = varargs.get(instance);
 
-    let buf_ptr = instance.memory_offset_addr(0, buf as _) as _;
-    let status = unsafe { pwrite(fd, buf_ptr, count as _, offset) as _ };
+    let status = if offset < 0 {
+        -errno::EINVAL
+    } else {
+        syscall_ret(
+            unsafe { guest_slice(instance, buf, count as usize) }.and_then(|buf_slice| {
+                vfs(instance)
+                    .pwrite(fd, buf_slice, offset as u64)
+                    .map(|count| count as c_int)
+            }),
+        )
+    };
     debug!(
         "=> fd: {}, buf: {}, count: {}, offset: {} = status:{}",
         fd, buf, count, offset, status
     );
+    trace_syscall!(
+        instance,
+        "pwrite",
+        status,
+        "{}, {}, {}, {}",
+        fd,
+        guest_buffer(instance, buf, count as usize),
+        count,
+        offset
+    );
     status
 }
 
+// getcwd
+pub extern "C" fn ___syscall183(
+    which: c_int,
+    mut varargs: VarArgs,
+    instance: &mut Instance,
+) -> c_int {
+    debug!("emscripten::___syscall183 (getcwd) {}", which);
+    let buf: u32 = varargs.get(instance);
+    let size: u32 = varargs.get(instance);
+    let cwd = vfs(instance).cwd().to_string();
+    let ret = if size == 0 {
+        -errno::EINVAL
+    } else if (size as usize) < cwd.len() + 1 {
+        -errno::ERANGE
+    } else {
+        match unsafe { guest_slice(instance, buf, cwd.len() + 1) } {
+            Ok(buf_slice) => {
+                buf_slice[..cwd.len()].copy_from_slice(cwd.as_bytes());
+                buf_slice[cwd.len()] = 0;
+                buf as c_int
+            }
+            Err(errno) => -errno,
+        }
+    };
+    trace_syscall!(instance, "getcwd", ret, "{:#x}, {}", buf, size);
+    ret
+}
+
 // stat64
 pub extern "C" fn ___syscall195(
     which: c_int,
@@ -797,18 +1390,24 @@ pub extern "C" fn ___syscall195(
     let pathname: u32 = varargs.get(instance);
     let buf: u32 = varargs.get(instance);
 
-    let pathname_addr = instance.memory_offset_addr(0, pathname as usize) as *const i8;
-
-    unsafe {
-        let mut _stat: stat = std::mem::zeroed();
-        let ret = stat(pathname_addr, &mut _stat);
-        debug!("ret: {}", ret);
-        if ret != 0 {
-            return ret;
+    let path = guest_path(instance, pathname);
+    let ret = match vfs(instance).stat(&path) {
+        Ok(stat) => {
+            unsafe { copy_stat_into_wasm(instance, buf, &stat) };
+            0
         }
-        copy_stat_into_wasm(instance, buf, &_stat);
-    }
-    0
+        Err(errno) => -errno,
+    };
+    debug!("ret: {}", ret);
+    trace_syscall!(
+        instance,
+        "stat64",
+        ret,
+        "{}, {:#x}",
+        guest_str(instance, pathname),
+        buf
+    );
+    ret
 }
 
 // fstat64
@@ -821,35 +1420,46 @@ pub extern "C" fn ___syscall197(
     let fd: c_int = varargs.get(instance);
     let buf: u32 = varargs.get(instance);
 
-    unsafe {
-        let mut stat = std::mem::zeroed();
-        let ret = fstat(fd, &mut stat);
-        debug!("ret: {}", ret);
-        if ret != 0 {
-            return ret;
+    let ret = match vfs(instance).fstat(fd) {
+        Ok(stat) => {
+            unsafe { copy_stat_into_wasm(instance, buf, &stat) };
+            0
         }
-        copy_stat_into_wasm(instance, buf, &stat);
-    }
-
-    0
+        Err(errno) => -errno,
+    };
+    debug!("ret: {}", ret);
+    trace_syscall!(instance, "fstat64", ret, "{}, {:#x}", fd, buf);
+    ret
 }
 
 // getgid
-pub extern "C" fn ___syscall201() -> gid_t {
+pub extern "C" fn ___syscall201(
+    _which: c_int,
+    _varargs: VarArgs,
+    instance: &mut Instance,
+) -> gid_t {
     debug!("emscripten::___syscall201 (getgid)");
-    unsafe {
+    let gid = unsafe {
         // Maybe fix: Emscripten returns 0 always
         getgid()
-    }
+    };
+    trace_syscall!(instance, "getgid", gid, "");
+    gid
 }
 
 // getgid32
-pub extern "C" fn ___syscall202() -> gid_t {
+pub extern "C" fn ___syscall202(
+    _which: c_int,
+    _varargs: VarArgs,
+    instance: &mut Instance,
+) -> gid_t {
     debug!("emscripten::___syscall202 (getgid32)");
-    unsafe {
+    let gid = unsafe {
         // Maybe fix: Emscripten returns 0 always
         getgid()
-    }
+    };
+    trace_syscall!(instance, "getgid32", gid, "");
+    gid
 }
 
 // chown
@@ -864,9 +1474,19 @@ pub extern "C" fn ___syscall212(
     let owner: u32 = varargs.get(instance);
     let group: u32 = varargs.get(instance);
 
-    let pathname_addr = instance.memory_offset_addr(0, pathname as usize) as *const i8;
-
-    unsafe { chown(pathname_addr, owner, group) }
+    let path = guest_path(instance, pathname);
+    // The owners aren't emulated
+    let ret = syscall_ret(vfs(instance).stat(&path).map(|_| 0));
+    trace_syscall!(
+        instance,
+        "chown",
+        ret,
+        "{}, {}, {}",
+        guest_str(instance, pathname),
+        owner,
+        group
+    );
+    ret
 }
 
 // fcntl64
@@ -877,12 +1497,15 @@ pub extern "C" fn ___syscall221(
 ) -> c_int {
     debug!("emscripten::___syscall221 (fcntl64) {}", which);
     // fcntl64
-    let _fd: i32 = varargs.get(instance);
+    let fd: i32 = varargs.get(instance);
     let cmd: u32 = varargs.get(instance);
-    match cmd {
+    let ret = match cmd {
+        _ if vfs(instance).fstat(fd).is_err() => -errno::EBADF,
         2 => 0,
         _ => -1,
-    }
+    };
+    trace_syscall!(instance, "fcntl64", ret, "{}, {}", fd, cmd);
+    ret
 }
 
 /// dup3
@@ -897,29 +1520,18 @@ pub extern "C" fn ___syscall330(
     let newfd: c_int = varargs.get(instance);
     let flags: c_int = varargs.get(instance);
 
-    if oldfd == newfd {
-        return EINVAL;
-    }
-
-    let res = unsafe { dup2(oldfd, newfd) };
-
-    // Set flags on newfd (https://www.gnu.org/software/libc/manual/html_node/Descriptor-Flags.html)
-    let mut old_flags = unsafe { fcntl(newfd, F_GETFD, 0) };
-
-    if old_flags > 0 {
-        old_flags |= flags;
-    } else if old_flags == 0 {
-        old_flags &= !flags;
-    }
-
-    unsafe {
-        fcntl(newfd, F_SETFD, old_flags);
-    }
+    // The only flag is O_CLOEXEC, which doesn't matter without exec
+    let res = if oldfd == newfd {
+        -errno::EINVAL
+    } else {
+        syscall_ret(vfs(instance).dup2(oldfd, newfd))
+    };
 
     debug!(
         "=> oldfd: {}, newfd: {}, flags: {} = pid: {}",
         oldfd, newfd, flags, res
     );
+    trace_syscall!(instance, "dup3", res, "{}, {}, {}", oldfd, newfd, flags);
     res
 }
 
@@ -931,9 +1543,9 @@ pub extern "C" fn ___syscall340(
 ) -> c_int {
     debug!("emscripten::___syscall340 (prlimit64), {}", which);
     // NOTE: Doesn't really matter. Wasm modules cannot exceed WASM_PAGE_SIZE anyway.
-    let _pid: i32 = varargs.get(instance);
-    let _resource: i32 = varargs.get(instance);
-    let _new_limit: u32 = varargs.get(instance);
+    let pid: i32 = varargs.get(instance);
+    let resource: i32 = varargs.get(instance);
+    let new_limit: u32 = varargs.get(instance);
     let old_limit: u32 = varargs.get(instance);
 
     if old_limit != 0 {
@@ -947,5 +1559,15 @@ pub extern "C" fn ___syscall340(
         LittleEndian::write_i32(&mut buf[12..], -1); // RLIM_INFINITY
     }
 
+    trace_syscall!(
+        instance,
+        "prlimit64",
+        0,
+        "{}, {}, {:#x}, {:#x}",
+        pid,
+        resource,
+        new_limit,
+        old_limit
+    );
     0
 }
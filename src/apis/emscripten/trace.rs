@@ -0,0 +1,249 @@
+//! strace-like tracing of the calls into the emscripten host functions
+//! (`wasmer run --trace-syscalls`).
+//!
+//! Each traced call is logged as a single line once it returns:
+//! `open("/etc/hosts", O_RDONLY, 0) = 3`, followed by the errno name
+//! and its description when it failed: `= -1 ENOENT (No such file or directory)`.
+//! Calls that don't return (like `exit`) are logged before running: `exit(0) = ?`.
+use std::io::Write;
+
+use crate::webassembly::Instance;
+
+/// Maximum number of bytes of a guest string or buffer shown in a trace line.
+const MAX_STRING_LEN: usize = 32;
+
+pub struct SyscallTracer {
+    output: Box<Write>,
+}
+
+impl SyscallTracer {
+    pub fn new(output: Box<Write>) -> Self {
+        SyscallTracer { output }
+    }
+
+    /// Logs a call that returned `ret` (`None` when it doesn't return),
+    /// failing with the guest `errno` when it isn't 0.
+    pub fn trace(&mut self, name: &str, args: &str, ret: Option<i64>, errno: i32) {
+        let result = match ret {
+            Some(ret) if errno != 0 => {
+                format!("{} {} ({})", ret, errno_name(errno), ::errno::Errno(errno))
+            }
+            Some(ret) => ret.to_string(),
+            None => "?".to_string(),
+        };
+        // A failure to trace shouldn't make the guest fail
+        writeln!(self.output, "{}({}) = {}", name, args, result).ok();
+        self.output.flush().ok();
+    }
+}
+
+/// The result of a syscall as seen from the guest, with its errno: the
+/// syscalls return the negated errno, which the libc turns into -1.
+pub fn syscall_result(ret: i64) -> (i64, i32) {
+    if ret < 0 && ret > -4096 {
+        (-1, -ret as i32)
+    } else {
+        (ret, 0)
+    }
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::from("\"");
+    for &byte in bytes.iter().take(MAX_STRING_LEN) {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\r' => escaped.push_str("\\r"),
+            b'\t' => escaped.push_str("\\t"),
+            0x20..=0x7e => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped.push('"');
+    if bytes.len() > MAX_STRING_LEN {
+        escaped.push_str("...");
+    }
+    escaped
+}
+
+/// The guest bytes at `ptr` that are shown in a trace line: at most `len`
+/// and `MAX_STRING_LEN + 1` (to tell if it was truncated), and never past the
+/// end of the memory. `None` when `ptr` is outside of the memory.
+fn guest_bytes(memory: &[u8], ptr: u32, len: usize) -> Option<&[u8]> {
+    let ptr = ptr as usize;
+    if ptr >= memory.len() {
+        return None;
+    }
+    let len = len.min(MAX_STRING_LEN + 1).min(memory.len() - ptr);
+    Some(&memory[ptr..ptr + len])
+}
+
+/// Formats the NUL-terminated guest string at `ptr`.
+pub fn guest_str(instance: &Instance, ptr: u32) -> String {
+    if ptr == 0 {
+        return "NULL".to_string();
+    }
+    match guest_bytes(&instance.memories[0], ptr, MAX_STRING_LEN + 1) {
+        Some(bytes) => {
+            let len = bytes
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(bytes.len());
+            escape(&bytes[..len])
+        }
+        None => format!("{:#x}", ptr),
+    }
+}
+
+/// Formats the guest buffer of `len` bytes at `ptr`.
+pub fn guest_buffer(instance: &Instance, ptr: u32, len: usize) -> String {
+    if ptr == 0 {
+        return "NULL".to_string();
+    }
+    match guest_bytes(&instance.memories[0], ptr, len) {
+        Some(bytes) => escape(bytes),
+        None => format!("{:#x}", ptr),
+    }
+}
+
+/// Formats the flags of `open`, using the values of the guest (musl) headers.
+pub fn open_flags(flags: i32) -> String {
+    const FLAGS: &[(i32, &str)] = &[
+        (0o100, "O_CREAT"),
+        (0o200, "O_EXCL"),
+        (0o400, "O_NOCTTY"),
+        (0o1000, "O_TRUNC"),
+        (0o2000, "O_APPEND"),
+        (0o4000, "O_NONBLOCK"),
+        (0o200_000, "O_DIRECTORY"),
+        (0o400_000, "O_NOFOLLOW"),
+        (0o2_000_000, "O_CLOEXEC"),
+    ];
+    let mut names = vec![match flags & 0o3 {
+        0 => "O_RDONLY",
+        1 => "O_WRONLY",
+        _ => "O_RDWR",
+    }
+    .to_string()];
+    let mut remaining = flags & !0o3;
+    for &(flag, name) in FLAGS {
+        if remaining & flag != 0 {
+            names.push(name.to_string());
+            remaining &= !flag;
+        }
+    }
+    if remaining != 0 {
+        names.push(format!("{:#o}", remaining));
+    }
+    names.join("|")
+}
+
+/// The name of a `socketcall` subcall.
+pub fn socketcall_name(call: i32) -> &'static str {
+    match call {
+        1 => "socket",
+        2 => "bind",
+        3 => "connect",
+        4 => "listen",
+        5 => "accept",
+        6 => "getsockname",
+        7 => "getpeername",
+        8 => "socketpair",
+        9 => "send",
+        10 => "recv",
+        11 => "sendto",
+        12 => "recvfrom",
+        13 => "shutdown",
+        14 => "setsockopt",
+        15 => "getsockopt",
+        16 => "sendmsg",
+        17 => "recvmsg",
+        _ => "socketcall",
+    }
+}
+
+/// The symbolic name of a (host) errno value.
+fn errno_name(errno: i32) -> String {
+    let name = match errno {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::ESRCH => "ESRCH",
+        libc::EINTR => "EINTR",
+        libc::EIO => "EIO",
+        libc::ENXIO => "ENXIO",
+        libc::E2BIG => "E2BIG",
+        libc::ENOEXEC => "ENOEXEC",
+        libc::EBADF => "EBADF",
+        libc::ECHILD => "ECHILD",
+        libc::EAGAIN => "EAGAIN",
+        libc::ENOMEM => "ENOMEM",
+        libc::EACCES => "EACCES",
+        libc::EFAULT => "EFAULT",
+        libc::EBUSY => "EBUSY",
+        libc::EEXIST => "EEXIST",
+        libc::EXDEV => "EXDEV",
+        libc::ENODEV => "ENODEV",
+        libc::ENOTDIR => "ENOTDIR",
+        libc::EISDIR => "EISDIR",
+        libc::EINVAL => "EINVAL",
+        libc::ENFILE => "ENFILE",
+        libc::EMFILE => "EMFILE",
+        libc::ENOTTY => "ENOTTY",
+        libc::EFBIG => "EFBIG",
+        libc::ENOSPC => "ENOSPC",
+        libc::ESPIPE => "ESPIPE",
+        libc::EROFS => "EROFS",
+        libc::EMLINK => "EMLINK",
+        libc::EPIPE => "EPIPE",
+        libc::ERANGE => "ERANGE",
+        libc::ENAMETOOLONG => "ENAMETOOLONG",
+        libc::ENOSYS => "ENOSYS",
+        libc::ENOTEMPTY => "ENOTEMPTY",
+        libc::ELOOP => "ELOOP",
+        libc::ENOTSOCK => "ENOTSOCK",
+        libc::EADDRINUSE => "EADDRINUSE",
+        libc::ECONNREFUSED => "ECONNREFUSED",
+        libc::ECONNRESET => "ECONNRESET",
+        libc::ENOTCONN => "ENOTCONN",
+        libc::ETIMEDOUT => "ETIMEDOUT",
+        _ => return format!("E{}", errno),
+    };
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, guest_bytes, open_flags, syscall_result};
+
+    #[test]
+    fn test_trace_formatting() {
+        assert_eq!(open_flags(0), "O_RDONLY");
+        assert_eq!(open_flags(0o1101), "O_WRONLY|O_CREAT|O_TRUNC");
+        assert_eq!(escape(b"a\"b\n\x01"), "\"a\\\"b\\n\\x01\"");
+    }
+
+    #[test]
+    fn test_guest_bytes_are_clamped() {
+        let memory = [b'a'; 64];
+        assert_eq!(guest_bytes(&memory, 8, 4), Some(&memory[8..12]));
+        // A huge length is cut after the shown bytes
+        assert_eq!(
+            guest_bytes(&memory, 8, usize::max_value()).unwrap().len(),
+            33
+        );
+        // and at the end of the memory
+        assert_eq!(guest_bytes(&memory, 60, 32), Some(&memory[60..]));
+        assert_eq!(guest_bytes(&memory, 64, 1), None);
+    }
+
+    #[test]
+    fn test_syscall_result() {
+        assert_eq!(syscall_result(3), (3, 0));
+        // -1 is EPERM, like any other negated errno
+        assert_eq!(syscall_result(-1), (-1, 1));
+        assert_eq!(syscall_result(-2), (-1, 2));
+        // Not an errno, like a pointer past 2GB returned by mmap
+        assert_eq!(syscall_result(-0x1000_0000), (-0x1000_0000, 0));
+    }
+}
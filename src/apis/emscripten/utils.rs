@@ -1,7 +1,7 @@
+use super::vfs::Stat;
 use crate::webassembly::module::Module;
 use crate::webassembly::Instance;
 use byteorder::{ByteOrder, LittleEndian};
-use libc::stat;
 use std::ffi::CStr;
 use std::mem::size_of;
 use std::os::raw::c_char;
@@ -112,31 +112,24 @@ pub struct GuestStat {
     st_ino: u64,
 }
 
-pub unsafe fn copy_stat_into_wasm(instance: &mut Instance, buf: u32, stat: &stat) {
+pub unsafe fn copy_stat_into_wasm(instance: &mut Instance, buf: u32, stat: &Stat) {
     let stat_ptr = instance.memory_offset_addr(0, buf as _) as *mut GuestStat;
-    (*stat_ptr).st_dev = stat.st_dev as _;
+    (*stat_ptr).st_dev = stat.dev as _;
     (*stat_ptr).__st_dev_padding = 0;
-    (*stat_ptr).__st_ino_truncated = stat.st_ino as _;
-    (*stat_ptr).st_mode = stat.st_mode as _;
-    (*stat_ptr).st_nlink = stat.st_nlink as _;
-    (*stat_ptr).st_uid = stat.st_uid as _;
-    (*stat_ptr).st_gid = stat.st_gid as _;
-    (*stat_ptr).st_rdev = stat.st_rdev as _;
+    (*stat_ptr).__st_ino_truncated = stat.ino as _;
+    (*stat_ptr).st_mode = stat.mode;
+    (*stat_ptr).st_nlink = stat.nlink;
+    (*stat_ptr).st_uid = stat.uid;
+    (*stat_ptr).st_gid = stat.gid;
+    (*stat_ptr).st_rdev = stat.rdev as _;
     (*stat_ptr).__st_rdev_padding = 0;
-    (*stat_ptr).st_size = stat.st_size as _;
+    (*stat_ptr).st_size = stat.size as _;
     (*stat_ptr).st_blksize = 4096;
-    #[cfg(not(target_os = "windows"))]
-    {
-        (*stat_ptr).st_blocks = stat.st_blocks as _;
-    }
-    #[cfg(target_os = "windows")]
-    {
-        (*stat_ptr).st_blocks = 0;
-    }
-    (*stat_ptr).st_atime = stat.st_atime as _;
-    (*stat_ptr).st_mtime = stat.st_mtime as _;
-    (*stat_ptr).st_ctime = stat.st_ctime as _;
-    (*stat_ptr).st_ino = stat.st_ino as _;
+    (*stat_ptr).st_blocks = stat.blocks as _;
+    (*stat_ptr).st_atime = stat.atime;
+    (*stat_ptr).st_mtime = stat.mtime;
+    (*stat_ptr).st_ctime = stat.ctime;
+    (*stat_ptr).st_ino = stat.ino;
 }
 
 #[cfg(test)]
@@ -0,0 +1,661 @@
+//! The virtual filesystem of the emscripten guests, replacing the `FS` of the
+//! emscripten JS runtime.
+//!
+//! The guest only sees the filesystems mounted in its `Vfs` (by default an
+//! empty `MemFileSystem`, like the `MEMFS` of emscripten), and only the file
+//! descriptors in its table: the file syscalls only reach the host files of
+//! the directories mounted with a `HostFileSystem`.
+//! Host descriptors (the sockets, and the standard streams unless the
+//! embedder replaces them) are in the same table, so a guest fd is valid for
+//! both kinds.
+//!
+//! The errors are the errno values of the guest (the Linux ones), which the
+//! syscalls return negated.
+use libc::c_int;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, SeekFrom, Write};
+use std::rc::Rc;
+
+use super::memfs::MemFileSystem;
+
+/// The errno values of the guest (musl).
+pub mod errno {
+    pub const EPERM: i32 = 1;
+    pub const ENOENT: i32 = 2;
+    pub const ESRCH: i32 = 3;
+    pub const EINTR: i32 = 4;
+    pub const EIO: i32 = 5;
+    pub const EBADF: i32 = 9;
+    pub const ECHILD: i32 = 10;
+    pub const EAGAIN: i32 = 11;
+    pub const ENOMEM: i32 = 12;
+    pub const EACCES: i32 = 13;
+    pub const EFAULT: i32 = 14;
+    pub const EBUSY: i32 = 16;
+    pub const EEXIST: i32 = 17;
+    pub const EXDEV: i32 = 18;
+    pub const ENOTDIR: i32 = 20;
+    pub const EISDIR: i32 = 21;
+    pub const EINVAL: i32 = 22;
+    pub const EMFILE: i32 = 24;
+    pub const ENOTTY: i32 = 25;
+    pub const EFBIG: i32 = 27;
+    pub const ESPIPE: i32 = 29;
+    pub const EROFS: i32 = 30;
+    pub const EPIPE: i32 = 32;
+    pub const ERANGE: i32 = 34;
+    pub const ENOTEMPTY: i32 = 39;
+    pub const EOVERFLOW: i32 = 75;
+    pub const ENOTSOCK: i32 = 88;
+    pub const EADDRINUSE: i32 = 98;
+    pub const EADDRNOTAVAIL: i32 = 99;
+    pub const ECONNRESET: i32 = 104;
+    pub const ENOTCONN: i32 = 107;
+    pub const ETIMEDOUT: i32 = 110;
+    pub const ECONNREFUSED: i32 = 111;
+    pub const EINPROGRESS: i32 = 115;
+}
+
+pub type VfsResult<T> = Result<T, i32>;
+
+/// The file type bits of `Stat::mode`.
+pub const S_IFMT: u32 = 0o170_000;
+pub const S_IFDIR: u32 = 0o040_000;
+pub const S_IFCHR: u32 = 0o020_000;
+pub const S_IFREG: u32 = 0o100_000;
+
+/// The flags of `open`, with the values of the guest (musl) headers.
+pub const O_ACCMODE: i32 = 0o3;
+pub const O_WRONLY: i32 = 0o1;
+pub const O_RDWR: i32 = 0o2;
+pub const O_CREAT: i32 = 0o100;
+pub const O_EXCL: i32 = 0o200;
+pub const O_TRUNC: i32 = 0o1000;
+pub const O_APPEND: i32 = 0o2000;
+pub const O_DIRECTORY: i32 = 0o200_000;
+
+/// Maximum number of open file descriptors.
+const MAX_FDS: i32 = 1024;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stat {
+    pub dev: u64,
+    pub ino: u64,
+    /// The file type and permission bits.
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub rdev: u64,
+    pub size: u64,
+    pub blocks: u64,
+    /// The times, in seconds since the epoch.
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u64,
+    pub is_dir: bool,
+}
+
+/// The flags of `open`, decoded.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    pub exclusive: bool,
+    pub truncate: bool,
+    pub append: bool,
+    pub directory: bool,
+}
+
+impl OpenFlags {
+    pub fn from_guest(flags: i32) -> Self {
+        let access = flags & O_ACCMODE;
+        OpenFlags {
+            read: access != O_WRONLY,
+            write: access == O_WRONLY || access == O_RDWR,
+            create: flags & O_CREAT != 0,
+            exclusive: flags & O_EXCL != 0,
+            truncate: flags & O_TRUNC != 0,
+            append: flags & O_APPEND != 0,
+            directory: flags & O_DIRECTORY != 0,
+        }
+    }
+}
+
+/// A filesystem that can be mounted in a `Vfs`. The paths are absolute and
+/// normalized, relative to the mount point: `/` is the root of the filesystem.
+pub trait FileSystem: fmt::Debug {
+    fn open(&mut self, path: &str, flags: OpenFlags, mode: u32) -> VfsResult<Box<FileHandle>>;
+    fn stat(&mut self, path: &str) -> VfsResult<Stat>;
+    fn mkdir(&mut self, path: &str, mode: u32) -> VfsResult<()>;
+    fn rmdir(&mut self, path: &str) -> VfsResult<()>;
+    fn unlink(&mut self, path: &str) -> VfsResult<()>;
+    /// Renames within the filesystem (renaming across mounts fails with `EXDEV`).
+    fn rename(&mut self, from: &str, to: &str) -> VfsResult<()>;
+    fn read_dir(&mut self, path: &str) -> VfsResult<Vec<DirEntry>>;
+}
+
+/// An open file (or directory, or host descriptor).
+pub trait FileHandle: fmt::Debug {
+    fn read(&mut self, buf: &mut [u8]) -> VfsResult<usize>;
+    fn write(&mut self, buf: &[u8]) -> VfsResult<usize>;
+    /// Moves the position, returning the new one.
+    fn seek(&mut self, pos: SeekFrom) -> VfsResult<u64>;
+    fn stat(&self) -> VfsResult<Stat>;
+    /// The host descriptor, for the handles that wrap one (like the sockets).
+    fn host_fd(&self) -> Option<c_int> {
+        None
+    }
+    /// Whether it is a terminal, for the handles that don't wrap a host descriptor.
+    fn is_tty(&self) -> bool {
+        false
+    }
+}
+
+/// A descriptor of the host, like a socket or one of the standard streams.
+#[derive(Debug)]
+pub struct HostDescriptor {
+    fd: c_int,
+    /// Shall we close it when the guest closes it?
+    owned: bool,
+}
+
+impl HostDescriptor {
+    /// Takes ownership of the host descriptor: it is closed with the handle.
+    pub fn new(fd: c_int) -> Self {
+        HostDescriptor { fd, owned: true }
+    }
+
+    /// A standard stream of the host, left open.
+    pub fn stdio(fd: c_int) -> Self {
+        HostDescriptor { fd, owned: false }
+    }
+}
+
+/// The guest errno value of a host one.
+fn guest_errno(host_errno: i32) -> i32 {
+    // The host and guest values are the same on Linux, not on macOS
+    match host_errno {
+        libc::EPERM => errno::EPERM,
+        libc::ENOENT => errno::ENOENT,
+        libc::ESRCH => errno::ESRCH,
+        libc::EINTR => errno::EINTR,
+        libc::EBADF => errno::EBADF,
+        libc::ECHILD => errno::ECHILD,
+        libc::EAGAIN => errno::EAGAIN,
+        libc::ENOMEM => errno::ENOMEM,
+        libc::EACCES => errno::EACCES,
+        libc::EBUSY => errno::EBUSY,
+        libc::EEXIST => errno::EEXIST,
+        libc::EXDEV => errno::EXDEV,
+        libc::ENOTDIR => errno::ENOTDIR,
+        libc::EISDIR => errno::EISDIR,
+        libc::EINVAL => errno::EINVAL,
+        libc::EMFILE => errno::EMFILE,
+        libc::ENOTTY => errno::ENOTTY,
+        libc::ESPIPE => errno::ESPIPE,
+        libc::EROFS => errno::EROFS,
+        libc::EPIPE => errno::EPIPE,
+        libc::ERANGE => errno::ERANGE,
+        libc::ENOTEMPTY => errno::ENOTEMPTY,
+        libc::ENOTSOCK => errno::ENOTSOCK,
+        libc::EADDRINUSE => errno::EADDRINUSE,
+        libc::EADDRNOTAVAIL => errno::EADDRNOTAVAIL,
+        libc::ECONNRESET => errno::ECONNRESET,
+        libc::ENOTCONN => errno::ENOTCONN,
+        libc::ETIMEDOUT => errno::ETIMEDOUT,
+        libc::ECONNREFUSED => errno::ECONNREFUSED,
+        libc::EINPROGRESS => errno::EINPROGRESS,
+        _ => errno::EIO,
+    }
+}
+
+/// The guest errno value of the last error of the host.
+pub fn last_host_errno() -> i32 {
+    guest_errno(::errno::errno().0)
+}
+
+/// The guest errno value of an I/O error.
+pub fn io_errno(err: io::Error) -> i32 {
+    err.raw_os_error().map(guest_errno).unwrap_or(errno::EIO)
+}
+
+impl FileHandle for HostDescriptor {
+    fn read(&mut self, buf: &mut [u8]) -> VfsResult<usize> {
+        let ret = unsafe { libc::read(self.fd, buf.as_mut_ptr() as _, buf.len()) };
+        if ret < 0 {
+            Err(last_host_errno())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> VfsResult<usize> {
+        let ret = unsafe { libc::write(self.fd, buf.as_ptr() as _, buf.len()) };
+        if ret < 0 {
+            Err(last_host_errno())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    fn seek(&mut self, _pos: SeekFrom) -> VfsResult<u64> {
+        Err(errno::ESPIPE)
+    }
+
+    fn stat(&self) -> VfsResult<Stat> {
+        stream_stat()
+    }
+
+    fn host_fd(&self) -> Option<c_int> {
+        Some(self.fd)
+    }
+}
+
+impl Drop for HostDescriptor {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+fn stream_stat() -> VfsResult<Stat> {
+    Ok(Stat {
+        mode: S_IFCHR | 0o666,
+        nlink: 1,
+        ..Default::default()
+    })
+}
+
+/// A standard input of the guest, read from the embedder.
+pub struct ReadStream(Box<Read>);
+
+impl ReadStream {
+    pub fn new(reader: Box<Read>) -> Self {
+        ReadStream(reader)
+    }
+}
+
+impl fmt::Debug for ReadStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ReadStream")
+    }
+}
+
+impl FileHandle for ReadStream {
+    fn read(&mut self, buf: &mut [u8]) -> VfsResult<usize> {
+        self.0.read(buf).map_err(io_errno)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> VfsResult<usize> {
+        Err(errno::EBADF)
+    }
+
+    fn seek(&mut self, _pos: SeekFrom) -> VfsResult<u64> {
+        Err(errno::ESPIPE)
+    }
+
+    fn stat(&self) -> VfsResult<Stat> {
+        stream_stat()
+    }
+
+    fn is_tty(&self) -> bool {
+        true
+    }
+}
+
+/// A standard output (or error) of the guest, written to the embedder.
+/// Every write is flushed, like on a terminal.
+pub struct WriteStream(Box<Write>);
+
+impl WriteStream {
+    pub fn new(writer: Box<Write>) -> Self {
+        WriteStream(writer)
+    }
+}
+
+impl fmt::Debug for WriteStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("WriteStream")
+    }
+}
+
+impl FileHandle for WriteStream {
+    fn read(&mut self, _buf: &mut [u8]) -> VfsResult<usize> {
+        Err(errno::EBADF)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> VfsResult<usize> {
+        self.0.write_all(buf).map_err(io_errno)?;
+        self.0.flush().map_err(io_errno)?;
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, _pos: SeekFrom) -> VfsResult<u64> {
+        Err(errno::ESPIPE)
+    }
+
+    fn stat(&self) -> VfsResult<Stat> {
+        stream_stat()
+    }
+
+    fn is_tty(&self) -> bool {
+        true
+    }
+}
+
+/// An open file description, shared by the descriptors duplicated with `dup`.
+type OpenFile = Rc<RefCell<Box<FileHandle>>>;
+
+#[derive(Debug)]
+pub struct Vfs {
+    /// The mounted filesystems, by mount point.
+    mounts: BTreeMap<String, Box<FileSystem>>,
+    fds: BTreeMap<i32, OpenFile>,
+    cwd: String,
+}
+
+impl Vfs {
+    /// A `Vfs` with `root` mounted at `/`, and the standard streams of the host.
+    pub fn new(root: Box<FileSystem>) -> Self {
+        let mut vfs = Vfs {
+            mounts: BTreeMap::new(),
+            fds: BTreeMap::new(),
+            cwd: "/".to_string(),
+        };
+        vfs.mount("/", root);
+        for fd in 0..3 {
+            vfs.insert(Box::new(HostDescriptor::stdio(fd))).unwrap();
+        }
+        vfs
+    }
+
+    /// Mounts a filesystem at `path` (which doesn't have to exist in the parent filesystem).
+    pub fn mount(&mut self, path: &str, fs: Box<FileSystem>) {
+        let path = normalize("/", path).unwrap_or_else(|_| "/".to_string());
+        self.mounts.insert(path, fs);
+    }
+
+    /// The absolute guest path of `path`, relative to the current directory.
+    pub fn absolute_path(&self, path: &str) -> VfsResult<String> {
+        normalize(&self.cwd, path)
+    }
+
+    /// The mount point of the filesystem of a path, and the path within it.
+    fn mount_point(&self, path: &str) -> VfsResult<(String, String)> {
+        let path = self.absolute_path(path)?;
+        let mount_point = self
+            .mounts
+            .keys()
+            .filter(|mount_point| is_within(&path, mount_point))
+            .max_by_key(|mount_point| mount_point.len())
+            .ok_or(errno::ENOENT)?;
+        let inner = match &path[mount_point.len()..] {
+            "" => "/".to_string(),
+            rest if rest.starts_with('/') => rest.to_string(),
+            rest => format!("/{}", rest),
+        };
+        Ok((mount_point.clone(), inner))
+    }
+
+    /// The filesystem of a path, and the path within it.
+    fn resolve(&mut self, path: &str) -> VfsResult<(&mut Box<FileSystem>, String)> {
+        let (mount_point, inner) = self.mount_point(path)?;
+        Ok((self.mounts.get_mut(&mount_point).unwrap(), inner))
+    }
+
+    /// Adds a handle to the table, with the lowest free descriptor.
+    pub fn insert(&mut self, handle: Box<FileHandle>) -> VfsResult<i32> {
+        let fd = (0..MAX_FDS)
+            .find(|fd| !self.fds.contains_key(fd))
+            .ok_or(errno::EMFILE)?;
+        self.fds.insert(fd, Rc::new(RefCell::new(handle)));
+        Ok(fd)
+    }
+
+    /// Installs a handle as the descriptor `fd`, closing the previous one
+    /// (to replace the standard streams).
+    pub fn set(&mut self, fd: i32, handle: Box<FileHandle>) {
+        self.fds.insert(fd, Rc::new(RefCell::new(handle)));
+    }
+
+    fn get(&self, fd: i32) -> VfsResult<&OpenFile> {
+        self.fds.get(&fd).ok_or(errno::EBADF)
+    }
+
+    /// The host descriptor behind a guest descriptor, if any.
+    pub fn host_fd(&self, fd: i32) -> Option<c_int> {
+        self.fds.get(&fd).and_then(|file| file.borrow().host_fd())
+    }
+
+    /// Whether a guest descriptor is one of the streams of the embedder, which
+    /// behave like terminals (for the guest not to buffer its output).
+    pub fn is_tty(&self, fd: i32) -> bool {
+        self.fds
+            .get(&fd)
+            .map_or(false, |file| file.borrow().is_tty())
+    }
+
+    pub fn open(&mut self, path: &str, flags: i32, mode: u32) -> VfsResult<i32> {
+        let handle = {
+            let (fs, path) = self.resolve(path)?;
+            fs.open(&path, OpenFlags::from_guest(flags), mode)?
+        };
+        self.insert(handle)
+    }
+
+    pub fn close(&mut self, fd: i32) -> VfsResult<()> {
+        self.fds.remove(&fd).map(|_| ()).ok_or(errno::EBADF)
+    }
+
+    pub fn dup(&mut self, fd: i32) -> VfsResult<i32> {
+        let file = self.get(fd)?.clone();
+        let new_fd = (0..MAX_FDS)
+            .find(|fd| !self.fds.contains_key(fd))
+            .ok_or(errno::EMFILE)?;
+        self.fds.insert(new_fd, file);
+        Ok(new_fd)
+    }
+
+    /// Makes `new_fd` a copy of `fd`, closing it first if needed.
+    pub fn dup2(&mut self, fd: i32, new_fd: i32) -> VfsResult<i32> {
+        let file = self.get(fd)?.clone();
+        if !(0..MAX_FDS).contains(&new_fd) {
+            return Err(errno::EBADF);
+        }
+        self.fds.insert(new_fd, file);
+        Ok(new_fd)
+    }
+
+    pub fn read(&mut self, fd: i32, buf: &mut [u8]) -> VfsResult<usize> {
+        self.get(fd)?.borrow_mut().read(buf)
+    }
+
+    pub fn write(&mut self, fd: i32, buf: &[u8]) -> VfsResult<usize> {
+        self.get(fd)?.borrow_mut().write(buf)
+    }
+
+    pub fn seek(&mut self, fd: i32, pos: SeekFrom) -> VfsResult<u64> {
+        self.get(fd)?.borrow_mut().seek(pos)
+    }
+
+    /// Reads at `offset`, without moving the position.
+    pub fn pread(&mut self, fd: i32, buf: &mut [u8], offset: u64) -> VfsResult<usize> {
+        let mut file = self.get(fd)?.borrow_mut();
+        let position = file.seek(SeekFrom::Current(0))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let ret = file.read(buf);
+        file.seek(SeekFrom::Start(position))?;
+        ret
+    }
+
+    /// Writes at `offset`, without moving the position.
+    pub fn pwrite(&mut self, fd: i32, buf: &[u8], offset: u64) -> VfsResult<usize> {
+        let mut file = self.get(fd)?.borrow_mut();
+        let position = file.seek(SeekFrom::Current(0))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let ret = file.write(buf);
+        file.seek(SeekFrom::Start(position))?;
+        ret
+    }
+
+    pub fn fstat(&self, fd: i32) -> VfsResult<Stat> {
+        self.get(fd)?.borrow().stat()
+    }
+
+    pub fn stat(&mut self, path: &str) -> VfsResult<Stat> {
+        let (fs, path) = self.resolve(path)?;
+        fs.stat(&path)
+    }
+
+    pub fn mkdir(&mut self, path: &str, mode: u32) -> VfsResult<()> {
+        let (fs, path) = self.resolve(path)?;
+        fs.mkdir(&path, mode)
+    }
+
+    pub fn rmdir(&mut self, path: &str) -> VfsResult<()> {
+        if self.mounts.contains_key(&self.absolute_path(path)?) {
+            return Err(errno::EBUSY);
+        }
+        let (fs, path) = self.resolve(path)?;
+        fs.rmdir(&path)
+    }
+
+    pub fn unlink(&mut self, path: &str) -> VfsResult<()> {
+        let (fs, path) = self.resolve(path)?;
+        fs.unlink(&path)
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> VfsResult<()> {
+        let (to_mount_point, to) = self.mount_point(to)?;
+        let (from_mount_point, from) = self.mount_point(from)?;
+        if from_mount_point != to_mount_point {
+            return Err(errno::EXDEV);
+        }
+        let fs = self.mounts.get_mut(&from_mount_point).unwrap();
+        fs.rename(&from, &to)
+    }
+
+    pub fn read_dir(&mut self, path: &str) -> VfsResult<Vec<DirEntry>> {
+        let (fs, path) = self.resolve(path)?;
+        fs.read_dir(&path)
+    }
+
+    pub fn chdir(&mut self, path: &str) -> VfsResult<()> {
+        if !self.stat(path)?.is_dir() {
+            return Err(errno::ENOTDIR);
+        }
+        self.cwd = self.absolute_path(path)?;
+        Ok(())
+    }
+
+    pub fn cwd(&self) -> &str {
+        &self.cwd
+    }
+}
+
+impl Default for Vfs {
+    /// An empty in-memory filesystem, with the directories created by emscripten.
+    fn default() -> Self {
+        let fs = MemFileSystem::new();
+        for dir in &["/tmp", "/home/web_user", "/dev"] {
+            fs.create_dir_all(dir).unwrap();
+        }
+        Vfs::new(Box::new(fs))
+    }
+}
+
+/// Whether `path` is `dir` or is inside it.
+fn is_within(path: &str, dir: &str) -> bool {
+    dir == "/" || path == dir || (path.starts_with(dir) && path[dir.len()..].starts_with('/'))
+}
+
+/// Resolves `path` relative to `cwd`, and removes the `.` and `..` components.
+pub fn normalize(cwd: &str, path: &str) -> VfsResult<String> {
+    if path.is_empty() {
+        return Err(errno::ENOENT);
+    }
+    let mut components: Vec<&str> = Vec::new();
+    let base = if path.starts_with('/') { "" } else { cwd };
+    for component in base.split('/').chain(path.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    Ok(format!("/{}", components.join("/")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{errno, normalize, Vfs, WriteStream, O_CREAT, O_RDWR, O_WRONLY};
+    use crate::apis::emscripten::MemFileSystem;
+    use std::io::{self, SeekFrom};
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/", "a/./b/../c/").unwrap(), "/a/c");
+        assert_eq!(normalize("/home", "../../etc").unwrap(), "/etc");
+        assert_eq!(normalize("/home", "/tmp//x").unwrap(), "/tmp/x");
+        assert_eq!(normalize("/", ""), Err(errno::ENOENT));
+    }
+
+    #[test]
+    fn test_vfs_files_and_mounts() {
+        let data = MemFileSystem::new();
+        data.write_file("/input.txt", b"hello").unwrap();
+        let mut vfs = Vfs::default();
+        vfs.mount("/data", Box::new(data.clone()));
+
+        vfs.chdir("/data").unwrap();
+        let fd = vfs.open("input.txt", O_RDWR, 0).unwrap();
+        assert_eq!(fd, 3);
+        let dup = vfs.dup(fd).unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(vfs.read(fd, &mut buf), Ok(3));
+        // The duplicated descriptor shares the position
+        assert_eq!(vfs.seek(dup, SeekFrom::Current(0)), Ok(3));
+        assert_eq!(vfs.write(dup, b"p!"), Ok(2));
+        vfs.close(fd).unwrap();
+        assert_eq!(vfs.close(fd), Err(errno::EBADF));
+
+        let out = vfs.open("/tmp/out.txt", O_WRONLY | O_CREAT, 0o644).unwrap();
+        vfs.write(out, b"result").unwrap();
+        assert_eq!(
+            vfs.rename("/tmp/out.txt", "/data/out.txt"),
+            Err(errno::EXDEV)
+        );
+        assert_eq!(vfs.stat("/tmp/out.txt").unwrap().size, 6);
+        assert_eq!(vfs.rmdir("/data"), Err(errno::EBUSY));
+        assert_eq!(data.read_file("/input.txt").unwrap(), b"help!");
+    }
+
+    #[test]
+    fn test_embedder_streams_are_terminals() {
+        let mut vfs = Vfs::default();
+        vfs.set(1, Box::new(WriteStream::new(Box::new(io::sink()))));
+        assert!(vfs.is_tty(1));
+        let fd = vfs.open("/tmp/out.txt", O_WRONLY | O_CREAT, 0o644).unwrap();
+        assert!(!vfs.is_tty(fd));
+        assert!(!vfs.is_tty(42));
+    }
+}
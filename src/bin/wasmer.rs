@@ -1,15 +1,18 @@
+#[macro_use]
+extern crate log;
 extern crate structopt;
 extern crate wasmer;
 
 use std::fs::File;
 use std::io;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::process::exit;
 
 use structopt::StructOpt;
 
-use wasmer::*;
+use wasmer::apis::emscripten::{HostFileSystem, Vfs};
+use wasmer::{apis, logger, update, webassembly};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "wasmer", about = "WASM execution runtime.")]
@@ -19,6 +22,14 @@ enum CLIOptions {
     #[structopt(name = "run")]
     Run(Run),
 
+    /// Validate a WebAssembly file, and report the location of the first error
+    #[structopt(name = "validate")]
+    Validate(Validate),
+
+    /// Print the structure of a WebAssembly file, or the IR and machine code of a function
+    #[structopt(name = "inspect")]
+    Inspect(Inspect),
+
     /// Update wasmer to the latest version
     #[structopt(name = "self-update")]
     SelfUpdate,
@@ -26,9 +37,81 @@ enum CLIOptions {
 
 #[derive(Debug, StructOpt)]
 struct Run {
+    /// Log the runtime diagnostics to stderr (same as -vv)
     #[structopt(short = "d", long = "debug")]
     debug: bool,
 
+    /// Log more runtime diagnostics to stderr (-v: info, -vv: debug, -vvv: trace),
+    /// RUST_LOG can also be used, with the env_logger syntax
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: u64,
+
+    /// Register the compiled code with the GDB JIT interface
+    #[structopt(long = "gdb-jit")]
+    gdb_jit: bool,
+
+    /// Write the compiled functions to /tmp/perf-<pid>.map for perf
+    #[structopt(long = "perfmap")]
+    perf_map: bool,
+
+    /// Write the compiled functions to /tmp/jit-<pid>.dump for `perf inject --jit`
+    #[structopt(long = "jitdump")]
+    jitdump: bool,
+
+    /// Log the calls into the emscripten host functions (syscalls, getenv...), like strace
+    #[structopt(long = "trace-syscalls")]
+    trace_syscalls: bool,
+
+    /// Write the syscalls trace to this file instead of stderr (implies --trace-syscalls)
+    #[structopt(long = "trace-file", parse(from_os_str))]
+    trace_file: Option<PathBuf>,
+
+    /// Sample the guest call stacks and write them to this file, as folded stacks
+    #[structopt(long = "profile", parse(from_os_str))]
+    profile: Option<PathBuf>,
+
+    /// Count the executions of each block of the guest code and write them to this file,
+    /// as JSON (and to the same path with the .info extension as lcov, if the module has
+    /// names or DWARF line info)
+    #[structopt(long = "coverage", parse(from_os_str))]
+    coverage: Option<PathBuf>,
+
+    /// Print the compilation times, code sizes and relocations, and the slowest and
+    /// largest functions
+    #[structopt(long = "stats")]
+    stats: bool,
+
+    /// Expose a host directory to the guest, at the same path (the guest can't access the
+    /// other host files). A relative path is relative to the current directory on the host,
+    /// and to the root (the initial working directory) in the guest. It can't contain `..`,
+    /// nor be the guest root, use --mapdir for these
+    #[structopt(long = "dir", raw(number_of_values = "1"), parse(from_os_str))]
+    dirs: Vec<PathBuf>,
+
+    /// Expose a host directory to the guest at another path, given as GUEST_DIR:HOST_DIR
+    #[structopt(
+        long = "mapdir",
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_mapdir")
+    )]
+    mapdirs: Vec<(String, PathBuf)>,
+
+    /// Set an environment variable of the guest, given as KEY=VALUE (the host environment
+    /// isn't exposed)
+    #[structopt(
+        long = "env",
+        raw(number_of_values = "1"),
+        parse(try_from_str = "parse_env")
+    )]
+    env: Vec<(String, String)>,
+
+    /// The size of the stack of the guest, in bytes (the one it was built with by default)
+    #[structopt(long = "stack-size")]
+    stack_size: Option<u32>,
+
+    #[structopt(flatten)]
+    features: FeatureOptions,
+
     /// Input file
     #[structopt(parse(from_os_str))]
     path: PathBuf,
@@ -38,6 +121,121 @@ struct Run {
     args: Vec<String>,
 }
 
+// The WebAssembly proposals that can be enabled. Only the ones we can also
+// translate are exposed, the others would always be rejected
+#[derive(Debug, StructOpt)]
+struct FeatureOptions {
+    /// Allow importing and exporting mutable globals
+    #[structopt(long = "enable-mutable-global-imports")]
+    enable_mutable_global_imports: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct Validate {
+    #[structopt(flatten)]
+    features: FeatureOptions,
+
+    /// Input file
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct Inspect {
+    /// Print the Cranelift IR of a function (given by index or name)
+    #[structopt(long = "ir")]
+    ir: Option<String>,
+
+    /// Print the machine code generated for a function (given by index or name).
+    /// It is not disassembled: each instruction of its final Cranelift IR is
+    /// listed with its encoding and bytes
+    #[structopt(long = "asm")]
+    asm: Option<String>,
+
+    #[structopt(flatten)]
+    features: FeatureOptions,
+
+    /// Input file
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+}
+
+/// Parse a GUEST_DIR:HOST_DIR directory mapping
+fn parse_mapdir(mapdir: &str) -> Result<(String, PathBuf), String> {
+    match mapdir.find(':') {
+        Some(index) if index > 0 && index + 1 < mapdir.len() => Ok((
+            mapdir[..index].to_string(),
+            PathBuf::from(&mapdir[index + 1..]),
+        )),
+        _ => Err(format!(
+            "Expected a directory mapping as GUEST_DIR:HOST_DIR, got {}",
+            mapdir
+        )),
+    }
+}
+
+/// Parse a KEY=VALUE environment variable
+fn parse_env(var: &str) -> Result<(String, String), String> {
+    match var.find('=') {
+        Some(index) if index > 0 => Ok((var[..index].to_string(), var[index + 1..].to_string())),
+        _ => Err(format!(
+            "Expected an environment variable as KEY=VALUE, got {}",
+            var
+        )),
+    }
+}
+
+/// Build the filesystem of the guest, with the host directories given on the command line
+fn get_vfs(options: &Run) -> Result<Option<Vfs>, String> {
+    if options.dirs.is_empty() && options.mapdirs.is_empty() {
+        return Ok(None);
+    }
+    let dirs = options
+        .dirs
+        .iter()
+        .map(|dir| (dir.to_string_lossy().into_owned(), dir.clone()));
+    let mut vfs = Vfs::default();
+    for (guest_dir, host_dir) in dirs.chain(options.mapdirs.iter().cloned()) {
+        let guest_dir = guest_mount_point(Path::new(&guest_dir))?;
+        let fs = HostFileSystem::new(&host_dir).map_err(|err| {
+            format!(
+                "Can't expose the directory {}: {}",
+                host_dir.as_os_str().to_string_lossy(),
+                err
+            )
+        })?;
+        vfs.mount(&guest_dir, Box::new(fs));
+    }
+    Ok(Some(vfs))
+}
+
+/// The absolute guest path where a directory is mounted, a relative one being resolved
+/// against the guest root. Mounting over the root would hide the whole in-memory
+/// filesystem (`/tmp`, `/dev`...), and `..` would resolve to a different directory in
+/// the guest and on the host, so they are rejected.
+fn guest_mount_point(guest_dir: &Path) -> Result<String, String> {
+    let mut components = Vec::new();
+    for component in guest_dir.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_string_lossy().into_owned()),
+            Component::RootDir | Component::CurDir => {}
+            _ => {
+                return Err(format!(
+                    "Can't mount a directory at {}: the path can't contain `..`",
+                    guest_dir.display()
+                ));
+            }
+        }
+    }
+    if components.is_empty() {
+        return Err(format!(
+            "Can't mount a directory at {}: it's the guest root",
+            guest_dir.display()
+        ));
+    }
+    Ok(format!("/{}", components.join("/")))
+}
+
 /// Read the contents of a file
 fn read_file_contents(path: &PathBuf) -> Result<Vec<u8>, io::Error> {
     let mut buffer: Vec<u8> = Vec::new();
@@ -48,10 +246,16 @@ fn read_file_contents(path: &PathBuf) -> Result<Vec<u8>, io::Error> {
     Ok(buffer)
 }
 
-/// Execute a WASM/WAT file
-fn execute_wasm(options: &Run) -> Result<(), String> {
-    let wasm_path = &options.path;
+/// Collect the WebAssembly proposals enabled from the command line
+fn get_features(options: &FeatureOptions) -> webassembly::Features {
+    webassembly::Features {
+        mutable_global_imports: options.enable_mutable_global_imports,
+        ..webassembly::Features::default()
+    }
+}
 
+/// Read a WASM/WAT file, as a wasm binary
+fn read_wasm_binary(wasm_path: &PathBuf) -> Result<Vec<u8>, String> {
     let mut wasm_binary: Vec<u8> = read_file_contents(wasm_path).map_err(|err| {
         format!(
             "Can't read the file {}: {}",
@@ -64,11 +268,18 @@ fn execute_wasm(options: &Run) -> Result<(), String> {
         wasm_binary = wabt::wat2wasm(wasm_binary)
             .map_err(|err| format!("Can't convert from wast to wasm: {:?}", err))?;
     }
+    Ok(wasm_binary)
+}
+
+/// Execute a WASM/WAT file, returning its exit code
+fn execute_wasm(options: &Run) -> Result<i32, String> {
+    let wasm_binary = read_wasm_binary(&options.path)?;
 
     let isa = webassembly::get_isa();
+    let features = get_features(&options.features);
 
     debug!("webassembly - creating module");
-    let module = webassembly::compile(wasm_binary)
+    let module = webassembly::compile_with_features(wasm_binary, &features)
         .map_err(|err| format!("Can't create the WebAssembly module: {}", err))?;
 
     let abi = if apis::is_emscripten_module(&module) {
@@ -83,6 +294,18 @@ fn execute_wasm(options: &Run) -> Result<(), String> {
         webassembly::ImportObject::new()
     };
 
+    let trace_syscalls: Option<Box<io::Write>> = match options.trace_file {
+        Some(ref trace_path) => Some(Box::new(File::create(trace_path).map_err(|err| {
+            format!(
+                "Can't create the trace file {}: {}",
+                trace_path.as_os_str().to_string_lossy(),
+                err
+            )
+        })?)),
+        None if options.trace_syscalls => Some(Box::new(io::stderr())),
+        None => None,
+    };
+
     let instance_options = webassembly::InstanceOptions {
         mock_missing_imports: true,
         mock_missing_globals: true,
@@ -90,23 +313,90 @@ fn execute_wasm(options: &Run) -> Result<(), String> {
         abi: abi,
         show_progressbar: true,
         isa: isa,
+        features: features,
+        gdb_jit: options.gdb_jit,
+        perf_map: options.perf_map,
+        jitdump: options.jitdump,
+        trace_syscalls: trace_syscalls,
+        coverage: options.coverage.is_some(),
+        vfs: get_vfs(options)?,
+        env: options.env.clone(),
+        stdin: None,
+        stdout: None,
+        stderr: None,
+        stack_size: options.stack_size,
     };
 
     debug!("webassembly - creating instance");
     let mut instance = webassembly::Instance::new(&module, import_object, instance_options)
         .map_err(|err| format!("Can't instantiate the WebAssembly module: {}", err))?;
 
-    webassembly::start_instance(
+    if options.stats {
+        eprint!("{}", instance.compile_stats.report(&module, 10));
+    }
+
+    let profiler = match options.profile {
+        Some(_) => Some(webassembly::profiler::Profiler::start(
+            webassembly::profiler::DEFAULT_INTERVAL_US,
+        )?),
+        None => None,
+    };
+
+    let result = webassembly::start_instance(
         &module,
         &mut instance,
         options.path.to_str().unwrap(),
         options.args.iter().map(|arg| arg.as_str()).collect(),
-    )
+    );
+
+    if let (Some(profiler), Some(profile_path)) = (profiler, &options.profile) {
+        profiler
+            .finish(&module, &instance, profile_path)
+            .map_err(|err| {
+                format!(
+                    "Can't write the profile to {}: {}",
+                    profile_path.as_os_str().to_string_lossy(),
+                    err
+                )
+            })?;
+    }
+
+    if let (Some(coverage), Some(coverage_path)) = (&instance.coverage, &options.coverage) {
+        write_coverage(coverage, &module, &options.path, coverage_path)?;
+    }
+
+    result
+}
+
+/// Write the coverage as JSON, and as lcov if possible
+fn write_coverage(
+    coverage: &webassembly::coverage::Coverage,
+    module: &webassembly::Module,
+    module_path: &PathBuf,
+    coverage_path: &PathBuf,
+) -> Result<(), String> {
+    let error = |path: &PathBuf, err: io::Error| {
+        format!(
+            "Can't write the coverage to {}: {}",
+            path.as_os_str().to_string_lossy(),
+            err
+        )
+    };
+    coverage
+        .write_json(module, coverage_path)
+        .map_err(|err| error(coverage_path, err))?;
+    if webassembly::coverage::Coverage::has_lcov_info(module) {
+        let lcov_path = coverage_path.with_extension("info");
+        coverage
+            .write_lcov(module, module_path, &lcov_path)
+            .map_err(|err| error(&lcov_path, err))?;
+    }
+    Ok(())
 }
 
 fn run(options: Run) {
     match execute_wasm(&options) {
-        Ok(()) => {}
+        Ok(code) => exit(code),
         Err(message) => {
             // let name = options.path.as_os_str().to_string_lossy();
             println!("{}", message);
@@ -115,10 +405,104 @@ fn run(options: Run) {
     }
 }
 
+/// Validate a WASM/WAT file
+fn validate_wasm(options: &Validate) -> Result<(), String> {
+    let wasm_binary = read_wasm_binary(&options.path)?;
+    let features = get_features(&options.features);
+    webassembly::validate_with_features_or_error(&wasm_binary, &features)
+        .map_err(|err| format!("{}: {}", options.path.as_os_str().to_string_lossy(), err))
+}
+
+fn validate(options: Validate) {
+    if let Err(message) = validate_wasm(&options) {
+        eprintln!("{}", message);
+        exit(1);
+    }
+}
+
+/// Find a function defined in the module, by index or name
+fn find_defined_function(
+    module: &webassembly::Module,
+    name_or_index: &str,
+) -> Result<cranelift_wasm::DefinedFuncIndex, String> {
+    let func_index = webassembly::inspect::find_function(module, name_or_index)
+        .ok_or_else(|| format!("Can't find the function {}", name_or_index))?;
+    module
+        .defined_func_index(func_index)
+        .ok_or_else(|| format!("The function {} is imported", name_or_index))
+}
+
+/// Print the structure of a WASM/WAT file, or the IR/machine code of a function
+fn inspect_wasm(options: &Inspect) -> Result<(), String> {
+    let wasm_binary = read_wasm_binary(&options.path)?;
+    let features = get_features(&options.features);
+    let module = webassembly::compile_with_features(wasm_binary, &features)
+        .map_err(|err| format!("Can't create the WebAssembly module: {}", err))?;
+
+    if let Some(ref name_or_index) = options.ir {
+        let defined_func = find_defined_function(&module, name_or_index)?;
+        print!(
+            "{}",
+            webassembly::inspect::function_ir(&module, defined_func)
+        );
+    }
+    if let Some(ref name_or_index) = options.asm {
+        let defined_func = find_defined_function(&module, name_or_index)?;
+        let isa = webassembly::get_isa();
+        let machine_code =
+            webassembly::inspect::function_machine_code(&module, defined_func, &*isa)
+                .map_err(|err| format!("Can't compile the function {}: {}", name_or_index, err))?;
+        print!("{}", machine_code);
+    }
+    if options.ir.is_none() && options.asm.is_none() {
+        print!("{}", webassembly::inspect::describe_module(&module));
+    }
+    Ok(())
+}
+
+fn inspect(options: Inspect) {
+    if let Err(message) = inspect_wasm(&options) {
+        eprintln!("{}", message);
+        exit(1);
+    }
+}
+
 fn main() {
     let options = CLIOptions::from_args();
+    let verbosity = match options {
+        CLIOptions::Run(ref options) if options.debug => options.verbose.max(2),
+        CLIOptions::Run(ref options) => options.verbose,
+        _ => 0,
+    };
+    logger::init(verbosity).expect("Can't install the logger");
     match options {
         CLIOptions::Run(options) => run(options),
+        CLIOptions::Validate(options) => validate(options),
+        CLIOptions::Inspect(options) => inspect(options),
         CLIOptions::SelfUpdate => update::self_update(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_env;
+
+    #[test]
+    fn test_parse_env() {
+        assert_eq!(
+            parse_env("HOME=/root"),
+            Ok(("HOME".to_string(), "/root".to_string()))
+        );
+        // Only the first `=` separates the value, which can be empty
+        assert_eq!(
+            parse_env("OPTS=a=b"),
+            Ok(("OPTS".to_string(), "a=b".to_string()))
+        );
+        assert_eq!(
+            parse_env("EMPTY="),
+            Ok(("EMPTY".to_string(), String::new()))
+        );
+        assert!(parse_env("HOME").is_err());
+        assert!(parse_env("=value").is_err());
+    }
+}
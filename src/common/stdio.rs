@@ -1,7 +1,10 @@
 use crate::common::file_descriptor::FileDescriptor;
 use libc;
+use std::cell::RefCell;
 use std::io::BufReader;
 use std::io::Read;
+use std::io::{self, Write};
+use std::rc::Rc;
 
 // A struct to hold the references to the base stdout and the captured one
 pub struct StdioCapturer {
@@ -73,3 +76,25 @@ impl StdioCapturer {
         Ok((stdout_read, stderr_read))
     }
 }
+
+/// An in-memory output, shared by its clones: one can be given to an instance
+/// as its stdout, and the other one read after the run.
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl OutputBuffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
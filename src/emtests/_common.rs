@@ -1,13 +1,16 @@
 macro_rules! assert_emscripten_output {
     ($file:expr, $name:expr, $args:expr, $expected:expr) => {{
         use crate::apis::generate_emscripten_env;
-        use crate::common::stdio::StdioCapturer;
+        use crate::common::stdio::OutputBuffer;
         use crate::webassembly::{
-            get_isa, instantiate, start_instance, InstanceABI, InstanceOptions,
+            get_isa, instantiate, start_instance, Features, InstanceABI, InstanceOptions,
         };
 
         let wasm_bytes = include_bytes!($file);
         let import_object = generate_emscripten_env();
+        // The output of the guest is captured by the instance, so the tests can run in parallel
+        let stdout = OutputBuffer::default();
+        let stderr = OutputBuffer::default();
         let options = Some(InstanceOptions {
             mock_missing_imports: true,
             mock_missing_globals: true,
@@ -15,10 +18,21 @@ macro_rules! assert_emscripten_output {
             abi: InstanceABI::Emscripten,
             show_progressbar: false,
             isa: get_isa(),
+            features: Features::default(),
+            gdb_jit: false,
+            perf_map: false,
+            jitdump: false,
+            trace_syscalls: None,
+            coverage: false,
+            vfs: None,
+            env: Vec::new(),
+            stdin: None,
+            stdout: Some(Box::new(stdout.clone())),
+            stderr: Some(Box::new(stderr.clone())),
+            stack_size: None,
         });
         let mut result_object = instantiate(wasm_bytes.to_vec(), import_object, options)
             .expect("Not compiled properly");
-        let capturer = StdioCapturer::new();
         start_instance(
             &result_object.module,
             &mut result_object.instance,
@@ -26,13 +40,14 @@ macro_rules! assert_emscripten_output {
             $args,
         )
         .unwrap();
-        let output = capturer.end().unwrap().0;
+        let output = stdout.contents();
         let expected_output = include_str!($expected);
         assert!(
             output.contains(expected_output),
-            "Output: `{}` does not contain expected output: `{}`",
+            "Output: `{}` does not contain expected output: `{}` (stderr: `{}`)",
             output,
-            expected_output
+            expected_output,
+            stderr.contents()
         );
     }};
 }
@@ -5,6 +5,8 @@ extern crate cranelift_entity;
 extern crate cranelift_native;
 extern crate cranelift_wasm;
 extern crate libc;
+#[macro_use]
+extern crate log;
 extern crate region;
 extern crate structopt;
 extern crate wabt;
@@ -14,6 +16,8 @@ extern crate target_lexicon;
 extern crate byteorder;
 extern crate console;
 extern crate indicatif;
+#[macro_use]
+extern crate lazy_static;
 pub extern crate nix; // re-exported for usage in macros
 extern crate rayon;
 #[cfg(windows)]
@@ -27,6 +31,7 @@ pub mod apis;
 pub mod common;
 #[cfg(test)]
 mod emtests;
+pub mod logger;
 pub mod sighandler;
 #[cfg(test)]
 mod spectests;
@@ -0,0 +1,164 @@
+//! The logger of the command line interface, writing the runtime diagnostics
+//! to stderr (so they don't mix with the output of the guest program).
+//!
+//! The log targets are the module paths, which gives one target per subsystem:
+//! `wasmer::webassembly` (validation, compilation), `wasmer::webassembly::instance`,
+//! `wasmer::apis::emscripten::syscalls`... The levels are set with `-v` for all of
+//! wasmer, or with `RUST_LOG`, using the `env_logger` syntax:
+//! `RUST_LOG=warn,wasmer::apis::emscripten=debug`.
+use log::{self, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::env;
+
+pub struct Logger {
+    /// The level of the targets starting with a path (all targets if `None`).
+    /// The longest matching path wins.
+    directives: Vec<(Option<String>, LevelFilter)>,
+}
+
+fn parse_level(level: &str) -> Option<LevelFilter> {
+    match level.trim().to_lowercase().as_str() {
+        "off" => Some(LevelFilter::Off),
+        "error" => Some(LevelFilter::Error),
+        "warn" => Some(LevelFilter::Warn),
+        "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "trace" => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+impl Logger {
+    /// Warnings and errors only, for all targets.
+    pub fn new() -> Self {
+        Logger {
+            directives: vec![(None, LevelFilter::Warn)],
+        }
+    }
+
+    /// Sets the level of the targets starting with `path`, or of all the targets.
+    pub fn set_level(&mut self, path: Option<&str>, level: LevelFilter) {
+        let path = path.map(str::to_string);
+        self.directives.retain(|(other, _)| *other != path);
+        self.directives.push((path, level));
+    }
+
+    /// Applies a filter like `info,wasmer::webassembly=debug`.
+    /// Invalid directives are ignored.
+    pub fn parse_filter(&mut self, filter: &str) {
+        for directive in filter.split(',').map(str::trim) {
+            let mut parts = directive.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(""), None) => {}
+                (Some(path_or_level), None) => match parse_level(path_or_level) {
+                    Some(level) => self.set_level(None, level),
+                    // A path alone enables all the levels
+                    None => self.set_level(Some(path_or_level), LevelFilter::Trace),
+                },
+                (Some(path), Some(level)) => {
+                    if let Some(level) = parse_level(level) {
+                        self.set_level(Some(path), level);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The level enabled for a target.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .filter(|(path, _)| match path {
+                Some(path) => {
+                    target == path
+                        || (target.starts_with(path.as_str())
+                            && target[path.len()..].starts_with("::"))
+                }
+                None => true,
+            })
+            .max_by_key(|(path, _)| path.as_ref().map(|path| path.len() + 1).unwrap_or(0))
+            .map(|&(_, level)| level)
+            .unwrap_or(LevelFilter::Off)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .map(|&(_, level)| level)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger::new()
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let level = match record.level() {
+                Level::Error => "ERROR",
+                Level::Warn => "WARN ",
+                Level::Info => "INFO ",
+                Level::Debug => "DEBUG",
+                Level::Trace => "TRACE",
+            };
+            eprintln!("[{} {}] {}", level, record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger: warnings and errors by default, then info, debug and
+/// trace for wasmer with each `-v`, then the filter of `RUST_LOG` if set.
+pub fn init(verbosity: u64) -> Result<(), SetLoggerError> {
+    let mut logger = Logger::new();
+    let level = match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    logger.set_level(Some("wasmer"), level);
+    if let Ok(filter) = env::var("RUST_LOG") {
+        logger.parse_filter(&filter);
+    }
+    let max_level = logger.max_level();
+    log::set_logger(Box::leak(Box::new(logger)))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Logger;
+    use log::LevelFilter;
+
+    #[test]
+    fn test_logger_filter() {
+        let mut logger = Logger::new();
+        logger.parse_filter(
+            "info, wasmer::apis::emscripten=debug,wasmer::webassembly=bogus,cranelift",
+        );
+        assert_eq!(logger.level("rayon"), LevelFilter::Info);
+        assert_eq!(
+            logger.level("wasmer::webassembly::instance"),
+            LevelFilter::Info
+        );
+        assert_eq!(
+            logger.level("wasmer::apis::emscripten::syscalls"),
+            LevelFilter::Debug
+        );
+        assert_eq!(logger.level("wasmer::apis::emscriptenx"), LevelFilter::Info);
+        assert_eq!(logger.level("cranelift_codegen"), LevelFilter::Info);
+        assert_eq!(logger.level("cranelift::isa"), LevelFilter::Trace);
+    }
+}
@@ -19,8 +19,52 @@ macro_rules! include_wast2wasm_bytes {
     }};
 }
 
+/// Logs a runtime diagnostic through the `log` crate, at the debug level, with
+/// the module path of the caller as target. Compiled out of release builds,
+/// unless the `debug` feature is enabled.
 #[macro_export]
 macro_rules! debug {
-    ($fmt:expr) => (if cfg!(any(debug_assertions, feature="debug")) { println!(concat!("Wasmer::", $fmt)) });
-    ($fmt:expr, $($arg:tt)*) => (if cfg!(any(debug_assertions, feature="debug")) { println!(concat!("Wasmer::", $fmt, "\n"), $($arg)*) });
+    ($($arg:tt)*) => (if cfg!(any(debug_assertions, feature="debug")) { log!(::log::Level::Debug, $($arg)*) });
+}
+
+/// Logs a call into the emscripten syscalls, if syscall tracing is enabled
+/// for the instance. The arguments are only formatted in that case.
+/// The syscalls return the negated errno of the guest when they fail.
+/// Example:
+/// trace_syscall!(instance, "close", ret, "{}", fd);
+#[macro_export]
+macro_rules! trace_syscall {
+    ($instance:expr, $name:expr, $ret:expr, $($arg:tt)*) => {{
+        let (ret, errno) = $crate::apis::emscripten::syscall_result($ret as i64);
+        trace_call!($instance, $name, ret, errno, $($arg)*);
+    }};
+}
+
+/// Logs a call into the other emscripten host functions, if syscall tracing is
+/// enabled for the instance. `errno` is the one set for the guest, or 0.
+/// Example:
+/// trace_call!(instance, "setenv", ret, errno::EINVAL, "{}", name);
+#[macro_export]
+macro_rules! trace_call {
+    ($instance:expr, $name:expr, $ret:expr, $errno:expr, $($arg:tt)*) => {{
+        if $instance.emscripten_data.as_ref().map_or(false, |data| data.tracer.is_some()) {
+            let args = format!($($arg)*);
+            if let Some(tracer) = $instance.emscripten_data.as_mut().and_then(|data| data.tracer.as_mut()) {
+                tracer.trace($name, &args, Some($ret as i64), $errno);
+            }
+        }
+    }};
+}
+
+/// Same as `trace_syscall!`, for the calls that don't return.
+#[macro_export]
+macro_rules! trace_syscall_noreturn {
+    ($instance:expr, $name:expr, $($arg:tt)*) => {{
+        if $instance.emscripten_data.as_ref().map_or(false, |data| data.tracer.is_some()) {
+            let args = format!($($arg)*);
+            if let Some(tracer) = $instance.emscripten_data.as_mut().and_then(|data| data.tracer.as_mut()) {
+                tracer.trace($name, &args, None, 0);
+            }
+        }
+    }};
 }
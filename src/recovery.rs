@@ -4,8 +4,11 @@
 //! are very special, the async signal unsafety of Rust's TLS implementation generally does not affect the correctness here
 //! unless you have memory unsafety elsewhere in your code.
 
-use nix::libc::siginfo_t;
-use std::cell::{Cell, UnsafeCell};
+use crate::webassembly::backtrace::Backtrace;
+use crate::webassembly::ErrorKind;
+use nix::libc::{c_int, c_void, siginfo_t};
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::mem;
 use std::sync::Once;
 
 extern "C" {
@@ -14,11 +17,22 @@ extern "C" {
 }
 
 const SETJMP_BUFFER_LEN: usize = 27;
+pub const MAX_CAUGHT_FRAMES: usize = 64;
+/// The value given to longjmp by `unwind` (the signals are positive).
+pub const UNWIND_WITHOUT_SIGNAL: c_int = -1;
 pub static SIGHANDLER_INIT: Once = Once::new();
 
 thread_local! {
     pub static SETJMP_BUFFER: UnsafeCell<[::nix::libc::c_int; SETJMP_BUFFER_LEN]> = UnsafeCell::new([0; SETJMP_BUFFER_LEN]);
     pub static CAUGHT_ADDRESS: Cell<usize> = Cell::new(0);
+    /// Instruction addresses of the frames that were on the stack when the trap happened
+    /// (the trapping instruction first, then the return addresses).
+    pub static CAUGHT_FRAMES: UnsafeCell<[usize; MAX_CAUGHT_FRAMES]> = UnsafeCell::new([0; MAX_CAUGHT_FRAMES]);
+    pub static CAUGHT_FRAMES_LEN: Cell<usize> = Cell::new(0);
+    /// Address of the stack at the last protected call, the frame walk stops there.
+    pub static PROTECTED_STACK_END: Cell<usize> = Cell::new(0);
+    /// The error returned by the protected call after an `unwind`.
+    pub static UNWIND_ERROR: RefCell<Option<ErrorKind>> = RefCell::new(None);
 }
 
 // We need a macro since the arguments we will provide to the funciton
@@ -32,7 +46,10 @@ thread_local! {
 macro_rules! call_protected {
     ($x:expr) => {
         unsafe {
-            use crate::recovery::{setjmp, CAUGHT_ADDRESS, SETJMP_BUFFER, SIGHANDLER_INIT};
+            use crate::recovery::{
+                caught_backtrace, setjmp, CAUGHT_ADDRESS, PROTECTED_STACK_END, SETJMP_BUFFER,
+                SIGHANDLER_INIT, UNWIND_ERROR, UNWIND_WITHOUT_SIGNAL,
+            };
             use crate::sighandler::install_sighandler;
             use crate::webassembly::ErrorKind;
 
@@ -40,14 +57,24 @@ macro_rules! call_protected {
 
             let jmp_buf = SETJMP_BUFFER.with(|buf| buf.get());
             let prev_jmp_buf = *jmp_buf;
+            let stack_end_marker = 0u8;
+            let prev_stack_end = PROTECTED_STACK_END.with(|cell| cell.get());
+            PROTECTED_STACK_END.with(|cell| cell.set(&stack_end_marker as *const u8 as usize));
 
             SIGHANDLER_INIT.call_once(|| {
                 install_sighandler();
             });
 
             let signum = setjmp(jmp_buf as *mut ::nix::libc::c_void);
-            if signum != 0 {
+            if signum == UNWIND_WITHOUT_SIGNAL {
                 *jmp_buf = prev_jmp_buf;
+                PROTECTED_STACK_END.with(|cell| cell.set(prev_stack_end));
+                Err(UNWIND_ERROR
+                    .with(|cell| cell.borrow_mut().take())
+                    .expect("unwinding without an error"))
+            } else if signum != 0 {
+                *jmp_buf = prev_jmp_buf;
+                PROTECTED_STACK_END.with(|cell| cell.set(prev_stack_end));
                 let addr = CAUGHT_ADDRESS.with(|cell| cell.get());
 
                 let signal = match Signal::from_c_int(signum) {
@@ -58,21 +85,89 @@ macro_rules! call_protected {
                     Err(_) => "error while getting the Signal",
                     _ => "unkown trapped signal",
                 };
-                Err(ErrorKind::RuntimeError(format!(
-                    "trap at {:#x} - {}",
-                    addr, signal
-                )))
+                Err(ErrorKind::RuntimeError(
+                    format!("trap at {:#x} - {}", addr, signal),
+                    caught_backtrace(),
+                ))
             } else {
                 let ret = $x; // TODO: Switch stack?
                 *jmp_buf = prev_jmp_buf;
+                PROTECTED_STACK_END.with(|cell| cell.set(prev_stack_end));
                 Ok(ret)
             }
         }
     };
 }
 
+/// Unwinds to the last protected call, which returns `err`: this is how the
+/// host functions stop the guest (like `exit` or `abort`). The frames skipped
+/// must not own anything to drop (so the error must be built here, not
+/// borrowed from a value of the caller).
+///
+/// # Panics
+///
+/// Outside of a protected call, as there is nowhere to return the error to.
+pub unsafe fn unwind(err: ErrorKind) -> ! {
+    let jmp_buf = SETJMP_BUFFER.with(|buf| buf.get());
+    if *jmp_buf == [0; SETJMP_BUFFER_LEN] {
+        panic!("Can't unwind outside of a protected call: {}", err);
+    }
+    UNWIND_ERROR.with(|cell| *cell.borrow_mut() = Some(err));
+    longjmp(jmp_buf as *mut ::nix::libc::c_void, UNWIND_WITHOUT_SIGNAL)
+}
+
+/// Returns the (unresolved) backtrace of the last trap caught in this thread.
+pub fn caught_backtrace() -> Backtrace {
+    let len = CAUGHT_FRAMES_LEN.with(|cell| cell.get());
+    let frames = CAUGHT_FRAMES.with(|frames| unsafe { (*frames.get())[..len].to_vec() });
+    Backtrace::from_addresses(&frames)
+}
+
+/// Collects the frames of the interrupted code by following the frame pointers chain.
+/// Cranelift always sets up a frame pointer, so this is reliable across JIT frames.
+/// We only follow frames that live between the interrupted stack pointer and `stack_end`
+/// (the stack of the last protected call), so we never read outside of the current stack.
+/// Returns the number of addresses written in `frames`.
+pub unsafe fn walk_frames(
+    pc: usize,
+    sp: usize,
+    fp: usize,
+    stack_end: usize,
+    frames: &mut [usize],
+) -> usize {
+    let mut len = 0;
+
+    if pc != 0 && len < frames.len() {
+        frames[len] = pc;
+        len += 1;
+    }
+
+    let mut fp = fp;
+    while len < frames.len() && fp >= sp && fp < stack_end && fp % mem::align_of::<usize>() == 0 {
+        let return_address = *(fp as *const usize).add(1);
+        let next_fp = *(fp as *const usize);
+        frames[len] = return_address;
+        len += 1;
+        // The stack grows downwards, callers' frames are always above.
+        if next_fp <= fp {
+            break;
+        }
+        fp = next_fp;
+    }
+
+    len
+}
+
+/// Records the frames of the trapping code, see `caught_backtrace`.
+unsafe fn record_frames(pc: usize, sp: usize, fp: usize) {
+    let stack_end = PROTECTED_STACK_END.with(|cell| cell.get());
+    let frames = CAUGHT_FRAMES.with(|frames| frames.get());
+    let len = walk_frames(pc, sp, fp, stack_end, &mut *frames);
+    CAUGHT_FRAMES_LEN.with(|cell| cell.set(len));
+}
+
 /// Unwinds to last protected_call.
-pub unsafe fn do_unwind(signum: i32, siginfo: *mut siginfo_t) -> ! {
+pub unsafe fn do_unwind(signum: i32, siginfo: *mut siginfo_t, ucontext: *mut c_void) -> ! {
     // Since do_unwind is only expected to get called from WebAssembly code which doesn't hold any host resources (locks etc.)
     // itself, accessing TLS here is safe. In case any other code calls this, it often indicates a memory safety bug and you should
     // temporarily disable the signal handlers to debug it.
@@ -85,5 +180,93 @@ pub unsafe fn do_unwind(signum: i32, siginfo: *mut siginfo_t) -> ! {
     #[cfg(target_os = "macos")]
     CAUGHT_ADDRESS.with(|cell| cell.set((*siginfo).si_addr as _));
 
+    let (pc, sp, fp) = get_registers(ucontext);
+    record_frames(pc, sp, fp);
+
     longjmp(jmp_buf as *mut ::nix::libc::c_void, signum)
 }
+
+/// Returns the instruction, stack and frame pointers of the interrupted code.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub unsafe fn get_registers(ucontext: *mut c_void) -> (usize, usize, usize) {
+    use nix::libc::{ucontext_t, REG_RBP, REG_RIP, REG_RSP};
+    let gregs = &(*(ucontext as *const ucontext_t)).uc_mcontext.gregs;
+    (
+        gregs[REG_RIP as usize] as usize,
+        gregs[REG_RSP as usize] as usize,
+        gregs[REG_RBP as usize] as usize,
+    )
+}
+
+/// Returns the instruction, stack and frame pointers of the interrupted code.
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+#[allow(non_camel_case_types, dead_code)]
+pub unsafe fn get_registers(ucontext: *mut c_void) -> (usize, usize, usize) {
+    // libc doesn't expose the darwin machine context, so we mirror <sys/_structs.h>
+    #[repr(C)]
+    struct ucontext_t {
+        uc_onstack: u32,
+        uc_sigmask: u32,
+        uc_stack: ::nix::libc::stack_t,
+        uc_link: *const ucontext_t,
+        uc_mcsize: u64,
+        uc_mcontext: *const mcontext_t,
+    }
+    #[repr(C)]
+    struct exception_state {
+        trapno: u16,
+        cpu: u16,
+        err: u32,
+        faultvaddr: u64,
+    }
+    #[repr(C)]
+    struct thread_state {
+        rax: u64,
+        rbx: u64,
+        rcx: u64,
+        rdx: u64,
+        rdi: u64,
+        rsi: u64,
+        rbp: u64,
+        rsp: u64,
+        r8: u64,
+        r9: u64,
+        r10: u64,
+        r11: u64,
+        r12: u64,
+        r13: u64,
+        r14: u64,
+        r15: u64,
+        rip: u64,
+        rflags: u64,
+        cs: u64,
+        fs: u64,
+        gs: u64,
+    }
+    #[repr(C)]
+    struct mcontext_t {
+        es: exception_state,
+        ss: thread_state,
+    }
+
+    let state = &(*(*(ucontext as *const ucontext_t)).uc_mcontext).ss;
+    (state.rip as usize, state.rsp as usize, state.rbp as usize)
+}
+
+/// Backtraces are not supported on this platform yet.
+#[cfg(not(all(any(target_os = "linux", target_os = "macos"), target_arch = "x86_64")))]
+pub unsafe fn get_registers(_ucontext: *mut c_void) -> (usize, usize, usize) {
+    (0, 0, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unwind;
+    use crate::webassembly::ErrorKind;
+
+    #[test]
+    #[should_panic(expected = "Can't unwind outside of a protected call")]
+    fn test_unwind_outside_of_a_protected_call() {
+        unsafe { unwind(ErrorKind::Exit(0)) }
+    }
+}
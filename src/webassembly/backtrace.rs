@@ -0,0 +1,88 @@
+//! Wasm-level backtraces, attached to the `RuntimeError`s caused by traps.
+//!
+//! The frames are captured by the signal handler as raw addresses (see
+//! `recovery::do_unwind`), and later resolved into wasm functions and
+//! bytecode offsets with the code ranges recorded by the `Instance`.
+use cranelift_entity::EntityRef;
+use cranelift_wasm::FuncIndex;
+use std::fmt;
+
+use super::instance::Instance;
+use super::module::Module;
+
+#[derive(Debug, Clone)]
+pub struct BacktraceFrame {
+    /// Address of the trapping instruction for the first frame, and
+    /// return addresses for the rest.
+    pub address: usize,
+    /// The wasm function the frame belongs to (None for host code).
+    pub func_index: Option<FuncIndex>,
+    /// Human-readable name of the function, if the module provides one.
+    pub name: Option<String>,
+    /// Offset of the wasm instruction in the module binary.
+    pub bytecode_offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Backtrace {
+    pub frames: Vec<BacktraceFrame>,
+}
+
+impl Backtrace {
+    pub fn from_addresses(addresses: &[usize]) -> Self {
+        let frames = addresses
+            .iter()
+            .map(|&address| BacktraceFrame {
+                address,
+                func_index: None,
+                name: None,
+                bytecode_offset: None,
+            })
+            .collect();
+        Backtrace { frames }
+    }
+
+    /// Map each frame address to the wasm function (and bytecode offset)
+    /// it belongs to in the given instance.
+    pub fn resolve(&mut self, module: &Module, instance: &Instance) {
+        for (i, frame) in self.frames.iter_mut().enumerate() {
+            // Return addresses point right after the call instruction,
+            // so we look up the call itself.
+            let address = if i == 0 {
+                frame.address
+            } else {
+                frame.address.wrapping_sub(1)
+            };
+            if let Some((func_index, code_offset)) = instance.function_at(address) {
+                frame.func_index = Some(func_index);
+                frame.name = module.function_name(func_index);
+                frame.bytecode_offset = instance.bytecode_offset(module, func_index, code_offset);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.frames.is_empty() {
+            return Ok(());
+        }
+        write!(f, "\nwasm backtrace:")?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(f, "\n  {:>3}: ", i)?;
+            match frame.func_index {
+                Some(func_index) => {
+                    match frame.name {
+                        Some(ref name) => write!(f, "{} (func {})", name, func_index.index())?,
+                        None => write!(f, "<func {}>", func_index.index())?,
+                    }
+                    if let Some(offset) = frame.bytecode_offset {
+                        write!(f, " @ {:#x}", offset)?;
+                    }
+                }
+                None => write!(f, "<host code> at {:#x}", frame.address)?,
+            }
+        }
+        Ok(())
+    }
+}
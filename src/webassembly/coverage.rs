@@ -0,0 +1,262 @@
+//! Code coverage of the guest code (`wasmer run --coverage <file>`).
+//!
+//! The `FuncEnvironment` of cranelift-wasm has no hook for basic blocks, so
+//! instead we instrument the IR it produced, before compiling it: a counter
+//! increment is inserted at the start of each EBB (the Cranelift basic
+//! blocks, that follow the wasm control flow). This works with any module,
+//! without recompiling it. Each block is reported with the offset, in the
+//! module binary, of its first wasm instruction.
+//!
+//! The counters are reported as JSON, and as lcov if the module has names or
+//! DWARF line info. Without DWARF, the lcov "lines" are bytecode offsets.
+use cranelift_codegen::cursor::{Cursor, FuncCursor};
+use cranelift_codegen::ir::{types, Ebb, Function, InstBuilder, MemFlags};
+use cranelift_entity::EntityRef;
+use cranelift_wasm::DefinedFuncIndex;
+use std::cell::UnsafeCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::ptr;
+
+use super::dwarf::{find_line, read_line_rows, LineRow};
+use super::module::Module;
+
+#[derive(Debug)]
+struct FunctionCoverage {
+    /// Bytecode offset (in the module binary) of each block, if it
+    /// contains wasm instructions.
+    offsets: Vec<Option<usize>>,
+    /// Execution count of each block, incremented by the compiled code
+    /// (behind the back of the compiler, hence the `UnsafeCell`).
+    counters: Box<[UnsafeCell<u64>]>,
+}
+
+impl FunctionCoverage {
+    /// The current count of a block.
+    fn count(&self, block: usize) -> u64 {
+        unsafe { ptr::read_volatile(self.counters[block].get()) }
+    }
+}
+
+#[derive(Debug)]
+pub struct Coverage {
+    functions: Vec<FunctionCoverage>,
+}
+
+/// A block of a function, as reported.
+struct Block {
+    offset: usize,
+    count: u64,
+}
+
+impl Coverage {
+    /// Instrument the functions defined in the module. Returns the counters
+    /// and the instrumented functions, to be compiled instead of the originals.
+    pub fn instrument(module: &Module) -> (Coverage, Vec<Function>) {
+        let mut functions = Vec::with_capacity(module.info.function_bodies.len());
+        let mut bodies = Vec::with_capacity(module.info.function_bodies.len());
+        for (defined_func, body) in module.info.function_bodies.iter() {
+            let body_offset = module.func_bytecode_offsets[defined_func.index()];
+            let (function, body) = instrument_function(body, body_offset);
+            functions.push(function);
+            bodies.push(body);
+        }
+        (Coverage { functions }, bodies)
+    }
+
+    fn blocks(&self, defined_func: usize) -> Vec<Block> {
+        let function = &self.functions[defined_func];
+        let mut blocks: Vec<Block> = function
+            .offsets
+            .iter()
+            .enumerate()
+            .filter_map(|(block, offset)| {
+                offset.map(|offset| Block {
+                    offset,
+                    count: function.count(block),
+                })
+            })
+            .collect();
+        blocks.sort_by_key(|block| block.offset);
+        blocks
+    }
+
+    /// Write the execution count of the blocks of each function, as JSON.
+    pub fn write_json(&self, module: &Module, path: &Path) -> io::Result<()> {
+        let mut out = String::from("{\n  \"functions\": [");
+        for i in 0..self.functions.len() {
+            let func_index = module.func_index(DefinedFuncIndex::new(i));
+            let blocks: Vec<String> = self
+                .blocks(i)
+                .iter()
+                .map(|block| {
+                    format!(
+                        "{{\"offset\": {}, \"count\": {}}}",
+                        block.offset, block.count
+                    )
+                })
+                .collect();
+            out.push_str(&format!(
+                "{}\n    {{\"index\": {}, \"name\": {}, \"blocks\": [{}]}}",
+                if i == 0 { "" } else { "," },
+                func_index.index(),
+                json_string(&module.function_symbol(func_index)),
+                blocks.join(", ")
+            ));
+        }
+        out.push_str("\n  ]\n}\n");
+        File::create(path)?.write_all(out.as_bytes())
+    }
+
+    /// Whether the module has the information needed for a meaningful lcov output.
+    pub fn has_lcov_info(module: &Module) -> bool {
+        !module.info.names.functions.is_empty() || !module.custom_sections(".debug_line").is_empty()
+    }
+
+    /// Write the coverage in the lcov tracefile format. Source files and lines
+    /// come from the DWARF line info if any, otherwise the module itself is the
+    /// source file, and its bytecode offsets the lines.
+    pub fn write_lcov(&self, module: &Module, module_path: &Path, path: &Path) -> io::Result<()> {
+        let mut rows: Vec<LineRow> = module
+            .custom_sections(".debug_line")
+            .iter()
+            .flat_map(|payload| read_line_rows(payload))
+            .collect();
+        rows.sort_by_key(|row| (row.address, !row.end_sequence));
+        let code_section_offset = module.code_section_offset.unwrap_or(0);
+        let module_name = module_path.to_string_lossy().to_string();
+        let location = |offset: usize| -> (String, u64) {
+            if !rows.is_empty() {
+                let address = offset.saturating_sub(code_section_offset) as u64;
+                if let Some(row) = find_line(&rows, address) {
+                    return (row.file.clone(), row.line);
+                }
+            }
+            (module_name.clone(), offset as u64)
+        };
+
+        // Functions and line counts, by source file
+        let mut files: BTreeMap<String, (Vec<(u64, String, u64)>, BTreeMap<u64, u64>)> =
+            BTreeMap::new();
+        for i in 0..self.functions.len() {
+            let blocks = self.blocks(i);
+            let first_block = match blocks.first() {
+                Some(block) => block,
+                None => continue,
+            };
+            let name = module.function_symbol(module.func_index(DefinedFuncIndex::new(i)));
+            let (file, line) = location(first_block.offset);
+            let entry_count = self.functions[i].count(0);
+            files
+                .entry(file)
+                .or_insert_with(Default::default)
+                .0
+                .push((line, name, entry_count));
+            for block in &blocks {
+                let (file, line) = location(block.offset);
+                let count = files
+                    .entry(file)
+                    .or_insert_with(Default::default)
+                    .1
+                    .entry(line)
+                    .or_insert(0);
+                *count = (*count).max(block.count);
+            }
+        }
+
+        let mut out = String::from("TN:\n");
+        for (file, (functions, lines)) in files {
+            out.push_str(&format!("SF:{}\n", file));
+            for (line, name, _) in &functions {
+                out.push_str(&format!("FN:{},{}\n", line, name));
+            }
+            for (_, name, count) in &functions {
+                out.push_str(&format!("FNDA:{},{}\n", count, name));
+            }
+            out.push_str(&format!("FNF:{}\n", functions.len()));
+            let hit = functions.iter().filter(|function| function.2 > 0).count();
+            out.push_str(&format!("FNH:{}\n", hit));
+            for (line, count) in &lines {
+                out.push_str(&format!("DA:{},{}\n", line, count));
+            }
+            out.push_str(&format!("LF:{}\n", lines.len()));
+            let hit = lines.values().filter(|&&count| count > 0).count();
+            out.push_str(&format!("LH:{}\nend_of_record\n", hit));
+        }
+        File::create(path)?.write_all(out.as_bytes())
+    }
+}
+
+/// A JSON string literal.
+fn json_string(string: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\u{0}'..='\u{1f}' => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// The bytecode offset of the first wasm instruction of an EBB.
+fn ebb_offset(func: &Function, ebb: Ebb, body_offset: usize) -> Option<usize> {
+    func.layout
+        .ebb_insts(ebb)
+        .map(|inst| func.srclocs[inst])
+        .find(|srcloc| !srcloc.is_default())
+        .map(|srcloc| body_offset + srcloc.bits() as usize)
+}
+
+fn instrument_function(body: &Function, body_offset: usize) -> (FunctionCoverage, Function) {
+    let mut func = body.clone();
+    let ebbs: Vec<Ebb> = func.layout.ebbs().collect();
+    let offsets = ebbs
+        .iter()
+        .map(|&ebb| ebb_offset(&func, ebb, body_offset))
+        .collect();
+    let counters = ebbs
+        .iter()
+        .map(|_| UnsafeCell::new(0))
+        .collect::<Vec<_>>()
+        .into_boxed_slice();
+
+    let mut flags = MemFlags::new();
+    flags.set_notrap();
+    flags.set_aligned();
+    {
+        let mut pos = FuncCursor::new(&mut func);
+        for (ebb, counter) in ebbs.iter().zip(counters.iter()) {
+            pos.goto_first_insertion_point(*ebb);
+            // The counters don't move (boxed slice), we can hardcode their address
+            let address = pos.ins().iconst(types::I64, counter.get() as i64);
+            let count = pos.ins().load(types::I64, flags, address, 0);
+            let count = pos.ins().iadd_imm(count, 1);
+            pos.ins().store(flags, count, address, 0);
+        }
+    }
+
+    (FunctionCoverage { offsets, counters }, func)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::json_string;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("_main"), "\"_main\"");
+        assert_eq!(json_string("a\"b\\c\nd"), "\"a\\\"b\\\\c\\nd\"");
+        // Unlike `{:?}`, the other control characters use the `\u` escapes
+        // and the non-ASCII characters are kept
+        assert_eq!(json_string("\u{1}\u{7f}é"), "\"\\u0001\u{7f}é\"");
+    }
+}
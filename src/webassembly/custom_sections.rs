@@ -0,0 +1,336 @@
+//! Custom sections are not handled by `cranelift_wasm::translate_module`,
+//! so we walk the binary ourselves to retain them, and decode the `name`
+//! section into human-readable identifiers. We also look for the `v128`
+//! values of the SIMD proposal, which our parser doesn't know about.
+//! See: https://webassembly.github.io/spec/core/appendix/custom.html
+use cranelift_entity::EntityRef;
+use cranelift_wasm::FuncIndex;
+use std::collections::HashMap;
+use std::str;
+
+const CUSTOM_SECTION_ID: u8 = 0;
+const TYPE_SECTION_ID: u8 = 1;
+const GLOBAL_SECTION_ID: u8 = 6;
+const CODE_SECTION_ID: u8 = 10;
+
+const V128_TYPE: u8 = 0x7b;
+const FUNC_TYPE: u8 = 0x60;
+
+const END_OPCODE: u8 = 0x0b;
+const GET_GLOBAL_OPCODE: u8 = 0x23;
+const I32_CONST_OPCODE: u8 = 0x41;
+const I64_CONST_OPCODE: u8 = 0x42;
+const F32_CONST_OPCODE: u8 = 0x43;
+const F64_CONST_OPCODE: u8 = 0x44;
+
+const MODULE_NAME_SUBSECTION: u8 = 0;
+const FUNCTION_NAMES_SUBSECTION: u8 = 1;
+const LOCAL_NAMES_SUBSECTION: u8 = 2;
+
+/// The decoded contents of the `name` custom section.
+#[derive(Debug, Default)]
+pub struct Names {
+    /// The name of the module.
+    pub module: Option<String>,
+    /// Names of the functions, imported and local.
+    pub functions: HashMap<FuncIndex, String>,
+    /// Names of the locals (params included) of each function.
+    pub locals: HashMap<FuncIndex, HashMap<u32, String>>,
+}
+
+/// A minimal cursor over the bytes of a (already validated) wasm binary.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    fn eof(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    /// Skips a LEB128 number of any size.
+    fn skip_leb(&mut self) -> Option<()> {
+        while self.read_u8()? & 0x80 != 0 {}
+        Some(())
+    }
+
+    /// Skips a constant expression (of the MVP), up to its `end`.
+    fn skip_init_expr(&mut self) -> Option<()> {
+        loop {
+            match self.read_u8()? {
+                END_OPCODE => return Some(()),
+                I32_CONST_OPCODE | I64_CONST_OPCODE | GET_GLOBAL_OPCODE => self.skip_leb()?,
+                F32_CONST_OPCODE => {
+                    self.read_bytes(4)?;
+                }
+                F64_CONST_OPCODE => {
+                    self.read_bytes(8)?;
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn read_var_u32(&mut self) -> Option<u32> {
+        let mut result: u32 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            result |= ((byte & 0x7f) as u32).checked_shl(shift)?;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+            shift += 7;
+            if shift > 28 {
+                return None;
+            }
+        }
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(len)?;
+        let bytes = self.bytes.get(self.position..end)?;
+        self.position = end;
+        Some(bytes)
+    }
+
+    fn read_string(&mut self) -> Option<&'a str> {
+        let len = self.read_var_u32()? as usize;
+        str::from_utf8(self.read_bytes(len)?).ok()
+    }
+}
+
+/// Split a wasm binary into its sections: id, offset of the payload in the
+/// binary, and payload. Stops at the first malformed section.
+fn read_sections(wasm: &[u8]) -> Vec<(u8, usize, &[u8])> {
+    let mut sections = Vec::new();
+    // Skip the magic number and the version
+    let mut reader = Reader::new(wasm.get(8..).unwrap_or(&[]));
+    while !reader.eof() {
+        let section = (|| {
+            let id = reader.read_u8()?;
+            let size = reader.read_var_u32()? as usize;
+            let offset = 8 + reader.position;
+            let payload = reader.read_bytes(size)?;
+            Some((id, offset, payload))
+        })();
+        match section {
+            Some(section) => sections.push(section),
+            None => break,
+        }
+    }
+    sections
+}
+
+/// Collect the payload of every custom section of a wasm binary, grouped
+/// by section name (a name may appear more than once).
+pub fn read_custom_sections(wasm: &[u8]) -> HashMap<String, Vec<Vec<u8>>> {
+    let mut sections: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+    for (id, _, payload) in read_sections(wasm) {
+        if id != CUSTOM_SECTION_ID {
+            continue;
+        }
+        let mut payload_reader = Reader::new(payload);
+        if let Some(name) = payload_reader.read_string() {
+            sections
+                .entry(name.to_string())
+                .or_insert_with(Vec::new)
+                .push(payload[payload_reader.position..].to_vec());
+        }
+    }
+    sections
+}
+
+/// The offset of the code section payload in a wasm binary. DWARF
+/// addresses of wasm code are relative to it.
+pub fn read_code_section_offset(wasm: &[u8]) -> Option<usize> {
+    read_sections(wasm)
+        .into_iter()
+        .find(|&(id, _, _)| id == CODE_SECTION_ID)
+        .map(|(_, offset, _)| offset)
+}
+
+/// Whether a wasm binary uses the `v128` type of the SIMD proposal: in the
+/// signatures, the globals or the locals. Only the value types are
+/// checked, not the operators.
+pub fn uses_v128(wasm: &[u8]) -> bool {
+    read_sections(wasm).into_iter().any(|(id, _, payload)| {
+        let mut reader = Reader::new(payload);
+        let found = match id {
+            TYPE_SECTION_ID => (|| {
+                for _ in 0..reader.read_var_u32()? {
+                    if reader.read_u8()? != FUNC_TYPE {
+                        return None;
+                    }
+                    // Params and results
+                    for _ in 0..2 {
+                        let count = reader.read_var_u32()? as usize;
+                        if reader.read_bytes(count)?.contains(&V128_TYPE) {
+                            return Some(true);
+                        }
+                    }
+                }
+                Some(false)
+            })(),
+            GLOBAL_SECTION_ID => (|| {
+                for _ in 0..reader.read_var_u32()? {
+                    if reader.read_u8()? == V128_TYPE {
+                        return Some(true);
+                    }
+                    // Mutability, then the initializer
+                    reader.read_u8()?;
+                    reader.skip_init_expr()?;
+                }
+                Some(false)
+            })(),
+            CODE_SECTION_ID => (|| {
+                for _ in 0..reader.read_var_u32()? {
+                    let size = reader.read_var_u32()? as usize;
+                    let mut body = Reader::new(reader.read_bytes(size)?);
+                    for _ in 0..body.read_var_u32()? {
+                        body.read_var_u32()?;
+                        if body.read_u8()? == V128_TYPE {
+                            return Some(true);
+                        }
+                    }
+                }
+                Some(false)
+            })(),
+            _ => None,
+        };
+        found.unwrap_or(false)
+    })
+}
+
+/// Decode the payload of a `name` custom section. Malformed subsections
+/// are ignored, as the spec mandates for custom sections.
+pub fn read_names(payload: &[u8]) -> Names {
+    let mut names = Names::default();
+    let mut reader = Reader::new(payload);
+    while !reader.eof() {
+        let subsection = (|| {
+            let id = reader.read_u8()?;
+            let size = reader.read_var_u32()? as usize;
+            Some((id, reader.read_bytes(size)?))
+        })();
+        let (id, subsection) = match subsection {
+            Some(subsection) => subsection,
+            None => break,
+        };
+        let mut subsection_reader = Reader::new(subsection);
+        match id {
+            MODULE_NAME_SUBSECTION => {
+                names.module = subsection_reader.read_string().map(str::to_string);
+            }
+            FUNCTION_NAMES_SUBSECTION => {
+                read_name_map(&mut subsection_reader, |index, name| {
+                    names
+                        .functions
+                        .insert(FuncIndex::new(index as usize), name.to_string());
+                });
+            }
+            LOCAL_NAMES_SUBSECTION => {
+                let count = subsection_reader.read_var_u32().unwrap_or(0);
+                for _ in 0..count {
+                    let func_index = match subsection_reader.read_var_u32() {
+                        Some(index) => FuncIndex::new(index as usize),
+                        None => break,
+                    };
+                    let locals = names.locals.entry(func_index).or_insert_with(HashMap::new);
+                    if !read_name_map(&mut subsection_reader, |index, name| {
+                        locals.insert(index, name.to_string());
+                    }) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    names
+}
+
+/// Read a `namemap` (a vector of index/name pairs). Returns false if it
+/// could not be read entirely.
+fn read_name_map<F: FnMut(u32, &str)>(reader: &mut Reader, mut f: F) -> bool {
+    let count = match reader.read_var_u32() {
+        Some(count) => count,
+        None => return false,
+    };
+    for _ in 0..count {
+        match (reader.read_var_u32(), reader.read_string()) {
+            (Some(index), Some(name)) => f(index, name),
+            _ => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_code_section_offset, read_custom_sections, read_names, uses_v128};
+    use cranelift_wasm::FuncIndex;
+
+    #[test]
+    fn test_read_custom_sections_and_names() {
+        let wasm = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+            0x00, 0x04, 0x03, b'f', b'o', b'o', // custom section `foo`, empty
+            0x00, 0x1a, 0x04, b'n', b'a', b'm', b'e', // custom section `name`
+            0x00, 0x04, 0x03, b'm', b'o', b'd', // module name
+            0x01, 0x05, 0x01, 0x00, 0x02, b'f', b'n', // function names
+            0x02, 0x06, 0x01, 0x00, 0x01, 0x00, 0x01, b'x', // local names
+        ];
+        let sections = read_custom_sections(&wasm);
+        assert_eq!(sections["foo"], vec![Vec::<u8>::new()]);
+        assert_eq!(read_code_section_offset(&wasm), None);
+
+        let names = read_names(&sections["name"][0]);
+        assert_eq!(names.module, Some("mod".to_string()));
+        assert_eq!(names.functions[&FuncIndex::new(0)], "fn");
+        assert_eq!(names.locals[&FuncIndex::new(0)][&0], "x");
+    }
+
+    #[test]
+    fn test_uses_v128() {
+        let header = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        // (func (param i32) (result i64))
+        let mvp = [0x01, 0x06, 0x01, 0x60, 0x01, 0x7f, 0x01, 0x7e];
+        // (func (param v128))
+        let simd_param = [0x01, 0x05, 0x01, 0x60, 0x01, 0x7b, 0x00];
+        // (func (local v128)), with a type section without v128
+        let simd_local = [
+            0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type
+            0x03, 0x02, 0x01, 0x00, // function
+            0x0a, 0x06, 0x01, 0x04, 0x01, 0x01, 0x7b, 0x0b, // code
+        ];
+        assert!(!uses_v128(&[&header[..], &mvp[..]].concat()));
+        assert!(uses_v128(&[&header[..], &simd_param[..]].concat()));
+        assert!(uses_v128(&[&header[..], &simd_local[..]].concat()));
+        // (global i64 (i64.const -1)) (global f64 (f64.const 0)) (global v128 ...)
+        let simd_global = [
+            0x06, 0x1c, 0x03, // global
+            0x7e, 0x00, 0x42, 0x7f, 0x0b, // i64
+            0x7c, 0x00, 0x44, 0, 0, 0, 0, 0, 0, 0, 0, 0x0b, // f64
+            0x7b, 0x00, 0xfd, 0x0c, 0, 0, 0, 0, 0, 0, // v128, truncated
+        ];
+        assert!(uses_v128(&[&header[..], &simd_global[..]].concat()));
+        let mvp_globals = [
+            0x06, 0x0e, 0x02, // global
+            0x7f, 0x01, 0x41, 0x00, 0x0b, // mut i32
+            0x7d, 0x00, 0x43, 0x00, 0x00, 0x80, 0x3f, 0x0b, // f32
+        ];
+        assert!(!uses_v128(&[&header[..], &mvp_globals[..]].concat()));
+    }
+}
@@ -0,0 +1,368 @@
+//! A minimal reader of the DWARF line number programs (`.debug_line`
+//! custom section), to map wasm code offsets back to source lines.
+//! Only the 32-bit format of DWARF versions 2 to 4 is supported.
+//! See: http://dwarfstd.org/doc/DWARF4.pdf (section 6.2)
+use std::cmp::Ordering;
+use std::str;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+/// A row of the line number matrix. For wasm, addresses are offsets
+/// from the start of the code section payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineRow {
+    pub address: u64,
+    pub file: String,
+    pub line: u64,
+    /// The first address after a sequence of instructions.
+    pub end_sequence: bool,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+
+    fn eof(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(len)?;
+        let bytes = self.bytes.get(self.position..end)?;
+        self.position = end;
+        Some(bytes)
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        Some(self.read_bytes(1)?[0])
+    }
+
+    fn read_uint(&mut self, size: usize) -> Option<u64> {
+        let bytes = self.read_bytes(size)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| (value << 8) | u64::from(byte)),
+        )
+    }
+
+    fn read_uleb(&mut self) -> Option<u64> {
+        let mut result: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift < 64 {
+                result |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+    }
+
+    fn read_sleb(&mut self) -> Option<i64> {
+        let mut result: i64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift < 64 {
+                result |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    result |= -1 << shift;
+                }
+                return Some(result);
+            }
+        }
+    }
+
+    fn read_cstr(&mut self) -> Option<&'a str> {
+        let len = self
+            .bytes
+            .get(self.position..)?
+            .iter()
+            .position(|&b| b == 0)?;
+        let string = str::from_utf8(self.read_bytes(len)?).ok()?;
+        self.position += 1;
+        Some(string)
+    }
+}
+
+/// Read the rows of all the line number programs of a `.debug_line` section.
+/// Malformed programs are skipped (after the rows read before the error).
+pub fn read_line_rows(debug_line: &[u8]) -> Vec<LineRow> {
+    let mut rows = Vec::new();
+    let mut reader = Reader::new(debug_line);
+    while !reader.eof() {
+        let unit_length = match reader.read_uint(4) {
+            // 64-bit DWARF is not supported
+            Some(length) if length < 0xffff_fff0 => length as usize,
+            _ => break,
+        };
+        let unit = match reader.read_bytes(unit_length) {
+            Some(unit) => unit,
+            None => break,
+        };
+        read_unit(unit, &mut rows);
+    }
+    rows
+}
+
+fn read_unit(unit: &[u8], rows: &mut Vec<LineRow>) -> Option<()> {
+    let mut reader = Reader::new(unit);
+    let version = reader.read_uint(2)?;
+    match version {
+        2..=4 => {}
+        _ => return None,
+    }
+    let header_length = reader.read_uint(4)? as usize;
+    let program_start = reader.position.checked_add(header_length)?;
+    let min_inst_length = u64::from(reader.read_u8()?);
+    if version >= 4 {
+        // maximum_operations_per_instruction, only used for VLIW
+        reader.read_u8()?;
+    }
+    let _default_is_stmt = reader.read_u8()?;
+    let line_base = i64::from(reader.read_u8()? as i8);
+    let line_range = u64::from(reader.read_u8()?);
+    let opcode_base = reader.read_u8()?;
+    if line_range == 0 || opcode_base == 0 {
+        return None;
+    }
+    let standard_opcode_lengths = reader.read_bytes(opcode_base as usize - 1)?;
+
+    let mut directories = vec![String::new()];
+    loop {
+        match reader.read_cstr()? {
+            "" => break,
+            directory => directories.push(directory.to_string()),
+        }
+    }
+    // File indices start at 1
+    let mut files = vec![String::new()];
+    loop {
+        match reader.read_cstr()? {
+            "" => break,
+            name => files.push(read_file_entry(&mut reader, &directories, name)?),
+        }
+    }
+
+    reader.position = program_start;
+    let mut address: u64 = 0;
+    let mut file = 1;
+    let mut line: i64 = 1;
+    while !reader.eof() {
+        let opcode = reader.read_u8()?;
+        let mut end_sequence = false;
+        if opcode >= opcode_base {
+            let adjusted = u64::from(opcode - opcode_base);
+            address = address.checked_add((adjusted / line_range).checked_mul(min_inst_length)?)?;
+            line = line.checked_add(line_base + (adjusted % line_range) as i64)?;
+        } else {
+            match opcode {
+                0 => {
+                    let length = reader.read_uleb()? as usize;
+                    let end = reader.position.checked_add(length)?;
+                    match reader.read_u8()? {
+                        DW_LNE_END_SEQUENCE => end_sequence = true,
+                        DW_LNE_SET_ADDRESS => match length.checked_sub(1)? {
+                            size @ 1..=8 => address = reader.read_uint(size)?,
+                            _ => return None,
+                        },
+                        DW_LNE_DEFINE_FILE => {
+                            let name = reader.read_cstr()?;
+                            let path = read_file_entry(&mut reader, &directories, name)?;
+                            files.push(path);
+                        }
+                        _ => {}
+                    }
+                    reader.position = end;
+                    if !end_sequence {
+                        continue;
+                    }
+                }
+                DW_LNS_COPY => {}
+                DW_LNS_ADVANCE_PC => {
+                    address =
+                        address.checked_add(reader.read_uleb()?.checked_mul(min_inst_length)?)?;
+                    continue;
+                }
+                DW_LNS_ADVANCE_LINE => {
+                    line = line.checked_add(reader.read_sleb()?)?;
+                    continue;
+                }
+                DW_LNS_SET_FILE => {
+                    file = reader.read_uleb()? as usize;
+                    continue;
+                }
+                DW_LNS_CONST_ADD_PC => {
+                    let advance = (u64::from(255 - opcode_base) / line_range) * min_inst_length;
+                    address = address.checked_add(advance)?;
+                    continue;
+                }
+                DW_LNS_FIXED_ADVANCE_PC => {
+                    address = address.checked_add(reader.read_uint(2)?)?;
+                    continue;
+                }
+                _ => {
+                    // Skip the (ULEB128) operands of the other standard opcodes
+                    for _ in 0..standard_opcode_lengths[opcode as usize - 1] {
+                        reader.read_uleb()?;
+                    }
+                    continue;
+                }
+            }
+        }
+        rows.push(LineRow {
+            address,
+            file: files.get(file).cloned().unwrap_or_default(),
+            line: line.max(0) as u64,
+            end_sequence,
+        });
+        if end_sequence {
+            address = 0;
+            file = 1;
+            line = 1;
+        }
+    }
+    Some(())
+}
+
+/// Read the directory index, modification time and length of a file entry,
+/// returning the path of the file.
+fn read_file_entry(reader: &mut Reader, directories: &[String], name: &str) -> Option<String> {
+    let directory = reader.read_uleb()? as usize;
+    reader.read_uleb()?; // modification time
+    reader.read_uleb()?; // length
+    Some(match directories.get(directory) {
+        Some(directory) if !directory.is_empty() && !name.starts_with('/') => {
+            format!("{}/{}", directory, name)
+        }
+        _ => name.to_string(),
+    })
+}
+
+/// Find the source location of an address, in rows sorted by address.
+pub fn find_line(rows: &[LineRow], address: u64) -> Option<&LineRow> {
+    // Index of the first row after the address
+    let after = match rows.binary_search_by(|row| {
+        if row.address <= address {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        }
+    }) {
+        Ok(position) | Err(position) => position,
+    };
+    if after == 0 {
+        return None;
+    }
+    let row = &rows[after - 1];
+    if row.end_sequence {
+        None
+    } else {
+        Some(row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_line, read_line_rows};
+
+    #[test]
+    fn test_read_line_rows() {
+        let mut program = vec![
+            0x04, 0x00, // version 4
+            0x1f, 0x00, 0x00, 0x00, // header length
+            0x01, 0x01, 0x01, // min instruction length, max ops, default is_stmt
+            0xfb, 0x0e, 0x0d, // line base -5, line range 14, opcode base 13
+            0x00, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+            0x01, // opcode lengths
+            b's', b'r', b'c', 0x00, 0x00, // include directories
+            b'a', b'.', b'c', 0x00, 0x01, 0x00, 0x00, 0x00, // files
+            0x00, 0x05, 0x02, 0x10, 0x00, 0x00, 0x00, // set address 0x10
+            0x03, 0x02, // advance line to 3
+            0x01, // copy
+            0x21, // special: address += 1, line += 1 (0x21 - 13 = 20 = 1 * 14 + 6)
+            0x02, 0x04, // advance pc by 4
+            0x00, 0x01, 0x01, // end sequence
+        ];
+        let mut section = vec![program.len() as u8, 0x00, 0x00, 0x00];
+        section.append(&mut program);
+
+        let rows = read_line_rows(&section);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(find_line(&rows, 0x0f), None);
+        let row = find_line(&rows, 0x10).unwrap();
+        assert_eq!((row.file.as_str(), row.line), ("src/a.c", 3));
+        let row = find_line(&rows, 0x14).unwrap();
+        assert_eq!((row.address, row.line), (0x11, 4));
+        assert_eq!(find_line(&rows, 0x15), None);
+    }
+
+    fn unit(program: &[u8]) -> Vec<u8> {
+        let mut unit = vec![
+            0x04, 0x00, // version 4
+            0x14, 0x00, 0x00, 0x00, // header length
+            0x01, 0x01, 0x01, // min instruction length, max ops, default is_stmt
+            0xfb, 0x0e, 0x0d, // line base -5, line range 14, opcode base 13
+            0x00, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+            0x01, // opcode lengths
+            0x00, 0x00, // no directories nor files
+        ];
+        unit.extend_from_slice(program);
+        let mut section = vec![unit.len() as u8, 0x00, 0x00, 0x00];
+        section.append(&mut unit);
+        section
+    }
+
+    #[test]
+    fn test_read_malformed_line_rows() {
+        // Extended opcode without its opcode byte
+        assert!(read_line_rows(&unit(&[0x00, 0x00, 0x02])).is_empty());
+        // Extended opcode longer than the section
+        let huge = [
+            0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+        ];
+        assert!(read_line_rows(&unit(&huge)).is_empty());
+        // Address overflow
+        let program = [
+            0x00, 0x09, 0x02, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // set address
+            0x01, // copy
+            0x02, 0x01, // advance pc by 1
+            0x01, // copy
+        ];
+        assert_eq!(read_line_rows(&unit(&program)).len(), 1);
+        // Line overflow
+        let program = [
+            0x03, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, // advance line
+            0x03, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, // advance line
+            0x01, // copy
+        ];
+        assert!(read_line_rows(&unit(&program)).is_empty());
+        // Header longer than the unit
+        let mut section = unit(&[0x01]);
+        section[6] = 0xff;
+        section[7] = 0xff;
+        assert!(read_line_rows(&section).is_empty());
+    }
+}
@@ -10,6 +10,12 @@
 //! RuntimeError object — the type that is thrown whenever WebAssembly
 //!  specifies a trap.
 
+//! Exit and Abort stop the program early, when it calls `exit` or `abort`
+//! (or the runtime aborts it).
+
+use super::backtrace::Backtrace;
+use super::validation::ValidationError;
+
 error_chain! {
     errors {
         CompileError(reason: String) {
@@ -17,14 +23,29 @@ error_chain! {
             display("Compilation error: {}", reason)
         }
 
+        ValidationError(error: ValidationError) {
+            description("WebAssembly validation error")
+            display("Validation error: {}", error)
+        }
+
         LinkError(reason: String) {
             description("WebAssembly link error")
             display("Link error: {}", reason)
         }
 
-        RuntimeError(reason: String) {
+        RuntimeError(reason: String, backtrace: Backtrace) {
             description("WebAssembly runtime error")
-            display("Runtime error: {}", reason)
+            display("Runtime error: {}{}", reason, backtrace)
+        }
+
+        Exit(code: i32) {
+            description("WebAssembly program exit")
+            display("Exited with code {}", code)
+        }
+
+        Abort(message: String) {
+            description("WebAssembly program abort")
+            display("Aborted: {}", message)
         }
     }
 }
@@ -0,0 +1,103 @@
+//! Toggles for the WebAssembly proposals that are not part of the
+//! MVP specification.
+//!
+//! A `Features` value decides which proposals a module is allowed to
+//! use. It is checked when validating (and so before translating) a
+//! module, so a host can pin the exact feature set it accepts.
+use wasmparser::{OperatorValidatorConfig, ValidatingParserConfig};
+
+use super::custom_sections::uses_v128;
+use super::errors::ErrorKind;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features {
+    /// Threads proposal: shared memories and atomic operators.
+    pub threads: bool,
+    /// Reference types proposal: `anyref` and the `ref.*` operators.
+    pub reference_types: bool,
+    /// Import and export of mutable globals.
+    pub mutable_global_imports: bool,
+    /// Fixed-width SIMD proposal (`v128`).
+    pub simd: bool,
+    /// Bulk memory operations proposal (`memory.copy`, `memory.fill`, ...).
+    pub bulk_memory: bool,
+    /// Multi-value proposal: multiple results for functions and blocks.
+    pub multi_value: bool,
+}
+
+impl Features {
+    /// Make sure every enabled proposal can actually be parsed and
+    /// translated by the wasmparser/cranelift versions we build with.
+    pub fn check_supported(&self) -> Result<(), ErrorKind> {
+        // cranelift-wasm can't translate the atomic operators (threads) nor
+        // the `ref.*` ones (reference types) yet, even if we can validate them
+        let unsupported = [
+            ("threads", self.threads),
+            ("reference-types", self.reference_types),
+            ("simd", self.simd),
+            ("bulk-memory", self.bulk_memory),
+            ("multi-value", self.multi_value),
+        ];
+        match unsupported.iter().find(|(_, enabled)| *enabled) {
+            Some((name, _)) => Err(ErrorKind::CompileError(format!(
+                "The `{}` WebAssembly feature is not supported yet",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Reject the modules that use a proposal we can't parse at all, with a
+    /// clearer error than the one of the validator.
+    pub fn check_module(&self, wasm: &[u8]) -> Result<(), ErrorKind> {
+        if uses_v128(wasm) {
+            return Err(ErrorKind::CompileError(
+                "The module uses `v128` values of the `simd` WebAssembly feature, which is not supported yet"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn validating_parser_config(&self) -> ValidatingParserConfig {
+        ValidatingParserConfig {
+            operator_config: OperatorValidatorConfig {
+                enable_threads: self.threads,
+                enable_reference_types: self.reference_types,
+            },
+            mutable_global_imports: self.mutable_global_imports,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Features;
+    use crate::webassembly::ErrorKind;
+
+    #[test]
+    fn test_unsupported_features_are_rejected() {
+        let mut features = Features::default();
+        assert!(features.check_supported().is_ok());
+        features.simd = true;
+        assert!(features.check_supported().is_err());
+    }
+
+    #[test]
+    fn test_untranslatable_features_are_rejected() {
+        let threads = Features {
+            threads: true,
+            ..Features::default()
+        };
+        let reference_types = Features {
+            reference_types: true,
+            ..Features::default()
+        };
+        for features in &[threads, reference_types] {
+            match features.check_supported() {
+                Err(ErrorKind::CompileError(_)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+}
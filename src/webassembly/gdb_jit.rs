@@ -0,0 +1,631 @@
+//! Registration of the compiled code with the GDB JIT interface, so native
+//! debuggers can show (and break into) the wasm functions by name.
+//!
+//! For each instance we emit a small in-memory ELF object that describes
+//! where the functions have been placed (the code itself is not copied, the
+//! `.text` section is `SHT_NOBITS`). It also carries a DWARF line table
+//! in which the "line" of each instruction is its offset in the wasm binary.
+//!
+//! See: https://sourceware.org/gdb/onlinedocs/gdb/JIT-Interface.html
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::ptr;
+use std::sync::Mutex;
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+pub struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry,
+}
+
+/// GDB reads this descriptor when `__jit_debug_register_code` is called.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: 0 as *mut JitCodeEntry,
+    first_entry: 0 as *mut JitCodeEntry,
+};
+
+/// GDB sets a breakpoint in this function to get notified of new code.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // Make sure the calls to this function are not optimized away.
+    unsafe {
+        ptr::read_volatile(&__jit_debug_descriptor.action_flag);
+    }
+}
+
+lazy_static! {
+    /// Guards the descriptor list, as instances can be created from any thread.
+    static ref DESCRIPTOR_LOCK: Mutex<()> = Mutex::new(());
+}
+
+fn with_descriptor_lock<F: FnOnce()>(f: F) {
+    // The list is only changed under the lock, a panic elsewhere can't
+    // leave it half updated
+    let _guard = DESCRIPTOR_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    f();
+}
+
+/// A compiled function to describe in the debug object.
+pub struct JitFunction<'a> {
+    pub name: String,
+    pub code: &'a [u8],
+    /// Offset of the function body in the wasm binary.
+    pub bytecode_offset: usize,
+    /// Machine code offsets paired with the (body relative) wasm offsets.
+    pub srclocs: &'a [(u32, u32)],
+}
+
+/// Keeps a debug object registered with GDB until dropped.
+#[derive(Debug)]
+pub struct GdbJitRegistration {
+    entry: *mut JitCodeEntry,
+    // GDB reads the object from memory, it must live as long as the entry
+    #[allow(dead_code)]
+    image: Vec<u8>,
+}
+
+impl GdbJitRegistration {
+    pub fn new(file_name: &str, functions: &[JitFunction]) -> Self {
+        let image = build_elf(file_name, functions);
+        let entry = Box::into_raw(Box::new(JitCodeEntry {
+            next_entry: ptr::null_mut(),
+            prev_entry: ptr::null_mut(),
+            symfile_addr: image.as_ptr(),
+            symfile_size: image.len() as u64,
+        }));
+        with_descriptor_lock(|| unsafe {
+            (*entry).next_entry = __jit_debug_descriptor.first_entry;
+            if !(*entry).next_entry.is_null() {
+                (*(*entry).next_entry).prev_entry = entry;
+            }
+            __jit_debug_descriptor.first_entry = entry;
+            __jit_debug_descriptor.relevant_entry = entry;
+            __jit_debug_descriptor.action_flag = JIT_REGISTER_FN;
+            __jit_debug_register_code();
+        });
+        GdbJitRegistration { entry, image }
+    }
+}
+
+impl Drop for GdbJitRegistration {
+    fn drop(&mut self) {
+        let entry = self.entry;
+        with_descriptor_lock(|| unsafe {
+            if (*entry).prev_entry.is_null() {
+                __jit_debug_descriptor.first_entry = (*entry).next_entry;
+            } else {
+                (*(*entry).prev_entry).next_entry = (*entry).next_entry;
+            }
+            if !(*entry).next_entry.is_null() {
+                (*(*entry).next_entry).prev_entry = (*entry).prev_entry;
+            }
+            __jit_debug_descriptor.relevant_entry = entry;
+            __jit_debug_descriptor.action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+            drop(Box::from_raw(entry));
+        });
+    }
+}
+
+// ELF constants
+const ET_REL: u16 = 1;
+const EM_X86_64: u16 = 62;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_FILE: u8 = 4;
+const SHN_ABS: u16 = 0xfff1;
+
+const ELF_HEADER_SIZE: usize = 64;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+// Section indexes
+const TEXT: u32 = 1;
+const STRTAB: u32 = 3;
+const SHSTRTAB: u32 = 4;
+const SECTIONS_COUNT: usize = 8;
+
+// DWARF constants
+const DW_TAG_COMPILE_UNIT: u8 = 0x11;
+const DW_CHILDREN_NO: u8 = 0;
+const DW_AT_NAME: u8 = 0x03;
+const DW_AT_STMT_LIST: u8 = 0x10;
+const DW_AT_LOW_PC: u8 = 0x11;
+const DW_AT_HIGH_PC: u8 = 0x12;
+const DW_FORM_ADDR: u8 = 0x01;
+const DW_FORM_DATA4: u8 = 0x06;
+const DW_FORM_STRING: u8 = 0x08;
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+fn write_uleb128(buffer: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buffer.push(byte);
+            return;
+        }
+        buffer.push(byte | 0x80);
+    }
+}
+
+fn write_sleb128(buffer: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            buffer.push(byte);
+            return;
+        }
+        buffer.push(byte | 0x80);
+    }
+}
+
+fn write_cstr(buffer: &mut Vec<u8>, string: &str) {
+    buffer.extend_from_slice(string.as_bytes());
+    buffer.push(0);
+}
+
+/// A string table, returning the offset of each added string.
+struct StringTable(Vec<u8>);
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable(vec![0])
+    }
+
+    fn add(&mut self, string: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        write_cstr(&mut self.0, string);
+        offset
+    }
+}
+
+fn build_debug_abbrev() -> Vec<u8> {
+    vec![
+        1, // abbreviation code
+        DW_TAG_COMPILE_UNIT,
+        DW_CHILDREN_NO,
+        DW_AT_NAME,
+        DW_FORM_STRING,
+        DW_AT_STMT_LIST,
+        DW_FORM_DATA4,
+        DW_AT_LOW_PC,
+        DW_FORM_ADDR,
+        DW_AT_HIGH_PC,
+        DW_FORM_ADDR,
+        0,
+        0,
+        0,
+    ]
+}
+
+fn build_debug_info(file_name: &str, low_pc: u64, high_pc: u64) -> Vec<u8> {
+    let mut unit = Vec::new();
+    unit.write_u16::<LittleEndian>(2).unwrap(); // version
+    unit.write_u32::<LittleEndian>(0).unwrap(); // .debug_abbrev offset
+    unit.push(8); // address size
+    write_uleb128(&mut unit, 1);
+    write_cstr(&mut unit, file_name);
+    unit.write_u32::<LittleEndian>(0).unwrap(); // .debug_line offset
+    unit.write_u64::<LittleEndian>(low_pc).unwrap();
+    unit.write_u64::<LittleEndian>(high_pc).unwrap();
+
+    let mut section = Vec::new();
+    section
+        .write_u32::<LittleEndian>(unit.len() as u32)
+        .unwrap();
+    section.extend(unit);
+    section
+}
+
+fn build_debug_line(file_name: &str, functions: &[JitFunction]) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.push(1); // minimum instruction length
+    header.push(1); // default is_stmt
+    header.push(-5i8 as u8); // line base
+    header.push(14); // line range
+    header.push(13); // opcode base
+    header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+    header.push(0); // no include directories
+    write_cstr(&mut header, file_name);
+    header.extend_from_slice(&[0, 0, 0]); // directory, mtime and length
+    header.push(0); // end of file names
+
+    let mut program = Vec::new();
+    for function in functions {
+        let address = function.code.as_ptr() as u64;
+        program.extend_from_slice(&[0, 9, DW_LNE_SET_ADDRESS]);
+        program.write_u64::<LittleEndian>(address).unwrap();
+
+        let mut current_offset = 0u64;
+        let mut current_line = 1i64;
+        for &(code_offset, srcloc) in function.srclocs {
+            let line = (function.bytecode_offset + srcloc as usize) as i64;
+            if line == current_line {
+                continue;
+            }
+            program.push(DW_LNS_ADVANCE_PC);
+            write_uleb128(&mut program, u64::from(code_offset) - current_offset);
+            program.push(DW_LNS_ADVANCE_LINE);
+            write_sleb128(&mut program, line - current_line);
+            program.push(DW_LNS_COPY);
+            current_offset = u64::from(code_offset);
+            current_line = line;
+        }
+
+        program.push(DW_LNS_ADVANCE_PC);
+        write_uleb128(&mut program, function.code.len() as u64 - current_offset);
+        program.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+    }
+
+    let mut unit = Vec::new();
+    unit.write_u16::<LittleEndian>(2).unwrap(); // version
+    unit.write_u32::<LittleEndian>(header.len() as u32).unwrap();
+    unit.extend(header);
+    unit.extend(program);
+
+    let mut section = Vec::new();
+    section
+        .write_u32::<LittleEndian>(unit.len() as u32)
+        .unwrap();
+    section.extend(unit);
+    section
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+/// Build an ELF object describing the given functions.
+pub fn build_elf(file_name: &str, functions: &[JitFunction]) -> Vec<u8> {
+    let low_pc = functions
+        .iter()
+        .map(|function| function.code.as_ptr() as u64)
+        .min()
+        .unwrap_or(0);
+    let high_pc = functions
+        .iter()
+        .map(|function| function.code.as_ptr() as u64 + function.code.len() as u64)
+        .max()
+        .unwrap_or(0);
+
+    let mut shstrtab = StringTable::new();
+    let mut strtab = StringTable::new();
+
+    let mut symtab = vec![0; SYMBOL_SIZE];
+    {
+        let mut write_symbol = |name: u32, info: u8, shndx: u16, value: u64, size: u64| {
+            symtab.write_u32::<LittleEndian>(name).unwrap();
+            symtab.push(info);
+            symtab.push(0);
+            symtab.write_u16::<LittleEndian>(shndx).unwrap();
+            symtab.write_u64::<LittleEndian>(value).unwrap();
+            symtab.write_u64::<LittleEndian>(size).unwrap();
+        };
+        let file_name_offset = strtab.add(file_name);
+        write_symbol(file_name_offset, (STB_LOCAL << 4) | STT_FILE, SHN_ABS, 0, 0);
+        for function in functions {
+            let name = strtab.add(&function.name);
+            write_symbol(
+                name,
+                (STB_GLOBAL << 4) | STT_FUNC,
+                TEXT as u16,
+                function.code.as_ptr() as u64,
+                function.code.len() as u64,
+            );
+        }
+    }
+
+    let debug_info = build_debug_info(file_name, low_pc, high_pc);
+    let debug_abbrev = build_debug_abbrev();
+    let debug_line = build_debug_line(file_name, functions);
+
+    let mut sections: Vec<SectionHeader> = Vec::with_capacity(SECTIONS_COUNT);
+    let mut data: Vec<u8> = Vec::new();
+    let mut add_section = |sections: &mut Vec<SectionHeader>,
+                           name: u32,
+                           kind: u32,
+                           contents: &[u8],
+                           link: u32,
+                           info: u32,
+                           entsize: u64| {
+        let offset = (ELF_HEADER_SIZE + data.len()) as u64;
+        data.extend_from_slice(contents);
+        sections.push(SectionHeader {
+            name,
+            kind,
+            flags: 0,
+            addr: 0,
+            offset,
+            size: contents.len() as u64,
+            link,
+            info,
+            align: 1,
+            entsize,
+        });
+    };
+
+    sections.push(SectionHeader {
+        name: 0,
+        kind: 0,
+        flags: 0,
+        addr: 0,
+        offset: 0,
+        size: 0,
+        link: 0,
+        info: 0,
+        align: 0,
+        entsize: 0,
+    });
+    sections.push(SectionHeader {
+        name: shstrtab.add(".text"),
+        kind: SHT_NOBITS,
+        flags: SHF_ALLOC | SHF_EXECINSTR,
+        addr: low_pc,
+        offset: ELF_HEADER_SIZE as u64,
+        size: high_pc - low_pc,
+        link: 0,
+        info: 0,
+        align: 16,
+        entsize: 0,
+    });
+    // The first global symbol comes after the null and file symbols
+    let symtab_name = shstrtab.add(".symtab");
+    add_section(
+        &mut sections,
+        symtab_name,
+        SHT_SYMTAB,
+        &symtab,
+        STRTAB,
+        2,
+        SYMBOL_SIZE as u64,
+    );
+    let strtab_name = shstrtab.add(".strtab");
+    add_section(&mut sections, strtab_name, SHT_STRTAB, &strtab.0, 0, 0, 0);
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    let debug_info_name = shstrtab.add(".debug_info");
+    let debug_abbrev_name = shstrtab.add(".debug_abbrev");
+    let debug_line_name = shstrtab.add(".debug_line");
+    add_section(
+        &mut sections,
+        shstrtab_name,
+        SHT_STRTAB,
+        &shstrtab.0,
+        0,
+        0,
+        0,
+    );
+    add_section(
+        &mut sections,
+        debug_info_name,
+        SHT_PROGBITS,
+        &debug_info,
+        0,
+        0,
+        0,
+    );
+    add_section(
+        &mut sections,
+        debug_abbrev_name,
+        SHT_PROGBITS,
+        &debug_abbrev,
+        0,
+        0,
+        0,
+    );
+    add_section(
+        &mut sections,
+        debug_line_name,
+        SHT_PROGBITS,
+        &debug_line,
+        0,
+        0,
+        0,
+    );
+    debug_assert_eq!(sections.len(), SECTIONS_COUNT);
+
+    // Section headers go after the sections data, 8-byte aligned
+    while data.len() % 8 != 0 {
+        data.push(0);
+    }
+    let section_headers_offset = (ELF_HEADER_SIZE + data.len()) as u64;
+
+    let mut elf =
+        Vec::with_capacity(ELF_HEADER_SIZE + data.len() + SECTION_HEADER_SIZE * sections.len());
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    elf.extend_from_slice(&[0; 8]);
+    elf.write_u16::<LittleEndian>(ET_REL).unwrap();
+    elf.write_u16::<LittleEndian>(EM_X86_64).unwrap();
+    elf.write_u32::<LittleEndian>(1).unwrap(); // version
+    elf.write_u64::<LittleEndian>(0).unwrap(); // entry
+    elf.write_u64::<LittleEndian>(0).unwrap(); // program headers offset
+    elf.write_u64::<LittleEndian>(section_headers_offset)
+        .unwrap();
+    elf.write_u32::<LittleEndian>(0).unwrap(); // flags
+    elf.write_u16::<LittleEndian>(ELF_HEADER_SIZE as u16)
+        .unwrap();
+    elf.write_u16::<LittleEndian>(0).unwrap(); // program header size
+    elf.write_u16::<LittleEndian>(0).unwrap(); // program headers count
+    elf.write_u16::<LittleEndian>(SECTION_HEADER_SIZE as u16)
+        .unwrap();
+    elf.write_u16::<LittleEndian>(sections.len() as u16)
+        .unwrap();
+    elf.write_u16::<LittleEndian>(SHSTRTAB as u16).unwrap();
+    debug_assert_eq!(elf.len(), ELF_HEADER_SIZE);
+
+    elf.extend(data);
+    for section in &sections {
+        elf.write_u32::<LittleEndian>(section.name).unwrap();
+        elf.write_u32::<LittleEndian>(section.kind).unwrap();
+        elf.write_u64::<LittleEndian>(section.flags).unwrap();
+        elf.write_u64::<LittleEndian>(section.addr).unwrap();
+        elf.write_u64::<LittleEndian>(section.offset).unwrap();
+        elf.write_u64::<LittleEndian>(section.size).unwrap();
+        elf.write_u32::<LittleEndian>(section.link).unwrap();
+        elf.write_u32::<LittleEndian>(section.info).unwrap();
+        elf.write_u64::<LittleEndian>(section.align).unwrap();
+        elf.write_u64::<LittleEndian>(section.entsize).unwrap();
+    }
+    elf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_elf, JitFunction, SHT_NOBITS, SHT_SYMTAB, SYMBOL_SIZE};
+    use crate::webassembly::dwarf::read_line_rows;
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::str;
+
+    struct Section<'a> {
+        name: &'a str,
+        kind: u32,
+        addr: u64,
+        size: u64,
+        contents: &'a [u8],
+    }
+
+    /// Reads the section headers of an ELF64 little endian object.
+    fn read_sections(elf: &[u8]) -> Vec<Section> {
+        assert_eq!(&elf[..6], &[0x7f, b'E', b'L', b'F', 2, 1]);
+        let shoff = LittleEndian::read_u64(&elf[0x28..]) as usize;
+        let shentsize = LittleEndian::read_u16(&elf[0x3a..]) as usize;
+        let shnum = LittleEndian::read_u16(&elf[0x3c..]) as usize;
+        let shstrndx = LittleEndian::read_u16(&elf[0x3e..]) as usize;
+        let header = |index: usize| &elf[shoff + index * shentsize..][..shentsize];
+        let contents = |header: &[u8]| {
+            let offset = LittleEndian::read_u64(&header[0x18..]) as usize;
+            let size = LittleEndian::read_u64(&header[0x20..]) as usize;
+            &elf[offset..offset + size]
+        };
+        let shstrtab = contents(header(shstrndx));
+        (0..shnum)
+            .map(|index| {
+                let header = header(index);
+                let name = &shstrtab[LittleEndian::read_u32(header) as usize..];
+                let name = &name[..name.iter().position(|&byte| byte == 0).unwrap()];
+                let kind = LittleEndian::read_u32(&header[4..]);
+                Section {
+                    name: str::from_utf8(name).unwrap(),
+                    kind,
+                    addr: LittleEndian::read_u64(&header[0x10..]),
+                    size: LittleEndian::read_u64(&header[0x20..]),
+                    contents: if kind == SHT_NOBITS {
+                        &[]
+                    } else {
+                        contents(header)
+                    },
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build_elf_round_trip() {
+        let code = [0x90u8; 64];
+        let functions = [
+            JitFunction {
+                name: "_main".to_string(),
+                code: &code[..32],
+                bytecode_offset: 100,
+                srclocs: &[(0, 5), (4, 5), (10, 9)],
+            },
+            JitFunction {
+                name: "_helper".to_string(),
+                code: &code[32..],
+                bytecode_offset: 200,
+                srclocs: &[(0, 2)],
+            },
+        ];
+        let start = code.as_ptr() as u64;
+        let elf = build_elf("module.wasm", &functions);
+        let sections = read_sections(&elf);
+
+        let names: Vec<_> = sections.iter().map(|section| section.name).collect();
+        assert_eq!(
+            names,
+            [
+                "",
+                ".text",
+                ".symtab",
+                ".strtab",
+                ".shstrtab",
+                ".debug_info",
+                ".debug_abbrev",
+                ".debug_line"
+            ]
+        );
+        let text = &sections[1];
+        assert_eq!((text.kind, text.addr, text.size), (SHT_NOBITS, start, 64));
+
+        // The null and file symbols, then the functions
+        let symtab = &sections[2];
+        assert_eq!(symtab.kind, SHT_SYMTAB);
+        assert_eq!(symtab.contents.len(), 4 * SYMBOL_SIZE);
+        let symbol = &symtab.contents[3 * SYMBOL_SIZE..];
+        let name = &sections[3].contents[LittleEndian::read_u32(symbol) as usize..];
+        assert!(name.starts_with(b"_helper\0"));
+        assert_eq!(LittleEndian::read_u64(&symbol[8..]), start + 32);
+        assert_eq!(LittleEndian::read_u64(&symbol[16..]), 32);
+
+        // The lines are the offsets in the wasm binary
+        let rows: Vec<_> = read_line_rows(sections[7].contents)
+            .into_iter()
+            .map(|row| (row.address - start, row.line, row.end_sequence))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (0, 105, false),
+                (10, 109, false),
+                (32, 109, true),
+                (32, 202, false),
+                (64, 202, true)
+            ]
+        );
+        assert!(read_line_rows(sections[7].contents)
+            .iter()
+            .all(|row| row.file == "module.wasm"));
+    }
+}
@@ -0,0 +1,233 @@
+//! Human-readable dumps of a module, used by `wasmer inspect` to diagnose
+//! miscompilations and ABI mismatches: the structure recorded in the
+//! `ModuleInfo`, the Cranelift IR produced by the `FuncEnvironment` for a
+//! function, and the machine code generated for it.
+use cranelift_codegen::isa::TargetIsa;
+use cranelift_codegen::{binemit, Context};
+use cranelift_entity::EntityRef;
+use cranelift_wasm::{DefinedFuncIndex, FuncIndex};
+use std::fmt::Write;
+
+use super::errors::ErrorKind;
+use super::module::{ImportableExportable, Module};
+use super::relocation::RelocSink;
+
+/// Find a function by its index, or by the name given to it in the module.
+pub fn find_function(module: &Module, name_or_index: &str) -> Option<FuncIndex> {
+    if let Ok(index) = name_or_index.parse::<usize>() {
+        if index < module.info.functions.len() {
+            return Some(FuncIndex::new(index));
+        }
+        return None;
+    }
+    (0..module.info.functions.len())
+        .map(FuncIndex::new)
+        .find(|&func_index| {
+            module
+                .function_name(func_index)
+                .as_ref()
+                .map(String::as_str)
+                == Some(name_or_index)
+        })
+}
+
+fn import_export_names<T>(entity: &ImportableExportable<T>) -> String {
+    let mut names = String::new();
+    if let Some((ref module, ref field)) = entity.import_name {
+        names.push_str(&format!(" imported from {}.{}", module, field));
+    }
+    for name in &entity.export_names {
+        names.push_str(&format!(" exported as {:?}", name));
+    }
+    names
+}
+
+/// Describe the imports, exports, functions, tables, memories, globals and
+/// data segments of the module.
+pub fn describe_module(module: &Module) -> String {
+    let info = &module.info;
+    let mut out = String::new();
+
+    if let Some(ref name) = info.names.module {
+        writeln!(out, "Module {:?}", name).unwrap();
+    }
+
+    writeln!(out, "Types ({}):", info.signatures.len()).unwrap();
+    for (i, signature) in info.signatures.iter().enumerate() {
+        writeln!(out, "  type {}: {}", i, signature).unwrap();
+    }
+
+    writeln!(out, "Imports ({}):", info.imported_funcs.len()).unwrap();
+    for (i, (module_name, field)) in info.imported_funcs.iter().enumerate() {
+        let signature_index = info.functions[FuncIndex::new(i)].entity;
+        writeln!(
+            out,
+            "  func {}: {}.{} (type {})",
+            i,
+            module_name,
+            field,
+            signature_index.index()
+        )
+        .unwrap();
+    }
+
+    let mut exports: Vec<_> = info.exports.iter().collect();
+    exports.sort_by(|a, b| a.0.cmp(b.0));
+    writeln!(out, "Exports ({}):", exports.len()).unwrap();
+    for (name, export) in exports {
+        writeln!(out, "  {:?}: {:?}", name, export).unwrap();
+    }
+
+    writeln!(out, "Functions ({} defined):", info.function_bodies.len()).unwrap();
+    for (i, function) in info.functions.iter().skip(info.imported_funcs.len()) {
+        writeln!(
+            out,
+            "  func {}: {} (type {}){}",
+            i.index(),
+            module.function_symbol(i),
+            function.entity.index(),
+            import_export_names(function)
+        )
+        .unwrap();
+    }
+    if let Some(start_func) = info.start_func {
+        writeln!(out, "  start: func {}", start_func.index()).unwrap();
+    }
+
+    writeln!(out, "Tables ({}):", info.tables.len()).unwrap();
+    for (i, table) in info.tables.iter().enumerate() {
+        writeln!(
+            out,
+            "  table {}: {:?}, minimum {}, maximum {:?}{}",
+            i,
+            table.entity.ty,
+            table.entity.minimum,
+            table.entity.maximum,
+            import_export_names(table)
+        )
+        .unwrap();
+    }
+    for elements in &info.table_elements {
+        writeln!(
+            out,
+            "  elements: table {}, base {:?}, offset {}, {} functions",
+            elements.table_index.index(),
+            elements.base.map(|base| base.index()),
+            elements.offset,
+            elements.elements.len()
+        )
+        .unwrap();
+    }
+
+    writeln!(out, "Memories ({}):", info.memories.len()).unwrap();
+    for (i, memory) in info.memories.iter().enumerate() {
+        writeln!(
+            out,
+            "  memory {}: minimum {} pages, maximum {:?}, shared {}{}",
+            i,
+            memory.entity.minimum,
+            memory.entity.maximum,
+            memory.entity.shared,
+            import_export_names(memory)
+        )
+        .unwrap();
+    }
+
+    writeln!(out, "Globals ({}):", info.globals.len()).unwrap();
+    for (i, global) in info.globals.iter().enumerate() {
+        writeln!(
+            out,
+            "  global {}: {} {}, {:?}{}",
+            i,
+            if global.entity.mutability {
+                "mut"
+            } else {
+                "const"
+            },
+            global.entity.ty,
+            global.entity.initializer,
+            import_export_names(global)
+        )
+        .unwrap();
+    }
+
+    writeln!(out, "Data segments ({}):", info.data_initializers.len()).unwrap();
+    for init in &info.data_initializers {
+        writeln!(
+            out,
+            "  memory {}: base {:?}, offset {:#x}, {} bytes",
+            init.memory_index.index(),
+            init.base.map(|base| base.index()),
+            init.offset,
+            init.data.len()
+        )
+        .unwrap();
+    }
+
+    let mut custom_sections: Vec<_> = info.custom_sections.iter().collect();
+    custom_sections.sort_by(|a, b| a.0.cmp(b.0));
+    writeln!(out, "Custom sections ({}):", custom_sections.len()).unwrap();
+    for (name, payloads) in custom_sections {
+        for payload in payloads {
+            writeln!(out, "  {:?}: {} bytes", name, payload.len()).unwrap();
+        }
+    }
+
+    out
+}
+
+/// The Cranelift IR of a function, as translated from wasm by the `FuncEnvironment`
+/// (before legalization and register allocation).
+pub fn function_ir(module: &Module, defined_func: DefinedFuncIndex) -> String {
+    module.info.function_bodies[defined_func]
+        .display(None)
+        .to_string()
+}
+
+/// The machine code generated for a function. It isn't disassembled: each
+/// instruction of the final (legalized, register allocated) IR is listed with
+/// its offset, encoding and bytes. Relocations (calls, libcalls) are not applied.
+pub fn function_machine_code(
+    module: &Module,
+    defined_func: DefinedFuncIndex,
+    isa: &TargetIsa,
+) -> Result<String, ErrorKind> {
+    let mut context = Context::for_function(module.info.function_bodies[defined_func].clone());
+    let mut code_buf: Vec<u8> = Vec::new();
+    let mut reloc_sink = RelocSink::new();
+    let mut trap_sink = binemit::NullTrapSink {};
+    context
+        .compile_and_emit(isa, &mut code_buf, &mut reloc_sink, &mut trap_sink)
+        .map_err(|e| ErrorKind::CompileError(e.to_string()))?;
+
+    let mut out = String::new();
+    let func = &context.func;
+    let encoding_info = isa.encoding_info();
+    writeln!(
+        out,
+        "function {} {} ; {} bytes",
+        func.name,
+        func.signature,
+        code_buf.len()
+    )
+    .unwrap();
+    for ebb in func.layout.ebbs() {
+        writeln!(out, "{}:", ebb).unwrap();
+        for (offset, inst, size) in func.inst_offsets(ebb, &encoding_info) {
+            let bytes: Vec<String> = code_buf[offset as usize..(offset + size) as usize]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            writeln!(
+                out,
+                "  {:06x}: {:<30} {:<16} {}",
+                offset,
+                bytes.join(" "),
+                encoding_info.display(func.encodings[inst]).to_string(),
+                func.dfg.display_inst(inst, isa)
+            )
+            .unwrap();
+        }
+    }
+    Ok(out)
+}
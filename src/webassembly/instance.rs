@@ -11,27 +11,36 @@ use cranelift_codegen::ir::{Function, LibCall};
 use cranelift_codegen::isa::TargetIsa;
 use cranelift_codegen::{binemit, Context};
 use cranelift_entity::EntityRef;
-use cranelift_wasm::{FuncIndex, GlobalInit};
+use cranelift_wasm::{DefinedFuncIndex, FuncIndex, GlobalInit};
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 
-use libc::c_int;
 use region;
-use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::io::{Read, Write};
 use std::iter::FromIterator;
 use std::iter::Iterator;
 use std::mem::size_of;
-use std::ptr::write_unaligned;
+use std::ptr::{self, write_unaligned};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{fmt, mem, slice};
 
 use super::super::common::slice::{BoundedSlice, UncheckedSlice};
+use super::coverage::Coverage;
 use super::errors::ErrorKind;
+use super::features::Features;
+use super::gdb_jit::{GdbJitRegistration, JitFunction};
 use super::import_object::{ImportObject, ImportValue};
 use super::libcalls;
 use super::memory::LinearMemory;
 use super::module::{Export, ImportableExportable, Module};
+use super::perf::{self, PerfFunction};
 use super::relocation::{Reloc, RelocSink, RelocationType};
+use super::stats::{CompileStats, FunctionStats, RelocationCounts};
+use crate::apis::emscripten::{
+    Environment, Exceptions, JumpBuffer, MemoryLayout, ReadStream, SyscallTracer, Vfs, WriteStream,
+};
 
 type TablesSlice = UncheckedSlice<BoundedSlice<usize>>;
 // TODO: this should be `type MemoriesSlice = UncheckedSlice<UncheckedSlice<u8>>;`, but that crashes for some reason.
@@ -74,11 +83,30 @@ pub struct EmscriptenData {
     pub memalign: extern "C" fn(u32, u32, &mut Instance) -> u32,
     pub memset: extern "C" fn(u32, i32, u32, &mut Instance) -> u32,
     pub stack_alloc: extern "C" fn(u32, &Instance) -> u32,
-    pub jumps: Vec<UnsafeCell<[c_int; 27]>>,
+    pub tracer: Option<SyscallTracer>,
+    // Exported functions used by the exception handling
+    pub stack_save: Option<extern "C" fn(&Instance) -> u32>,
+    pub stack_restore: Option<extern "C" fn(u32, &Instance)>,
+    pub set_threw: Option<extern "C" fn(i32, i32, &Instance)>,
+    pub cxa_can_catch: Option<extern "C" fn(u32, u32, u32, &Instance) -> i32>,
+    /// The exported `___errno_location`, through which the guest errno is set
+    pub errno_location: Option<extern "C" fn(&Instance) -> u32>,
+    /// The exported `dynCall_*` functions, by name
+    pub dyn_calls: HashMap<String, *const u8>,
+    /// The context of the active `invoke_*` trampolines, the innermost last
+    pub invoke_jumps: Vec<Box<JumpBuffer>>,
+    pub exceptions: Exceptions,
+    pub temp_ret_0: i32,
+    /// The filesystems and file descriptors of the guest
+    pub vfs: Vfs,
+    /// The environment variables of the guest
+    pub env: Environment,
+    /// Where the stack and the heap are
+    pub layout: MemoryLayout,
 }
 
 impl EmscriptenData {
-    pub fn new(module: &Module, instance: &Instance) -> Self {
+    pub fn new(module: &Module, instance: &Instance, layout: MemoryLayout) -> Self {
         unsafe {
             debug!("emscripten::new");
             let malloc_export = module.info.exports.get("_malloc");
@@ -113,13 +141,41 @@ impl EmscriptenData {
                 stack_alloc_addr = instance.get_function_pointer(*stack_alloc_index);
             }
 
+            let export_addr = |name: &str| match module.info.exports.get(name) {
+                Some(Export::Function(func_index)) => instance.get_function_pointer(*func_index),
+                _ => ptr::null(),
+            };
+            let dyn_calls = module
+                .info
+                .exports
+                .iter()
+                .filter_map(|(name, export)| match export {
+                    Export::Function(func_index) if name.starts_with("dynCall_") => {
+                        Some((name.clone(), instance.get_function_pointer(*func_index)))
+                    }
+                    _ => None,
+                })
+                .collect();
+
             EmscriptenData {
                 malloc: mem::transmute(malloc_addr),
                 free: mem::transmute(free_addr),
                 memalign: mem::transmute(memalign_addr),
                 memset: mem::transmute(memset_addr),
                 stack_alloc: mem::transmute(stack_alloc_addr),
-                jumps: Vec::new(),
+                tracer: None,
+                stack_save: mem::transmute(export_addr("stackSave")),
+                stack_restore: mem::transmute(export_addr("stackRestore")),
+                set_threw: mem::transmute(export_addr("setThrew")),
+                cxa_can_catch: mem::transmute(export_addr("___cxa_can_catch")),
+                errno_location: mem::transmute(export_addr("___errno_location")),
+                dyn_calls,
+                invoke_jumps: Vec::new(),
+                exceptions: Exceptions::default(),
+                temp_ret_0: 0,
+                vfs: Vfs::default(),
+                env: Environment::default(),
+                layout,
             }
         }
     }
@@ -194,12 +250,25 @@ pub struct Instance {
     /// Imported functions
     import_functions: Vec<*const u8>,
 
+    /// For each function, the offsets of its machine code paired with the
+    /// offsets of the wasm instructions they were generated from
+    functions_srclocs: Vec<Vec<(u32, u32)>>,
+
     /// The module start function
     pub start_func: Option<FuncIndex>,
     // Region start memory location
     // code_base: *const (),
     pub emscripten_data: Option<EmscriptenData>,
 
+    /// Debug object describing our code to GDB (if enabled)
+    gdb_jit_registration: Option<GdbJitRegistration>,
+
+    /// Execution counters of the guest code (if enabled)
+    pub coverage: Option<Coverage>,
+
+    /// Statistics of the compilation of the module
+    pub compile_stats: CompileStats,
+
     // Workarounds to prevent use after free issue
     memories_pointer: Vec<BoundedSlice<u8>>,
     tables_pointer: Vec<BoundedSlice<usize>>,
@@ -229,6 +298,29 @@ pub struct InstanceOptions {
     pub abi: InstanceABI,
     pub show_progressbar: bool,
     pub isa: Box<TargetIsa>,
+    // The WebAssembly proposals the module was compiled with
+    pub features: Features,
+    // Shall we register the compiled code with the GDB JIT interface?
+    pub gdb_jit: bool,
+    // Shall we write the compiled functions to /tmp/perf-<pid>.map?
+    pub perf_map: bool,
+    // Shall we write the compiled functions to /tmp/jit-<pid>.dump?
+    pub jitdump: bool,
+    // Where to log the calls into the emscripten host functions, if anywhere
+    pub trace_syscalls: Option<Box<Write>>,
+    // Shall we count the executions of each block of the guest code?
+    pub coverage: bool,
+    // The filesystem of an emscripten guest (an empty in-memory one by default)
+    pub vfs: Option<Vfs>,
+    // The environment variables of an emscripten guest, added to the defaults of emscripten
+    // (the host environment is never exposed)
+    pub env: Vec<(String, String)>,
+    // The standard streams of an emscripten guest (the ones of the host by default)
+    pub stdin: Option<Box<Read>>,
+    pub stdout: Option<Box<Write>>,
+    pub stderr: Option<Box<Write>>,
+    // The size of the stack of an emscripten guest (the one it was built with by default)
+    pub stack_size: Option<u32>,
 }
 
 extern "C" fn mock_fn() -> i32 {
@@ -241,12 +333,15 @@ struct CompiledFunction {
     code_buf: Vec<u8>,
     reloc_sink: RelocSink,
     trap_sink: binemit::NullTrapSink,
+    srclocs: Vec<(u32, u32)>,
+    compile_time: Duration,
 }
 
 fn compile_function(
     isa: &TargetIsa,
     function_body: &Function,
 ) -> Result<CompiledFunction, ErrorKind> {
+    let start = Instant::now();
     let mut func_context = Context::for_function(function_body.to_owned());
 
     let mut code_buf: Vec<u8> = Vec::new();
@@ -260,10 +355,27 @@ fn compile_function(
             ErrorKind::CompileError(e.to_string())
         })?;
 
+    // Map the offset of each emitted instruction to the (function relative)
+    // offset of the wasm instruction it comes from, to resolve backtraces.
+    let mut srclocs = Vec::new();
+    let encoding_info = isa.encoding_info();
+    let func = &func_context.func;
+    for ebb in func.layout.ebbs() {
+        for (offset, inst, _size) in func.inst_offsets(ebb, &encoding_info) {
+            let srcloc = func.srclocs[inst];
+            if !srcloc.is_default() {
+                srclocs.push((offset, srcloc.bits()));
+            }
+        }
+    }
+    srclocs.sort();
+
     Ok(CompiledFunction {
         code_buf,
         reloc_sink,
         trap_sink,
+        srclocs,
+        compile_time: start.elapsed(),
     })
 }
 
@@ -277,7 +389,7 @@ impl Instance {
     ///     Also make sure imports that are not declared do not get added to the instance
     pub fn new(
         module: &Module,
-        import_object: ImportObject<&str, &str>,
+        mut import_object: ImportObject<&str, &str>,
         options: InstanceOptions,
     ) -> Result<Instance, ErrorKind> {
         let mut tables: Vec<Vec<usize>> = Vec::new();
@@ -286,6 +398,27 @@ impl Instance {
 
         let mut functions: Vec<Vec<u8>> = Vec::new();
         let mut import_functions: Vec<*const u8> = Vec::new();
+        let mut functions_srclocs: Vec<Vec<(u32, u32)>> = Vec::new();
+        let mut compile_stats = module.stats.clone();
+
+        // The emscripten modules import the layout of their memory as globals
+        let emscripten_layout = if options.abi == InstanceABI::Emscripten {
+            let layout =
+                MemoryLayout::new(module, options.stack_size).map_err(ErrorKind::LinkError)?;
+            debug!("emscripten::layout {:?}", layout);
+            layout.set_globals(&mut import_object);
+            Some(layout)
+        } else {
+            None
+        };
+
+        // When collecting coverage, we compile instrumented copies of the functions
+        let (coverage, instrumented_bodies) = if options.coverage {
+            let (coverage, bodies) = Coverage::instrument(module);
+            (Some(coverage), Some(bodies))
+        } else {
+            (None, None)
+        };
 
         debug!("Instance - Instantiating functions");
         // Instantiate functions
@@ -305,6 +438,16 @@ impl Instance {
                 let imported = import_object.get(&module.as_str(), &field.as_str());
                 let function: &*const u8 = match imported {
                     Some(ImportValue::Func(f)) => f,
+                    // Mocking a trampoline would silently stop catching the exceptions
+                    None if options.abi == InstanceABI::Emscripten
+                        && module == "env"
+                        && field.starts_with("invoke_") =>
+                    {
+                        return Err(ErrorKind::LinkError(format!(
+                            "The signature of the emscripten trampoline {}.{} is not supported",
+                            module, field
+                        )));
+                    }
                     None => {
                         if options.mock_missing_imports {
                             debug!(
@@ -328,7 +471,10 @@ impl Instance {
 
             debug!("Instance - Compiling functions");
             // Compile the functions (from cranelift IR to machine code)
-            let values: Vec<&Function> = Vec::from_iter(module.info.function_bodies.values());
+            let values: Vec<&Function> = match instrumented_bodies {
+                Some(ref bodies) => bodies.iter().collect(),
+                None => Vec::from_iter(module.info.function_bodies.values()),
+            };
             // let isa: &TargetIsa = &*options.isa;
 
             let progress_bar_option = if options.show_progressbar {
@@ -347,6 +493,7 @@ impl Instance {
                 None
             };
 
+            let compile_start = Instant::now();
             let compiled_funcs: Vec<CompiledFunction> = values
                 .par_iter()
                 .map(|function_body| -> CompiledFunction {
@@ -367,16 +514,29 @@ impl Instance {
                 )));
             };
 
-            for compiled_func in compiled_funcs.into_iter() {
+            compile_stats.compile_time = compile_start.elapsed();
+
+            for (i, compiled_func) in compiled_funcs.into_iter().enumerate() {
                 let CompiledFunction {
                     code_buf,
                     reloc_sink,
+                    srclocs,
+                    compile_time,
                     ..
                 } = compiled_func;
 
+                compile_stats.functions.push(FunctionStats {
+                    defined_func: DefinedFuncIndex::new(i),
+                    bytecode_size: module.func_bytecode_sizes[i],
+                    compile_time,
+                    code_size: code_buf.len(),
+                    relocations: RelocationCounts::from_relocations(&reloc_sink.func_relocs),
+                });
+
                 // let func_offset = code_buf;
                 protect_codebuf(&code_buf).unwrap();
                 functions.push(code_buf);
+                functions_srclocs.push(srclocs);
 
                 // context_and_offsets.push(func_context);
                 relocations.push(reloc_sink.func_relocs);
@@ -442,6 +602,50 @@ impl Instance {
             }
         }
 
+        let gdb_jit_registration = if options.gdb_jit {
+            debug!("Instance - Registering functions in GDB");
+            let jit_functions: Vec<JitFunction> = functions
+                .iter()
+                .zip(functions_srclocs.iter())
+                .enumerate()
+                .map(|(i, (code, srclocs))| JitFunction {
+                    name: module.function_symbol(module.func_index(DefinedFuncIndex::new(i))),
+                    code: &code[..],
+                    bytecode_offset: module.func_bytecode_offsets[i],
+                    srclocs: &srclocs[..],
+                })
+                .collect();
+            let file_name = match module.info.names.module {
+                Some(ref name) => format!("{}.wasm", name),
+                None => "module.wasm".to_string(),
+            };
+            Some(GdbJitRegistration::new(&file_name, &jit_functions))
+        } else {
+            None
+        };
+
+        if options.perf_map || options.jitdump {
+            debug!("Instance - Writing functions for perf");
+            let perf_functions: Vec<PerfFunction> = functions
+                .iter()
+                .enumerate()
+                .map(|(i, code)| PerfFunction {
+                    name: module.function_symbol(module.func_index(DefinedFuncIndex::new(i))),
+                    code: &code[..],
+                })
+                .collect();
+            if options.perf_map {
+                if let Err(err) = perf::write_perf_map(&perf_functions) {
+                    warn!("Can't write the perf map: {}", err);
+                }
+            }
+            if options.jitdump {
+                if let Err(err) = perf::write_jitdump(&perf_functions) {
+                    warn!("Can't write the jitdump: {}", err);
+                }
+            }
+        }
+
         debug!("Instance - Instantiating globals");
         // Instantiate Globals
         let globals_data = {
@@ -578,19 +782,12 @@ impl Instance {
             // Get memories in module
             for memory in &module.info.memories {
                 let memory = memory.entity;
-                // If we use emscripten, we set a fixed initial and maximum
                 debug!(
                     "Instance - init memory ({}, {:?})",
                     memory.minimum, memory.maximum
                 );
-                let memory = if options.abi == InstanceABI::Emscripten {
-                    // We use MAX_PAGES, so at the end the result is:
-                    // (initial * LinearMemory::PAGE_SIZE) == LinearMemory::DEFAULT_HEAP_SIZE
-                    // However, it should be: (initial * LinearMemory::PAGE_SIZE) == 16777216
-                    LinearMemory::new(LinearMemory::MAX_PAGES, None)
-                } else {
-                    LinearMemory::new(memory.minimum, memory.maximum.map(|m| m as u32))
-                };
+                // The emscripten modules grow it with enlargeMemory
+                let memory = LinearMemory::new(memory.minimum, memory.maximum.map(|m| m as u32));
                 memories.push(memory);
             }
 
@@ -607,9 +804,10 @@ impl Instance {
                 let to_init = &mut mem[offset..offset + init.data.len()];
                 to_init.copy_from_slice(&init.data);
             }
-            if options.abi == InstanceABI::Emscripten {
+            if let Some(ref layout) = emscripten_layout {
                 debug!("emscripten::setup memory");
-                crate::apis::emscripten::emscripten_set_up_memory(&mut memories[0]);
+                crate::apis::emscripten::emscripten_set_up_memory(&mut memories[0], layout)
+                    .map_err(ErrorKind::LinkError)?;
                 debug!("emscripten::finish setup memory");
             }
         }
@@ -644,14 +842,37 @@ impl Instance {
             globals,
             functions,
             import_functions,
+            functions_srclocs,
             start_func,
             emscripten_data: None,
+            gdb_jit_registration,
+            coverage,
+            compile_stats,
             memories_pointer,
             tables_pointer,
         };
 
-        if options.abi == InstanceABI::Emscripten {
-            instance.emscripten_data = Some(EmscriptenData::new(module, &instance));
+        if let Some(layout) = emscripten_layout {
+            let mut emscripten_data = EmscriptenData::new(module, &instance, layout);
+            emscripten_data.tracer = options.trace_syscalls.map(SyscallTracer::new);
+            if let Some(vfs) = options.vfs {
+                emscripten_data.vfs = vfs;
+            }
+            emscripten_data.env = Environment::new(options.env);
+            if let Some(stdin) = options.stdin {
+                emscripten_data.vfs.set(0, Box::new(ReadStream::new(stdin)));
+            }
+            if let Some(stdout) = options.stdout {
+                emscripten_data
+                    .vfs
+                    .set(1, Box::new(WriteStream::new(stdout)));
+            }
+            if let Some(stderr) = options.stderr {
+                emscripten_data
+                    .vfs
+                    .set(2, Box::new(WriteStream::new(stderr)));
+            }
+            instance.emscripten_data = Some(emscripten_data);
         }
 
         Ok(instance)
@@ -674,6 +895,40 @@ impl Instance {
         get_function_addr(&func_index, &self.import_functions, &self.functions)
     }
 
+    /// Returns the local function whose machine code contains `address`,
+    /// along with the offset of `address` in that code.
+    pub fn function_at(&self, address: usize) -> Option<(FuncIndex, usize)> {
+        self.functions.iter().enumerate().find_map(|(i, code)| {
+            let start = code.as_ptr() as usize;
+            if address >= start && address < start + code.len() {
+                let func_index = FuncIndex::new(self.import_functions.len() + i);
+                Some((func_index, address - start))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns the offset (in the module binary) of the wasm instruction
+    /// that generated the machine code at `code_offset` of a function.
+    pub fn bytecode_offset(
+        &self,
+        module: &Module,
+        func_index: FuncIndex,
+        code_offset: usize,
+    ) -> Option<usize> {
+        let defined_func_index = module.defined_func_index(func_index)?.index();
+        let srclocs = self.functions_srclocs.get(defined_func_index)?;
+        let position =
+            match srclocs.binary_search_by_key(&(code_offset as u32), |&(offset, _)| offset) {
+                Ok(position) => position,
+                Err(0) => return None,
+                Err(position) => position - 1,
+            };
+        let body_offset = module.func_bytecode_offsets.get(defined_func_index)?;
+        Some(body_offset + srclocs[position].1 as usize)
+    }
+
     pub fn start(&self) -> Result<(), ErrorKind> {
         if let Some(func_index) = self.start_func {
             let func: fn(&Instance) = get_instance_function!(&self, func_index);
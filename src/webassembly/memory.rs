@@ -3,6 +3,7 @@
 //! webassembly::Instance.
 //! A memory created by Rust or in WebAssembly code will be accessible and
 //! mutable from both Rust and WebAssembly.
+use byteorder::{ByteOrder, LittleEndian};
 use region;
 use std::ops::{Deref, DerefMut};
 use std::slice;
@@ -99,6 +100,16 @@ impl LinearMemory {
         self.current
     }
 
+    /// A bounds checked view of the memory, addressed with 64-bit offsets.
+    pub fn view(&self) -> MemoryView<'_> {
+        MemoryView::new(self)
+    }
+
+    /// Same as `view`, for writing to the memory.
+    pub fn view_mut(&mut self) -> MemoryViewMut<'_> {
+        MemoryViewMut::new(self)
+    }
+
     /// Returns the maximum number of wasm pages allowed.
     pub fn maximum_size(&self) -> u32 {
         self.maximum.unwrap_or(Self::MAX_PAGES)
@@ -132,8 +143,8 @@ impl LinearMemory {
             return None;
         }
 
-        let prev_bytes = (prev_pages * Self::PAGE_SIZE) as usize;
-        let new_bytes = (new_pages * Self::PAGE_SIZE) as usize;
+        let prev_bytes = prev_pages as usize * Self::PAGE_SIZE as usize;
+        let new_bytes = new_pages as usize * Self::PAGE_SIZE as usize;
 
         // if new_bytes > self.mmap.len() - self.offset_guard_size {
         unsafe {
@@ -205,3 +216,124 @@ impl DerefMut for LinearMemory {
         }
     }
 }
+
+/// The range of `len` bytes at `offset`, if it is inside `size` bytes.
+fn checked_range(size: usize, offset: u64, len: u64) -> Option<(usize, usize)> {
+    let end = offset.checked_add(len)?;
+    if end > size as u64 {
+        return None;
+    }
+    Some((offset as usize, end as usize))
+}
+
+/// The bytes of a guest memory, addressed with `u64` offsets so that the
+/// same accessors serve the 32-bit and the 64-bit (memory64) guests. The
+/// accesses outside of the memory return `None`.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryView<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> MemoryView<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        MemoryView { bytes }
+    }
+
+    /// The size of the memory, in bytes.
+    pub fn len(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn get(&self, offset: u64, len: u64) -> Option<&'a [u8]> {
+        let (start, end) = checked_range(self.bytes.len(), offset, len)?;
+        Some(&self.bytes[start..end])
+    }
+
+    pub fn read_u8(&self, offset: u64) -> Option<u8> {
+        self.get(offset, 1).map(|bytes| bytes[0])
+    }
+
+    pub fn read_u32(&self, offset: u64) -> Option<u32> {
+        self.get(offset, 4).map(LittleEndian::read_u32)
+    }
+
+    pub fn read_u64(&self, offset: u64) -> Option<u64> {
+        self.get(offset, 8).map(LittleEndian::read_u64)
+    }
+
+    /// The NUL terminated string at `offset`, without the NUL.
+    pub fn read_cstr(&self, offset: u64) -> Option<&'a [u8]> {
+        let bytes = self.get(offset, self.len().checked_sub(offset)?)?;
+        let len = bytes.iter().position(|&byte| byte == 0)?;
+        Some(&bytes[..len])
+    }
+}
+
+/// Same as `MemoryView`, for writing.
+#[derive(Debug)]
+pub struct MemoryViewMut<'a> {
+    bytes: &'a mut [u8],
+}
+
+impl<'a> MemoryViewMut<'a> {
+    pub fn new(bytes: &'a mut [u8]) -> Self {
+        MemoryViewMut { bytes }
+    }
+
+    pub fn as_view(&self) -> MemoryView<'_> {
+        MemoryView::new(self.bytes)
+    }
+
+    pub fn get_mut(&mut self, offset: u64, len: u64) -> Option<&mut [u8]> {
+        let (start, end) = checked_range(self.bytes.len(), offset, len)?;
+        Some(&mut self.bytes[start..end])
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Option<()> {
+        self.get_mut(offset, data.len() as u64)?
+            .copy_from_slice(data);
+        Some(())
+    }
+
+    pub fn write_u32(&mut self, offset: u64, value: u32) -> Option<()> {
+        self.get_mut(offset, 4)
+            .map(|bytes| LittleEndian::write_u32(bytes, value))
+    }
+
+    pub fn write_u64(&mut self, offset: u64, value: u64) -> Option<()> {
+        self.get_mut(offset, 8)
+            .map(|bytes| LittleEndian::write_u64(bytes, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryView, MemoryViewMut};
+
+    #[test]
+    fn test_memory_view_bounds() {
+        let mut bytes = vec![0u8; 16];
+        {
+            let mut view = MemoryViewMut::new(&mut bytes);
+            assert_eq!(view.write_u32(4, 0xdead_beef), Some(()));
+            assert_eq!(view.write(8, b"hi\0"), Some(()));
+            assert_eq!(view.write(14, b"ab"), Some(()));
+            assert_eq!(view.write_u64(12, 1), None);
+            assert_eq!(view.write(u64::max_value(), b"x"), None);
+        }
+        let view = MemoryView::new(&bytes);
+        assert_eq!(view.len(), 16);
+        assert_eq!(view.read_u32(4), Some(0xdead_beef));
+        assert_eq!(view.read_cstr(8), Some(&b"hi"[..]));
+        // Not terminated
+        assert_eq!(view.read_cstr(14), None);
+        assert_eq!(view.read_u8(16), None);
+        // Beyond 4 GiB
+        assert_eq!(view.read_u64(1 << 32), None);
+        assert_eq!(view.get(u64::max_value() - 1, 4), None);
+    }
+}
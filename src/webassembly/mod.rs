@@ -1,11 +1,22 @@
+pub mod backtrace;
+pub mod coverage;
+pub mod custom_sections;
+pub mod dwarf;
 pub mod errors;
+pub mod features;
+pub mod gdb_jit;
 pub mod import_object;
+pub mod inspect;
 pub mod instance;
 pub mod libcalls;
 pub mod memory;
 pub mod module;
+pub mod perf;
+pub mod profiler;
 pub mod relocation;
+pub mod stats;
 pub mod utils;
+pub mod validation;
 pub mod vmcontext;
 pub mod vmoffsets;
 
@@ -17,15 +28,18 @@ use cranelift_wasm::ModuleEnvironment;
 use std::io::{self, Write};
 use std::panic;
 use std::str::FromStr;
+use std::time::Instant;
 use target_lexicon;
-use wasmparser;
-use wasmparser::WasmDecoder;
 
+pub use self::backtrace::Backtrace;
 pub use self::errors::{Error, ErrorKind};
+pub use self::features::Features;
 pub use self::import_object::{ImportObject, ImportValue};
 pub use self::instance::{Instance, InstanceABI, InstanceOptions};
-pub use self::memory::LinearMemory;
+pub use self::memory::{LinearMemory, MemoryView, MemoryViewMut};
 pub use self::module::{Export, Module, ModuleInfo};
+pub use self::stats::CompileStats;
+pub use self::validation::ValidationError;
 
 use crate::apis::emscripten::{allocate_cstr_on_stack, allocate_on_stack, is_emscripten_module};
 
@@ -58,7 +72,11 @@ pub fn instantiate(
     options: Option<InstanceOptions>,
 ) -> Result<ResultObject, ErrorKind> {
     let isa = get_isa();
-    let module = compile(buffer_source)?;
+    let features = options
+        .as_ref()
+        .map(|options| options.features)
+        .unwrap_or_default();
+    let module = compile_with_features(buffer_source, &features)?;
 
     let abi = if is_emscripten_module(&module) {
         InstanceABI::Emscripten
@@ -73,6 +91,18 @@ pub fn instantiate(
         abi,
         show_progressbar: false,
         isa,
+        features,
+        gdb_jit: false,
+        perf_map: false,
+        jitdump: false,
+        trace_syscalls: None,
+        coverage: false,
+        vfs: None,
+        env: Vec::new(),
+        stdin: None,
+        stdout: None,
+        stderr: None,
+        stack_size: None,
     });
 
     debug!("webassembly - creating instance");
@@ -102,14 +132,26 @@ pub fn instantiate_streaming(
 /// If the operation fails, the Result rejects with a
 /// webassembly::CompileError.
 pub fn compile(buffer_source: Vec<u8>) -> Result<Module, ErrorKind> {
+    compile_with_features(buffer_source, &Features::default())
+}
+
+/// Same as `compile`, but only accepts the WebAssembly proposals
+/// enabled in `features`.
+pub fn compile_with_features(
+    buffer_source: Vec<u8>,
+    features: &Features,
+) -> Result<Module, ErrorKind> {
     // TODO: This should be automatically validated when creating the Module
     debug!("webassembly - validating module");
-    validate_or_error(&buffer_source)?;
+    let start = Instant::now();
+    validate_with_features_or_error(&buffer_source, features)?;
+    let validate_time = start.elapsed();
 
     let isa = get_isa();
 
     debug!("webassembly - creating module");
-    let module = Module::from_bytes(buffer_source, isa.frontend_config())?;
+    let mut module = Module::from_bytes(buffer_source, isa.frontend_config())?;
+    module.stats.validate_time = validate_time;
     debug!("webassembly - module created");
 
     Ok(module)
@@ -125,21 +167,21 @@ pub fn validate(buffer_source: &[u8]) -> bool {
     validate_or_error(buffer_source).is_ok()
 }
 
+/// Same as `validate`, but only accepts the WebAssembly proposals
+/// enabled in `features`.
+pub fn validate_with_features(buffer_source: &[u8], features: &Features) -> bool {
+    validate_with_features_or_error(buffer_source, features).is_ok()
+}
+
 pub fn validate_or_error(bytes: &[u8]) -> Result<(), ErrorKind> {
-    let mut parser = wasmparser::ValidatingParser::new(bytes, None);
-    loop {
-        let state = parser.read();
-        match *state {
-            wasmparser::ParserState::EndWasm => return Ok(()),
-            wasmparser::ParserState::Error(err) => {
-                return Err(ErrorKind::CompileError(format!(
-                    "Validation error: {}",
-                    err.message
-                )));
-            }
-            _ => (),
-        }
-    }
+    validate_with_features_or_error(bytes, &Features::default())
+}
+
+pub fn validate_with_features_or_error(bytes: &[u8], features: &Features) -> Result<(), ErrorKind> {
+    features.check_supported()?;
+    features.check_module(bytes)?;
+    validation::validate_module(bytes, features.validating_parser_config())
+        .map_err(ErrorKind::ValidationError)
 }
 
 pub fn get_isa() -> Box<isa::TargetIsa> {
@@ -210,12 +252,14 @@ fn store_module_arguments(path: &str, args: Vec<&str>, instance: &Instance) -> (
 //     (argc, argv_offset)
 // }
 
+/// Run the main function of the instance, returning its exit code (the value
+/// returned by an emscripten `main`, or given to `exit`).
 pub fn start_instance(
     module: &Module,
     instance: &mut Instance,
     path: &str,
     args: Vec<&str>,
-) -> Result<(), String> {
+) -> Result<i32, String> {
     if let Some(ref emscripten_data) = &instance.emscripten_data {
         emscripten_data.atinit(module, instance)?;
 
@@ -227,25 +271,48 @@ pub fn start_instance(
         let sig_index = module.get_func_type(func_index);
         let signature = module.get_signature(sig_index);
         let num_params = signature.params.len();
-        let result = match num_params {
-            2 => {
-                let main: extern "C" fn(u32, u32, &Instance) =
+        let returns_code = !signature.returns.is_empty();
+        let result = match (num_params, returns_code) {
+            (2, true) => {
+                let main: extern "C" fn(u32, u32, &Instance) -> i32 =
                     get_instance_function!(instance, func_index);
                 let (argc, argv) = store_module_arguments(path, args, instance);
                 call_protected!(main(argc, argv, &instance))
             }
-            0 => {
-                let main: extern "C" fn(&Instance) = get_instance_function!(instance, func_index);
+            (2, false) => {
+                let main: extern "C" fn(u32, u32, &Instance) =
+                    get_instance_function!(instance, func_index);
+                let (argc, argv) = store_module_arguments(path, args, instance);
+                call_protected!(main(argc, argv, &instance)).map(|()| 0)
+            }
+            (0, true) => {
+                let main: extern "C" fn(&Instance) -> i32 =
+                    get_instance_function!(instance, func_index);
                 call_protected!(main(&instance))
             }
+            (0, false) => {
+                let main: extern "C" fn(&Instance) = get_instance_function!(instance, func_index);
+                call_protected!(main(&instance)).map(|()| 0)
+            }
             _ => panic!(
                 "The emscripten main function has received an incorrect number of params {}",
                 num_params
             ),
-        }
-        .map_err(|err| format!("{}", err));
+        };
+        let result = match result {
+            Ok(code) | Err(ErrorKind::Exit(code)) => Ok(code),
+            Err(err) => Err(format!("{}", resolve_backtrace(module, instance, err))),
+        };
 
-        emscripten_data.atexit(module, instance)?;
+        // The trampolines skipped by an exit or a trap are gone
+        if let Some(emscripten_data) = instance.emscripten_data.as_mut() {
+            emscripten_data.invoke_jumps.clear();
+        }
+        instance
+            .emscripten_data
+            .as_ref()
+            .unwrap()
+            .atexit(module, instance)?;
 
         result
     } else {
@@ -257,6 +324,22 @@ pub fn start_instance(
                     _ => panic!("Main function not found"),
                 });
         let main: extern "C" fn(&Instance) = get_instance_function!(instance, func_index);
-        call_protected!(main(&instance)).map_err(|err| format!("{}", err))
+        match call_protected!(main(&instance)) {
+            Ok(()) => Ok(0),
+            Err(ErrorKind::Exit(code)) => Ok(code),
+            Err(err) => Err(format!("{}", resolve_backtrace(module, instance, err))),
+        }
+    }
+}
+
+/// Resolve the wasm functions of the backtrace of a `RuntimeError`
+/// that happened while running code of the given instance.
+pub fn resolve_backtrace(module: &Module, instance: &Instance, err: ErrorKind) -> ErrorKind {
+    match err {
+        ErrorKind::RuntimeError(reason, mut backtrace) => {
+            backtrace.resolve(module, instance);
+            ErrorKind::RuntimeError(reason, backtrace)
+        }
+        err => err,
     }
 }
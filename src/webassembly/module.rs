@@ -3,6 +3,7 @@
 //! (that will be later compiled in the WebAssembly instance)
 use std::collections::HashMap;
 use std::string::String;
+use std::time::Instant;
 use std::vec::Vec;
 
 use cranelift_codegen::cursor::FuncCursor;
@@ -21,9 +22,11 @@ use cranelift_wasm::{
     ReturnMode, SignatureIndex, Table, TableIndex, WasmResult,
 };
 
+use super::custom_sections::{read_code_section_offset, read_custom_sections, read_names, Names};
 use super::errors::ErrorKind;
 use super::instance::Instance;
 use super::memory::LinearMemory;
+use super::stats::CompileStats;
 
 /// Get the integer type used for representing pointers on this platform.
 fn native_pointer_type() -> ir::Type {
@@ -154,6 +157,12 @@ pub struct ModuleInfo {
 
     /// The external function declaration for implementing wasm's `grow_memory`.
     pub grow_memory_extfunc: Option<FuncRef>,
+
+    /// Payloads of the custom sections, by section name.
+    pub custom_sections: HashMap<String, Vec<Vec<u8>>>,
+
+    /// Names decoded from the `name` custom section.
+    pub names: Names,
 }
 
 impl ModuleInfo {
@@ -178,6 +187,8 @@ impl ModuleInfo {
             exports: HashMap::new(),
             current_memory_extfunc: None,
             grow_memory_extfunc: None,
+            custom_sections: HashMap::new(),
+            names: Names::default(),
         }
     }
 }
@@ -229,6 +240,16 @@ pub struct Module {
 
     /// Vector of wasm bytecode size for each function.
     pub func_bytecode_sizes: Vec<usize>,
+
+    /// Vector of the offset of each function body in the wasm binary.
+    pub func_bytecode_offsets: Vec<usize>,
+
+    /// Offset of the code section payload in the wasm binary, which
+    /// DWARF addresses are relative to.
+    pub code_section_offset: Option<usize>,
+
+    /// Time spent validating, parsing and translating the module.
+    pub stats: CompileStats,
     // How to return from functions.
     // return_mode: ReturnMode,
 }
@@ -244,16 +265,68 @@ impl Module {
             info: ModuleInfo::new(config),
             trans: FuncTranslator::new(),
             func_bytecode_sizes: Vec::new(),
+            func_bytecode_offsets: Vec::new(),
+            code_section_offset: None,
+            stats: CompileStats::default(),
             // return_mode,
         };
 
         // We iterate through the source bytes, generating the compiled module
+        let start = Instant::now();
         translate_module(&buffer_source, &mut module)
             .map_err(|e| ErrorKind::CompileError(e.to_string()))?;
+        module.stats.parse_time = start.elapsed() - module.stats.translate_time;
+
+        // Function bodies are slices of `buffer_source`, we recorded their
+        // addresses while translating, so we make them relative now.
+        let buffer_address = buffer_source.as_ptr() as usize;
+        for offset in module.func_bytecode_offsets.iter_mut() {
+            *offset -= buffer_address;
+        }
+
+        module.code_section_offset = read_code_section_offset(&buffer_source);
+        module.info.custom_sections = read_custom_sections(&buffer_source);
+        if let Some(payload) = module.custom_sections("name").first() {
+            let names = read_names(payload);
+            module.info.names = names;
+        }
 
         Ok(module)
     }
 
+    /// Return the payloads of the custom sections named `name`, in the
+    /// order they appear in the module.
+    pub fn custom_sections(&self, name: &str) -> &[Vec<u8>] {
+        self.info
+            .custom_sections
+            .get(name)
+            .map(|sections| sections.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Return a human-readable name for a function: the one given by the
+    /// `name` section if any, otherwise its export or import name.
+    pub fn function_name(&self, func_index: FuncIndex) -> Option<String> {
+        if let Some(name) = self.info.names.functions.get(&func_index) {
+            return Some(name.clone());
+        }
+        let function = self.info.functions.get(func_index)?;
+        if let Some(name) = function.export_names.first() {
+            return Some(name.clone());
+        }
+        function
+            .import_name
+            .as_ref()
+            .map(|(module, field)| format!("{}.{}", module, field))
+    }
+
+    /// Return the name of a function, or a placeholder built from its
+    /// index when the module doesn't provide any.
+    pub fn function_symbol(&self, func_index: FuncIndex) -> String {
+        self.function_name(func_index)
+            .unwrap_or_else(|| format!("wasm-function[{}]", func_index.index()))
+    }
+
     /// Return a `FuncEnvironment` for translating functions within this
     /// `Module`.
     pub fn func_env(&self) -> FuncEnvironment {
@@ -841,6 +914,7 @@ impl<'data> ModuleEnvironment<'data> for Module {
     }
 
     fn define_function_body(&mut self, body_bytes: &'data [u8]) -> WasmResult<()> {
+        let start = Instant::now();
         let func = {
             let mut func_environ = FuncEnvironment::new(&self.info); // , self.return_mode);
             let func_index =
@@ -852,7 +926,10 @@ impl<'data> ModuleEnvironment<'data> for Module {
                 .translate(body_bytes, &mut func, &mut func_environ)?;
             func
         };
+        self.stats.translate_time += start.elapsed();
         self.func_bytecode_sizes.push(body_bytes.len());
+        self.func_bytecode_offsets
+            .push(body_bytes.as_ptr() as usize);
         self.info.function_bodies.push(func);
         Ok(())
     }
@@ -0,0 +1,213 @@
+//! Let `perf` attribute samples to the wasm functions.
+//!
+//! Two formats are supported:
+//! * The perf map (`/tmp/perf-<pid>.map`), one line per function with its
+//!   address, size and name. Used by `perf report` directly.
+//! * The jitdump (`/tmp/jit-<pid>.dump`), that also carries the code itself,
+//!   so samples can be annotated. It has to be injected in the recorded data:
+//!   `perf record -k mono ...` followed by `perf inject --jit`.
+//!
+//! See: https://github.com/torvalds/linux/blob/master/tools/perf/Documentation/jitdump-specification.txt
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// A function placed in memory, as reported to perf.
+pub struct PerfFunction<'a> {
+    pub name: String,
+    pub code: &'a [u8],
+}
+
+/// Append the functions to the perf map of this process.
+pub fn write_perf_map(functions: &[PerfFunction]) -> io::Result<()> {
+    let path = format!("/tmp/perf-{}.map", process::id());
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(perf_map_entries(functions).as_bytes())
+}
+
+/// The lines of the perf map for the functions: `START SIZE NAME`, in hex.
+fn perf_map_entries(functions: &[PerfFunction]) -> String {
+    let mut entries = String::new();
+    for function in functions {
+        entries.push_str(&format!(
+            "{:x} {:x} {}\n",
+            function.code.as_ptr() as usize,
+            function.code.len(),
+            function.name
+        ));
+    }
+    entries
+}
+
+const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const JITDUMP_HEADER_SIZE: u32 = 40;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_LOAD_HEADER_SIZE: usize = 16 + 40;
+const EM_X86_64: u32 = 62;
+
+lazy_static! {
+    /// The jitdump of the process, created by the first `write_jitdump`.
+    static ref JITDUMP_FILE: Mutex<Option<File>> = Mutex::new(None);
+}
+static JITDUMP_CODE_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+fn timestamp() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(target_os = "linux")]
+fn thread_id() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_id() -> u32 {
+    process::id()
+}
+
+fn open_jitdump() -> io::Result<File> {
+    let path = format!("/tmp/jit-{}.dump", process::id());
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+
+    file.write_all(&jitdump_header(process::id(), timestamp())?)?;
+
+    // perf finds the jitdump of a process by looking for an executable
+    // mapping of it in the recorded events, so we map (and leak) its first page.
+    unsafe {
+        use std::os::unix::io::AsRawFd;
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let address = libc::mmap(
+            ::std::ptr::null_mut(),
+            page_size,
+            libc::PROT_READ | libc::PROT_EXEC,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        );
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+fn jitdump_header(pid: u32, timestamp: u64) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(JITDUMP_HEADER_SIZE as usize);
+    header.write_u32::<LittleEndian>(JITDUMP_MAGIC)?;
+    header.write_u32::<LittleEndian>(JITDUMP_VERSION)?;
+    header.write_u32::<LittleEndian>(JITDUMP_HEADER_SIZE)?;
+    header.write_u32::<LittleEndian>(EM_X86_64)?;
+    header.write_u32::<LittleEndian>(0)?; // padding
+    header.write_u32::<LittleEndian>(pid)?;
+    header.write_u64::<LittleEndian>(timestamp)?;
+    header.write_u64::<LittleEndian>(0)?; // flags
+    Ok(header)
+}
+
+/// Appends the `JIT_CODE_LOAD` record of a function to `records`.
+fn write_code_load(
+    records: &mut Vec<u8>,
+    function: &PerfFunction,
+    pid: u32,
+    tid: u32,
+    timestamp: u64,
+    code_index: u64,
+) -> io::Result<()> {
+    let address = function.code.as_ptr() as u64;
+    let total_size = JIT_CODE_LOAD_HEADER_SIZE + function.name.len() + 1 + function.code.len();
+    records.write_u32::<LittleEndian>(JIT_CODE_LOAD)?;
+    records.write_u32::<LittleEndian>(total_size as u32)?;
+    records.write_u64::<LittleEndian>(timestamp)?;
+    records.write_u32::<LittleEndian>(pid)?;
+    records.write_u32::<LittleEndian>(tid)?;
+    records.write_u64::<LittleEndian>(address)?; // vma
+    records.write_u64::<LittleEndian>(address)?; // code address
+    records.write_u64::<LittleEndian>(function.code.len() as u64)?;
+    records.write_u64::<LittleEndian>(code_index)?;
+    records.extend_from_slice(function.name.as_bytes());
+    records.push(0);
+    records.extend_from_slice(function.code);
+    Ok(())
+}
+
+/// Append a code load record for each function to the jitdump of this process.
+pub fn write_jitdump(functions: &[PerfFunction]) -> io::Result<()> {
+    let mut file = JITDUMP_FILE.lock().unwrap();
+    if file.is_none() {
+        *file = Some(open_jitdump()?);
+    }
+    let file = file.as_mut().unwrap();
+
+    let pid = process::id();
+    let tid = thread_id();
+    let mut records = Vec::new();
+    for function in functions {
+        let code_index = JITDUMP_CODE_INDEX.fetch_add(1, Ordering::SeqCst) as u64;
+        write_code_load(&mut records, function, pid, tid, timestamp(), code_index)?;
+    }
+    file.write_all(&records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        jitdump_header, perf_map_entries, write_code_load, PerfFunction, JITDUMP_MAGIC,
+        JIT_CODE_LOAD_HEADER_SIZE,
+    };
+    use byteorder::{ByteOrder, LittleEndian};
+
+    #[test]
+    fn test_perf_map_entries() {
+        let code = [0xc3u8; 0x20];
+        let functions = [PerfFunction {
+            name: "_main".to_string(),
+            code: &code,
+        }];
+        let expected = format!("{:x} 20 _main\n", code.as_ptr() as usize);
+        assert_eq!(perf_map_entries(&functions), expected);
+    }
+
+    #[test]
+    fn test_jitdump_records() {
+        let header = jitdump_header(42, 7).unwrap();
+        assert_eq!(header.len(), 40);
+        assert_eq!(LittleEndian::read_u32(&header), JITDUMP_MAGIC);
+        assert_eq!(LittleEndian::read_u32(&header[20..]), 42);
+        assert_eq!(LittleEndian::read_u64(&header[24..]), 7);
+
+        let code = [0x90u8, 0xc3];
+        let function = PerfFunction {
+            name: "f".to_string(),
+            code: &code,
+        };
+        let mut records = Vec::new();
+        write_code_load(&mut records, &function, 42, 43, 7, 3).unwrap();
+        // The size of the record includes its header
+        assert_eq!(records.len(), JIT_CODE_LOAD_HEADER_SIZE + 2 + 2);
+        assert_eq!(
+            LittleEndian::read_u32(&records[4..]) as usize,
+            records.len()
+        );
+        assert_eq!(LittleEndian::read_u32(&records[20..]), 43);
+        assert_eq!(LittleEndian::read_u64(&records[24..]), code.as_ptr() as u64);
+        assert_eq!(LittleEndian::read_u64(&records[40..]), 2);
+        assert_eq!(LittleEndian::read_u64(&records[48..]), 3);
+        assert_eq!(&records[56..], b"f\0\x90\xc3");
+    }
+}
@@ -0,0 +1,312 @@
+//! A sampling profiler for the guest code.
+//!
+//! A `SIGPROF` timer interrupts the running code at a fixed interval (of
+//! consumed CPU time) and the signal handler records the guest call stack by
+//! walking the frame pointers, as done for backtraces. The samples are
+//! written into a preallocated buffer (so the handler never allocates),
+//! and resolved into wasm functions once the profiling is stopped.
+//!
+//! Only the thread that started the profiler is sampled: its stack bounds
+//! are published in atomics before the timer is armed, so the handler
+//! doesn't need the thread locals (which aren't async-signal-safe).
+//!
+//! The output uses the "folded stacks" format, one line per unique stack:
+//! `main;foo;bar 42`, that flamegraph tools (`flamegraph.pl`, `inferno`)
+//! take as input.
+use nix::libc::{self, c_int, c_void, siginfo_t};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, SIGPROF};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use super::instance::Instance;
+use super::module::Module;
+use crate::recovery::{get_registers, walk_frames};
+
+extern "C" {
+    fn setitimer(
+        which: c_int,
+        new_value: *const libc::itimerval,
+        old_value: *mut libc::itimerval,
+    ) -> c_int;
+}
+
+const ITIMER_PROF: c_int = 2;
+
+/// Sampling interval, in microseconds of CPU time.
+pub const DEFAULT_INTERVAL_US: i64 = 5000;
+
+const MAX_SAMPLE_DEPTH: usize = 48;
+const MAX_SAMPLES: usize = 1 << 15;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    len: usize,
+    frames: [usize; MAX_SAMPLE_DEPTH],
+}
+
+static PROFILER_RUNNING: AtomicBool = AtomicBool::new(false);
+// `ptr::null_mut` can't be used in a static yet
+#[allow(clippy::zero_ptr)]
+static SAMPLES: AtomicPtr<Sample> = AtomicPtr::new(0 as *mut Sample);
+static NEXT_SAMPLE: AtomicUsize = AtomicUsize::new(0);
+/// The stack of the profiled thread
+static STACK_LOW: AtomicUsize = AtomicUsize::new(0);
+static STACK_HIGH: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn sigprof_handler(_signum: c_int, _siginfo: *mut siginfo_t, ucontext: *mut c_void) {
+    unsafe {
+        let samples = SAMPLES.load(Ordering::Acquire);
+        if samples.is_null() {
+            return;
+        }
+        let (pc, sp, fp) = get_registers(ucontext);
+        // Another thread was interrupted
+        let stack_high = STACK_HIGH.load(Ordering::Relaxed);
+        if sp < STACK_LOW.load(Ordering::Relaxed) || sp >= stack_high {
+            return;
+        }
+        let index = NEXT_SAMPLE.fetch_add(1, Ordering::Relaxed);
+        if index >= MAX_SAMPLES {
+            return;
+        }
+        let sample = &mut *samples.add(index);
+        sample.len = walk_frames(pc, sp, fp, stack_high, &mut sample.frames);
+    }
+}
+
+/// The lowest and highest addresses of the stack of the current thread.
+#[cfg(target_os = "linux")]
+fn current_stack_bounds() -> Option<(usize, usize)> {
+    unsafe {
+        let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let mut address = ptr::null_mut();
+        let mut size = 0;
+        let ret = libc::pthread_attr_getstack(&attr, &mut address, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+        if ret != 0 {
+            return None;
+        }
+        Some((address as usize, address as usize + size))
+    }
+}
+
+/// The lowest and highest addresses of the stack of the current thread.
+#[cfg(target_os = "macos")]
+fn current_stack_bounds() -> Option<(usize, usize)> {
+    unsafe {
+        let thread = libc::pthread_self();
+        let high = libc::pthread_get_stackaddr_np(thread) as usize;
+        Some((high - libc::pthread_get_stacksize_np(thread), high))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn current_stack_bounds() -> Option<(usize, usize)> {
+    None
+}
+
+fn set_timer(interval_us: i64) -> io::Result<()> {
+    let interval = libc::timeval {
+        tv_sec: (interval_us / 1_000_000) as _,
+        tv_usec: (interval_us % 1_000_000) as _,
+    };
+    let timer = libc::itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+    if unsafe { setitimer(ITIMER_PROF, &timer, ptr::null_mut()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Samples the guest call stacks until stopped. Only one profiler can run at a time.
+pub struct Profiler {
+    samples: Vec<Sample>,
+    previous_action: Option<SigAction>,
+}
+
+impl Profiler {
+    /// Starts sampling the current thread.
+    pub fn start(interval_us: i64) -> Result<Profiler, String> {
+        let (stack_low, stack_high) = current_stack_bounds()
+            .ok_or_else(|| "Can't find the stack of the profiled thread".to_string())?;
+        if PROFILER_RUNNING.swap(true, Ordering::SeqCst) {
+            return Err("A profiler is already running".to_string());
+        }
+        let mut profiler = Profiler {
+            samples: vec![
+                Sample {
+                    len: 0,
+                    frames: [0; MAX_SAMPLE_DEPTH],
+                };
+                MAX_SAMPLES
+            ],
+            previous_action: None,
+        };
+        NEXT_SAMPLE.store(0, Ordering::SeqCst);
+        STACK_LOW.store(stack_low, Ordering::SeqCst);
+        STACK_HIGH.store(stack_high, Ordering::SeqCst);
+        SAMPLES.store(profiler.samples.as_mut_ptr(), Ordering::SeqCst);
+
+        // Restart the syscalls interrupted by the timer, the guest shouldn't notice it
+        let action = SigAction::new(
+            SigHandler::SigAction(sigprof_handler),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        let previous_action = unsafe { sigaction(SIGPROF, &action) }
+            .map_err(|err| format!("Can't install the profiler signal handler: {}", err))?;
+        profiler.previous_action = Some(previous_action);
+        set_timer(interval_us).map_err(|err| format!("Can't start the profiler timer: {}", err))?;
+
+        Ok(profiler)
+    }
+
+    fn disable(&mut self) {
+        if !PROFILER_RUNNING.load(Ordering::SeqCst) {
+            return;
+        }
+        set_timer(0).ok();
+        unsafe {
+            if let Some(ref previous_action) = self.previous_action {
+                sigaction(SIGPROF, previous_action).ok();
+            }
+        }
+        SAMPLES.store(ptr::null_mut(), Ordering::SeqCst);
+        PROFILER_RUNNING.store(false, Ordering::SeqCst);
+    }
+
+    /// Stops sampling and writes the collected stacks, in the folded format.
+    pub fn finish(mut self, module: &Module, instance: &Instance, path: &Path) -> io::Result<()> {
+        let mut stacks: Vec<(String, usize)> = self.stop(module, instance).into_iter().collect();
+        stacks.sort();
+
+        let mut file = File::create(path)?;
+        for (stack, count) in stacks {
+            writeln!(file, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+
+    /// Stops sampling and returns the number of samples of each stack.
+    pub fn stop(&mut self, module: &Module, instance: &Instance) -> HashMap<String, usize> {
+        self.disable();
+        let taken = NEXT_SAMPLE.load(Ordering::SeqCst);
+        if taken > MAX_SAMPLES {
+            warn!(
+                "Profiler: the samples buffer is full, {} samples were dropped",
+                taken - MAX_SAMPLES
+            );
+        }
+        self.samples.truncate(taken.min(MAX_SAMPLES));
+
+        fold_samples(&self.samples, |address| {
+            instance
+                .function_at(address)
+                .map(|(func_index, _)| module.function_symbol(func_index))
+        })
+    }
+}
+
+/// Counts the samples of each stack, naming the frames with `resolve`
+/// (`None` for the addresses outside of the guest code).
+fn fold_samples<F: Fn(usize) -> Option<String>>(
+    samples: &[Sample],
+    resolve: F,
+) -> HashMap<String, usize> {
+    let mut stacks = HashMap::new();
+    for sample in samples {
+        let mut names = Vec::new();
+        for (i, &address) in sample.frames[..sample.len].iter().enumerate() {
+            // Return addresses point right after the call instruction
+            let address = if i == 0 {
+                address
+            } else {
+                address.wrapping_sub(1)
+            };
+            match resolve(address) {
+                Some(name) => names.push(name),
+                // Interrupted in a host function (called by the guest, or
+                // before and after it runs). The host code may not keep
+                // frame pointers, so the other host frames are skipped, but
+                // the guest frames found above it are kept.
+                None if i == 0 => names.push("[host]".to_string()),
+                None => {}
+            }
+        }
+        if names.is_empty() {
+            continue;
+        }
+        names.reverse();
+        *stacks.entry(names.join(";")).or_insert(0) += 1;
+    }
+    stacks
+}
+
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold_samples, Profiler, Sample, MAX_SAMPLE_DEPTH, NEXT_SAMPLE};
+    use nix::libc::{self, c_int};
+    use nix::sys::signal::SIGPROF;
+    use std::sync::atomic::Ordering;
+
+    fn sample(frames: &[usize]) -> Sample {
+        let mut sample = Sample {
+            len: frames.len(),
+            frames: [0; MAX_SAMPLE_DEPTH],
+        };
+        sample.frames[..frames.len()].copy_from_slice(frames);
+        sample
+    }
+
+    #[test]
+    fn test_fold_samples() {
+        // The guest functions are at 0x100 (main) and 0x200 (foo)
+        let resolve = |address: usize| match address {
+            0x100..=0x1ff => Some("main".to_string()),
+            0x200..=0x2ff => Some("foo".to_string()),
+            _ => None,
+        };
+        let samples = [
+            sample(&[0x210, 0x121]),
+            sample(&[0x220, 0x121]),
+            // In a host function called by foo, through host frames
+            sample(&[0x9000, 0x9100, 0x231, 0x121]),
+            // Outside of the guest
+            sample(&[0x9000]),
+        ];
+        let stacks = fold_samples(&samples, resolve);
+        assert_eq!(stacks.len(), 3);
+        assert_eq!(stacks["main;foo"], 2);
+        assert_eq!(stacks["main;foo;[host]"], 1);
+        assert_eq!(stacks["[host]"], 1);
+    }
+
+    #[test]
+    fn test_profiler_samples_the_current_thread() {
+        // The timer doesn't fire during the test, the signal is sent to this
+        // thread only: the other tests can run in parallel
+        let mut profiler = Profiler::start(1_000_000_000).unwrap();
+        assert!(Profiler::start(1000).is_err());
+        unsafe {
+            libc::pthread_kill(libc::pthread_self(), SIGPROF as c_int);
+        }
+        profiler.disable();
+        assert_eq!(NEXT_SAMPLE.load(Ordering::SeqCst), 1);
+        assert!(profiler.samples[0].len > 0);
+    }
+}
@@ -0,0 +1,236 @@
+//! Statistics of the compilation of a module (`wasmer run --stats`): the
+//! time spent in each phase, and for each function its compile time, the
+//! size of its bytecode and machine code, and its relocations.
+//!
+//! The parse, translation and validation times are recorded by
+//! `compile_with_features` in `Module::stats`, the `Instance` completes them
+//! with the compilation to machine code in `Instance::compile_stats`.
+use cranelift_entity::EntityRef;
+use cranelift_wasm::DefinedFuncIndex;
+use std::fmt::Write;
+use std::ops::AddAssign;
+use std::time::Duration;
+
+use super::module::Module;
+use super::relocation::{Relocation, RelocationType};
+
+/// Number of relocations, by kind of target.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelocationCounts {
+    /// Calls to other functions (wasm or imported).
+    pub calls: usize,
+    /// Calls to libcalls (float rounding, stack probes).
+    pub libcalls: usize,
+    /// Calls to the memory intrinsics (`current_memory`, `grow_memory`).
+    pub memory: usize,
+    /// Other intrinsics.
+    pub intrinsics: usize,
+}
+
+impl RelocationCounts {
+    pub fn from_relocations(relocations: &[Relocation]) -> Self {
+        let mut counts = RelocationCounts::default();
+        for relocation in relocations {
+            match relocation.target {
+                RelocationType::Normal(_) => counts.calls += 1,
+                RelocationType::LibCall(_) => counts.libcalls += 1,
+                RelocationType::GrowMemory | RelocationType::CurrentMemory => counts.memory += 1,
+                RelocationType::Intrinsic(_) => counts.intrinsics += 1,
+            }
+        }
+        counts
+    }
+
+    pub fn total(&self) -> usize {
+        self.calls + self.libcalls + self.memory + self.intrinsics
+    }
+}
+
+impl AddAssign for RelocationCounts {
+    fn add_assign(&mut self, other: RelocationCounts) {
+        self.calls += other.calls;
+        self.libcalls += other.libcalls;
+        self.memory += other.memory;
+        self.intrinsics += other.intrinsics;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionStats {
+    pub defined_func: DefinedFuncIndex,
+    /// Size of the function body in the wasm binary.
+    pub bytecode_size: usize,
+    /// Time spent compiling the Cranelift IR to machine code.
+    pub compile_time: Duration,
+    /// Size of the generated machine code.
+    pub code_size: usize,
+    pub relocations: RelocationCounts,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompileStats {
+    /// Time spent validating the module binary.
+    pub validate_time: Duration,
+    /// Time spent parsing the module, excluding the translation of the function bodies.
+    pub parse_time: Duration,
+    /// Time spent translating the function bodies to Cranelift IR.
+    pub translate_time: Duration,
+    /// Elapsed time compiling the functions to machine code (they are compiled in parallel).
+    pub compile_time: Duration,
+    /// The statistics of each defined function, in index order.
+    pub functions: Vec<FunctionStats>,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
+
+impl CompileStats {
+    /// Sum of the compile time of each function (CPU time, across threads).
+    pub fn functions_compile_time(&self) -> Duration {
+        self.functions
+            .iter()
+            .map(|function| function.compile_time)
+            .sum()
+    }
+
+    pub fn bytecode_size(&self) -> usize {
+        self.functions
+            .iter()
+            .map(|function| function.bytecode_size)
+            .sum()
+    }
+
+    pub fn code_size(&self) -> usize {
+        self.functions
+            .iter()
+            .map(|function| function.code_size)
+            .sum()
+    }
+
+    pub fn relocations(&self) -> RelocationCounts {
+        let mut counts = RelocationCounts::default();
+        for function in &self.functions {
+            counts += function.relocations;
+        }
+        counts
+    }
+
+    /// The `count` functions that took the longest to compile.
+    pub fn slowest_functions(&self, count: usize) -> Vec<&FunctionStats> {
+        let mut functions: Vec<&FunctionStats> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.compile_time.cmp(&a.compile_time));
+        functions.truncate(count);
+        functions
+    }
+
+    /// The `count` functions with the largest machine code.
+    pub fn largest_functions(&self, count: usize) -> Vec<&FunctionStats> {
+        let mut functions: Vec<&FunctionStats> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.code_size.cmp(&a.code_size));
+        functions.truncate(count);
+        functions
+    }
+
+    /// A human-readable report, listing the `top` slowest and largest functions.
+    pub fn report(&self, module: &Module, top: usize) -> String {
+        let mut out = String::new();
+        writeln!(out, "Validation:   {:10.3} ms", millis(self.validate_time)).unwrap();
+        writeln!(out, "Parsing:      {:10.3} ms", millis(self.parse_time)).unwrap();
+        writeln!(out, "Translation:  {:10.3} ms", millis(self.translate_time)).unwrap();
+        writeln!(
+            out,
+            "Compilation:  {:10.3} ms ({} functions, {:.3} ms on all threads)",
+            millis(self.compile_time),
+            self.functions.len(),
+            millis(self.functions_compile_time())
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "Code size:    {} bytes of bytecode, {} bytes of machine code",
+            self.bytecode_size(),
+            self.code_size()
+        )
+        .unwrap();
+        let relocations = self.relocations();
+        writeln!(
+            out,
+            "Relocations:  {} ({} calls, {} libcalls, {} memory intrinsics, {} other intrinsics)",
+            relocations.total(),
+            relocations.calls,
+            relocations.libcalls,
+            relocations.memory,
+            relocations.intrinsics
+        )
+        .unwrap();
+
+        let describe = |out: &mut String, function: &FunctionStats| {
+            let func_index = module.func_index(function.defined_func);
+            writeln!(
+                out,
+                "  {:10.3} ms {:8} bytes  func {} {} ({} bytes of bytecode, {} relocations)",
+                millis(function.compile_time),
+                function.code_size,
+                func_index.index(),
+                module.function_symbol(func_index),
+                function.bytecode_size,
+                function.relocations.total()
+            )
+            .unwrap();
+        };
+        writeln!(out, "Slowest functions:").unwrap();
+        for function in self.slowest_functions(top) {
+            describe(&mut out, function);
+        }
+        writeln!(out, "Largest functions:").unwrap();
+        for function in self.largest_functions(top) {
+            describe(&mut out, function);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompileStats, FunctionStats, RelocationCounts};
+    use cranelift_entity::EntityRef;
+    use cranelift_wasm::DefinedFuncIndex;
+    use std::time::Duration;
+
+    fn function(index: usize, compile_ms: u64, code_size: usize) -> FunctionStats {
+        FunctionStats {
+            defined_func: DefinedFuncIndex::new(index),
+            bytecode_size: 10,
+            compile_time: Duration::from_millis(compile_ms),
+            code_size,
+            relocations: RelocationCounts {
+                calls: 1,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_compile_stats_totals() {
+        let stats = CompileStats {
+            functions: vec![
+                function(0, 5, 100),
+                function(1, 20, 50),
+                function(2, 1, 300),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(stats.code_size(), 450);
+        assert_eq!(stats.bytecode_size(), 30);
+        assert_eq!(stats.relocations().calls, 3);
+        assert_eq!(stats.functions_compile_time(), Duration::from_millis(26));
+        let slowest: Vec<usize> = stats
+            .slowest_functions(2)
+            .iter()
+            .map(|function| function.defined_func.index())
+            .collect();
+        assert_eq!(slowest, vec![1, 0]);
+        assert_eq!(stats.largest_functions(1)[0].defined_func.index(), 2);
+    }
+}
@@ -0,0 +1,104 @@
+//! Validation of the module binary, reporting where the first error is:
+//! its offset in the binary, the section, and for code errors the function
+//! and the offset of the instruction from the start of its body.
+use std::fmt;
+use wasmparser::{
+    ImportSectionEntryType, ParserState, SectionCode, ValidatingParser, ValidatingParserConfig,
+    WasmDecoder,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub message: String,
+    /// Offset of the error in the module binary.
+    pub offset: usize,
+    /// The section where the error is, if it's inside one.
+    pub section: Option<String>,
+    /// Index of the function (imported functions included), for errors in the code section.
+    pub func_index: Option<usize>,
+    /// Offset of the error from the start of the function body.
+    pub func_offset: Option<usize>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {:#x}", self.message, self.offset)?;
+        if let Some(ref section) = self.section {
+            write!(f, ", in the {} section", section)?;
+        }
+        if let (Some(func_index), Some(func_offset)) = (self.func_index, self.func_offset) {
+            write!(f, ", function {} at {:#x}", func_index, func_offset)?;
+        }
+        Ok(())
+    }
+}
+
+fn section_name(code: &SectionCode) -> String {
+    match *code {
+        SectionCode::Custom { name, .. } => format!("custom {:?}", name),
+        ref code => format!("{:?}", code).to_lowercase(),
+    }
+}
+
+/// Validate the module binary, stopping at the first error.
+pub fn validate_module(
+    bytes: &[u8],
+    config: ValidatingParserConfig,
+) -> Result<(), ValidationError> {
+    let mut parser = ValidatingParser::new(bytes, Some(config));
+    let mut section = None;
+    let mut imported_funcs = 0;
+    let mut defined_funcs = 0;
+    // Index and start offset of the function body being read
+    let mut function: Option<(usize, usize)> = None;
+    loop {
+        match *parser.read() {
+            ParserState::EndWasm => return Ok(()),
+            ParserState::Error(err) => {
+                return Err(ValidationError {
+                    message: err.message.to_string(),
+                    offset: err.offset,
+                    section,
+                    func_index: function.map(|(index, _)| index),
+                    func_offset: function.map(|(_, start)| err.offset.saturating_sub(start)),
+                });
+            }
+            ParserState::BeginSection { ref code, .. } => section = Some(section_name(code)),
+            ParserState::EndSection => section = None,
+            ParserState::ImportSectionEntry {
+                ty: ImportSectionEntryType::Function(_),
+                ..
+            } => imported_funcs += 1,
+            ParserState::BeginFunctionBody { range } => {
+                function = Some((imported_funcs + defined_funcs, range.start));
+                defined_funcs += 1;
+            }
+            ParserState::EndFunctionBody => function = None,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::features::Features;
+    use super::validate_module;
+
+    #[test]
+    fn test_validation_error_location() {
+        let wasm = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+            0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f, // type: () -> i32
+            0x03, 0x03, 0x02, 0x00, 0x00, // two functions of type 0
+            0x0a, 0x0c, 0x02, // code
+            0x04, 0x00, 0x41, 0x00, 0x0b, // i32.const 0
+            0x05, 0x00, 0x41, 0x01, 0x6a, 0x0b, // i32.const 1, i32.add (missing operand)
+        ];
+        let config = Features::default().validating_parser_config();
+        let err = validate_module(&wasm, config).unwrap_err();
+        assert_eq!(err.section, Some("code".to_string()));
+        assert_eq!(err.func_index, Some(1));
+        assert!(err.offset >= 29 && err.offset < wasm.len());
+        assert!(err.func_offset.unwrap() > 0);
+    }
+}